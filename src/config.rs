@@ -0,0 +1,266 @@
+//! The TOML configuration file.
+//!
+//! Every setting in the file can also be given as a command line flag or an
+//! environment variable. Flags and environment variables override the file.
+
+use clap::{parser::ValueSource, ArgMatches};
+use serde_derive::Deserialize;
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use crate::{dht::node_id::NodeIdPolicy, Args};
+
+/// The error when the configuration file cannot be used.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The file cannot be read.
+    #[error("could not read config file {}: {source}", path.display())]
+    Read {
+        /// The config file
+        path: PathBuf,
+        /// The underlying error
+        source: io::Error,
+    },
+    /// The file is not valid TOML or has unknown or mistyped settings.
+    #[error("invalid config file {}: {source}", path.display())]
+    Parse {
+        /// The config file
+        path: PathBuf,
+        /// The underlying error
+        source: toml::de::Error,
+    },
+    /// A setting has an invalid value.
+    #[error("invalid config file {}: {key}: {message}", path.display())]
+    Invalid {
+        /// The config file
+        path: PathBuf,
+        /// The setting
+        key: &'static str,
+        /// Why the value is invalid
+        message: String,
+    },
+}
+
+/// The settings in the configuration file.
+///
+/// The keys are the same as the command line flags with underscores instead
+/// of dashes.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    dht_bind: Option<Ipv4Addr>,
+    dht_bind6: Option<Ipv6Addr>,
+    dht_port: Option<u16>,
+    disable_ipv4: Option<bool>,
+    disable_ipv6: Option<bool>,
+    read_only: Option<bool>,
+    http_bind: Option<IpAddr>,
+    http_port: Option<u16>,
+    bootstrap: Option<Vec<String>>,
+    signing_key_file: Option<PathBuf>,
+    external_ip: Option<IpAddr>,
+    node_id_policy: Option<String>,
+    regenerate_node_id: Option<bool>,
+    state_file: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    client_version: Option<String>,
+    default_query_timeout_secs: Option<u64>,
+    strict_response_node_id: Option<bool>,
+    routing_table_next_response_interval_secs: Option<u64>,
+    routing_table_next_query_interval_secs: Option<u64>,
+    announce_interval_secs: Option<u64>,
+    ready_min_good_nodes: Option<usize>,
+}
+
+/// Checks that a client version is 4 ASCII characters.
+///
+/// The version is usually a two character client code followed by a two
+/// character version number.
+pub fn parse_client_version(value: &str) -> Result<String, String> {
+    if value.len() == 4 && value.is_ascii() {
+        Ok(value.to_string())
+    } else {
+        Err(String::from("client version must be 4 ASCII characters"))
+    }
+}
+
+/// Checks that an interval is at least one second.
+fn parse_secs(value: u64) -> Result<u64, String> {
+    if value == 0 {
+        Err(String::from("must be at least 1 second"))
+    } else {
+        Ok(value)
+    }
+}
+
+impl FileConfig {
+    /// Reads and validates a configuration file.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be read or has an invalid setting, an error is returned.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).map_err(|source| Error::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::parse(&contents).map_err(|e| e.with_path(path))
+    }
+
+    fn parse(contents: &str) -> Result<Self, Error> {
+        let config: FileConfig = toml::from_str(contents).map_err(|source| Error::Parse {
+            path: PathBuf::new(),
+            source,
+        })?;
+
+        let invalid = |key, message| Error::Invalid {
+            path: PathBuf::new(),
+            key,
+            message,
+        };
+        if let Some(value) = &config.node_id_policy {
+            value
+                .parse::<NodeIdPolicy>()
+                .map_err(|e| invalid("node_id_policy", e.to_string()))?;
+        }
+        if let Some(value) = &config.client_version {
+            parse_client_version(value).map_err(|e| invalid("client_version", e))?;
+        }
+        for (key, value) in [
+            (
+                "default_query_timeout_secs",
+                config.default_query_timeout_secs,
+            ),
+            (
+                "routing_table_next_response_interval_secs",
+                config.routing_table_next_response_interval_secs,
+            ),
+            (
+                "routing_table_next_query_interval_secs",
+                config.routing_table_next_query_interval_secs,
+            ),
+            ("announce_interval_secs", config.announce_interval_secs),
+        ] {
+            if let Some(value) = value {
+                parse_secs(value).map_err(|e| invalid(key, e))?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Sets the arguments which were not given on the command line or in an
+    /// environment variable to the file's settings.
+    pub fn merge_into(self, args: &mut Args, matches: &ArgMatches) {
+        let is_overridden = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = self.$field {
+                        if !is_overridden(stringify!($field)) {
+                            args.$field = value.into();
+                        }
+                    }
+                )*
+            };
+        }
+
+        merge!(
+            dht_bind,
+            dht_bind6,
+            dht_port,
+            disable_ipv4,
+            disable_ipv6,
+            read_only,
+            http_bind,
+            http_port,
+            bootstrap,
+            signing_key_file,
+            external_ip,
+            regenerate_node_id,
+            state_file,
+            data_dir,
+            client_version,
+            default_query_timeout_secs,
+            strict_response_node_id,
+            routing_table_next_response_interval_secs,
+            routing_table_next_query_interval_secs,
+            announce_interval_secs,
+            ready_min_good_nodes,
+        );
+        if let Some(value) = self.node_id_policy {
+            if !is_overridden("node_id_policy") {
+                args.node_id_policy = value.parse().expect("node ID policy was validated");
+            }
+        }
+    }
+}
+
+impl Error {
+    fn with_path(self, path: &Path) -> Self {
+        let path = path.to_path_buf();
+        match self {
+            Error::Read { source, .. } => Error::Read { path, source },
+            Error::Parse { source, .. } => Error::Parse { path, source },
+            Error::Invalid { key, message, .. } => Error::Invalid { path, key, message },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    #[test]
+    fn test_command_line_overrides_file() {
+        let file = FileConfig::parse(
+            r#"
+            dht_port = 7000
+            read_only = true
+            bootstrap = ["127.0.0.1:6881"]
+            node_id_policy = "enforce"
+            announce_interval_secs = 60
+            "#,
+        )
+        .unwrap();
+
+        let matches = Args::command()
+            .try_get_matches_from(["waynode", "--dht-port", "7001"])
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        file.merge_into(&mut args, &matches);
+
+        assert_eq!(args.dht_port, 7001);
+        assert!(args.read_only);
+        assert_eq!(args.bootstrap, vec![String::from("127.0.0.1:6881")]);
+        assert_eq!(args.node_id_policy, NodeIdPolicy::Enforce);
+        assert_eq!(args.announce_interval_secs, 60);
+        assert_eq!(args.http_port, 8080);
+
+        let matches = Args::command().try_get_matches_from(["waynode"]).unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        assert!(!args.read_only);
+
+        assert!(matches!(
+            FileConfig::parse("dht_prot = 7000"),
+            Err(Error::Parse { .. })
+        ));
+        assert!(matches!(
+            FileConfig::parse("announce_interval_secs = 0"),
+            Err(Error::Invalid {
+                key: "announce_interval_secs",
+                ..
+            })
+        ));
+    }
+}
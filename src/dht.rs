@@ -4,101 +4,259 @@
 //!
 //! | BEP                  | Status |
 //! | ---------------------|--------|
-//! | [BEP 0005][bep_0005] | WIP    |
-//! | [BEP 0032][bep_0032] | -      |
-//! | [BEP 0033][bep_0033] | -      |
-//! | [BEP 0043][bep_0043] | -      |
-//! | [BEP 0044][bep_0044] | -      |
+//! | [BEP 0005][bep_0005] | Done   |
+//! | [BEP 0032][bep_0032] | Done   |
+//! | [BEP 0033][bep_0033] | Done   |
+//! | [BEP 0042][bep_0042] | Done   |
+//! | [BEP 0043][bep_0043] | Done   |
+//! | [BEP 0044][bep_0044] | Done   |
 //! | [BEP 0045][bep_0045] | -      |
-//! | [BEP 0046][bep_0046] | -      |
-//! | [BEP 0051][bep_0051] | -      |
+//! | [BEP 0046][bep_0046] | Done   |
+//! | [BEP 0051][bep_0051] | Done   |
 //!
 //! [bittorrent]: http://bittorrent.org/
 //! [bep_0005]: http://bittorrent.org/beps/bep_0005.html
 //! [bep_0032]: http://bittorrent.org/beps/bep_0032.html
 //! [bep_0033]: http://bittorrent.org/beps/bep_0033.html
+//! [bep_0042]: http://bittorrent.org/beps/bep_0042.html
 //! [bep_0043]: http://bittorrent.org/beps/bep_0043.html
 //! [bep_0044]: http://bittorrent.org/beps/bep_0044.html
 //! [bep_0045]: http://bittorrent.org/beps/bep_0045.html
 //! [bep_0046]: http://bittorrent.org/beps/bep_0046.html
 //! [bep_0051]: http://bittorrent.org/beps/bep_0051.html
 
-// TODO: Configuration for whether node IDs are valid for IP
-
+mod bloom;
+mod external_ip;
 pub mod find_node_op;
-
-use crate::dht::find_node_op::FindNodeOp;
+pub mod get_peers_op;
+pub mod item_op;
+mod item_store;
+mod krpc_ext;
+mod lookup;
+pub mod metrics;
+pub mod mutable_torrent;
+pub mod node_id;
+mod peer_store;
+pub mod sample_infohashes_op;
+mod snapshot;
+pub mod state;
+mod token;
+mod transactions;
+
+use crate::dht::{
+    bloom::BloomFilter,
+    external_ip::ExternalIpVotes,
+    find_node_op::{FindNodeOp, FindNodeOpSnapshot, FindNodeResult},
+    get_peers_op::{AnnounceArgs, AnnouncePeerQuery, GetPeersOp, GetPeersQuery, GetPeersResult},
+    item_op::{GetItemQuery, GetItemResult, Item, ItemOp, PutError, PutItemQuery},
+    item_store::ItemStore,
+    krpc_ext::{
+        GetPeersQueryArgs, GetPeersRespValues, GetQueryArgs, GetRespValues, MsgExt, PutQueryArgs,
+        QueryMsg, RespMsg, SampleInfoHashesRespValues, Want, METHOD_GET, METHOD_PUT,
+        METHOD_SAMPLE_INFOHASHES,
+    },
+    metrics::{Gauges, Metrics},
+    mutable_torrent::MutableTorrentLink,
+    node_id::NodeIdPolicy,
+    peer_store::PeerStore,
+    sample_infohashes_op::{SampleInfoHashesOp, SampleInfoHashesQuery, SampleInfoHashesResult},
+    state::DhtState,
+    token::{Tokens, TOKEN_LEN},
+    transactions::Transactions,
+};
 
 use anyhow::Context;
-use cloudburst::dht::{
-    krpc::{
-        self,
-        find_node::{self, METHOD_FIND_NODE},
-        ping::{self, METHOD_PING},
-        transaction::{self, Transaction, Transactions},
-        CompactAddr, CompactAddrV4, ErrorCode, Msg, QueryArgs, RespValues, Ty,
+use bt_bencode::Value;
+use cloudburst::{
+    dht::{
+        krpc::{
+            self,
+            announce_peer::{self, METHOD_ANNOUNCE_PEER},
+            find_node::{self, METHOD_FIND_NODE},
+            get_peers::{self, METHOD_GET_PEERS},
+            ping::{self, METHOD_PING},
+            transaction::{self, Transaction},
+            CompactAddr, ErrorCode, Msg, QueryArgs, RespValues, Ty,
+        },
+        node::{self, AddrId, AddrOptId, LocalId},
+        routing::{Bucket, Table},
     },
-    node::{self, AddrId, AddrOptId, LocalId},
-    routing::{Bucket, Table},
+    metainfo::InfoHash,
 };
 use core::{fmt, time::Duration};
+use ed25519_dalek::{SigningKey, PUBLIC_KEY_LENGTH};
 use find_node_op::OpsManager;
+use rand::seq::SliceRandom;
 use serde_bytes::Bytes;
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     convert::TryFrom,
     io::{self, Cursor},
-    net::{SocketAddr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
     time::Instant,
 };
 use tokio::{
     net::UdpSocket,
     sync::{mpsc, oneshot},
 };
-use tracing::{debug, error, trace};
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(Debug)]
 pub enum Cmd {
     GetConfig(oneshot::Sender<Config>),
+    UpdateConfig(ConfigUpdate, oneshot::Sender<(Vec<&'static str>, Config)>),
+    SetBootstrapAddrs(Vec<SocketAddr>),
+    GetPeers(InfoHash, oneshot::Sender<GetPeersResult>),
+    Scrape(InfoHash, oneshot::Sender<GetPeersResult>),
+    GetAnnounces(oneshot::Sender<Vec<(InfoHash, AnnounceArgs)>>),
+    Announce(InfoHash, AnnounceArgs),
+    StopAnnounce(InfoHash, oneshot::Sender<bool>),
+    GetItem(node::Id, oneshot::Sender<GetItemResult>),
+    GetMutableItem(
+        [u8; PUBLIC_KEY_LENGTH],
+        Vec<u8>,
+        oneshot::Sender<GetItemResult>,
+    ),
+    PutItem(Value, oneshot::Sender<GetItemResult>),
+    PutMutableItem(Vec<u8>, Value, oneshot::Sender<GetItemResult>),
+    ResolveMutableTorrent(MutableTorrentLink, oneshot::Sender<GetItemResult>),
+    PublishMutableTorrent(Vec<u8>, InfoHash, oneshot::Sender<GetItemResult>),
+    SampleInfoHashes(usize, oneshot::Sender<SampleInfoHashesResult>),
+    GetStatus(oneshot::Sender<Status>),
+    GetRoutingTable(oneshot::Sender<RoutingTableSnapshot>),
+    GetTransactions(oneshot::Sender<Vec<Transaction<CompactAddr, transaction::Id, Instant>>>),
+    GetOps(oneshot::Sender<Vec<FindNodeOpSnapshot>>),
+    FindNode(node::Id, oneshot::Sender<FindNodeResult>),
+    GetMetrics(oneshot::Sender<String>),
+    GetHealth(oneshot::Sender<Health>),
 }
 
 pub(super) async fn dht_task(
-    socket: UdpSocket,
-    node: Node<SocketAddrV4>,
+    socket: Option<UdpSocket>,
+    socket6: Option<UdpSocket>,
+    node: Node<SocketAddr>,
+    data_paths: DataPaths,
     cmd_rx: mpsc::Receiver<Cmd>,
     completion_tx: oneshot::Sender<()>,
 ) -> io::Result<()> {
-    let result = dht_handler(socket, node, cmd_rx).await;
+    let sockets = Sockets {
+        v4: socket,
+        v6: socket6,
+    };
+    let result = dht_handler(sockets, node, data_paths, cmd_rx).await;
 
     let _ = completion_tx.send(());
 
     result
 }
 
+/// The sockets which the local node sends and receives messages on.
+#[derive(Debug)]
+struct Sockets {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl Sockets {
+    /// Sends data to an address using the socket for the address family.
+    ///
+    /// If there is no socket for the address family, the data is dropped.
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let socket = match addr {
+            SocketAddr::V4(_) => self.v4.as_ref(),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        };
+        match socket {
+            Some(socket) => socket.send_to(buf, addr).await,
+            None => {
+                warn!(%addr, "no socket to send to address");
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// Receives from a socket if it exists, otherwise never completes.
+async fn recv_from(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => core::future::pending().await,
+    }
+}
+
+/// The files which the node's data is kept in across restarts.
+#[derive(Debug, Clone, Default)]
+pub struct DataPaths {
+    /// The file with the local node ID and the routing table nodes
+    pub state_file: Option<PathBuf>,
+    /// The directory with the announced peers and stored items
+    pub data_dir: Option<PathBuf>,
+}
+
+/// Writes the node's state and stored data to the data paths.
+fn save_data(node: &Node<SocketAddr>, data_paths: &DataPaths, now: Instant) {
+    if let Some(path) = &data_paths.state_file {
+        let state = node.state(now);
+        match state.save(path) {
+            Ok(()) => {
+                debug!(path = %path.display(), nodes = state.addr_ids.len(), "saved DHT state");
+            }
+            Err(e) => {
+                warn!(%e, path = %path.display(), "could not save DHT state");
+            }
+        }
+    }
+
+    if let Some(dir) = &data_paths.data_dir {
+        match node.save_stores(dir, now) {
+            Ok(()) => {
+                debug!(dir = %dir.display(), "saved peers and items");
+            }
+            Err(e) => {
+                warn!(%e, dir = %dir.display(), "could not save peers and items");
+            }
+        }
+    }
+}
+
 async fn dht_handler(
-    socket: UdpSocket,
-    mut node: Node<SocketAddrV4>,
+    sockets: Sockets,
+    mut node: Node<SocketAddr>,
+    data_paths: DataPaths,
     mut cmd_rx: mpsc::Receiver<Cmd>,
 ) -> io::Result<()> {
     let mut read_buf = vec![0; 4096];
+    let mut read_buf6 = vec![0; 4096];
     let mut write_buf = vec![0; 4096];
+    let mut save_data_deadline = Instant::now() + SAVE_DATA_INTERVAL;
 
     loop {
-        send_find_node_queries(&mut node, &socket, &mut write_buf, Instant::now()).await?;
+        send_find_node_queries(&mut node, &sockets, &mut write_buf, Instant::now()).await?;
+        send_get_peers_queries(&mut node, &sockets, &mut write_buf, Instant::now()).await?;
+        send_announce_peer_queries(&mut node, &sockets, &mut write_buf).await?;
+        send_get_item_queries(&mut node, &sockets, &mut write_buf, Instant::now()).await?;
+        send_put_item_queries(&mut node, &sockets, &mut write_buf).await?;
+        send_sample_infohashes_queries(&mut node, &sockets, &mut write_buf, Instant::now()).await?;
 
         let now = Instant::now();
         let timeout_deadline = node.timeout().map_or(
             tokio::time::Instant::from(now) + Duration::from_secs(60),
             tokio::time::Instant::from,
         );
+        let timeout_deadline = timeout_deadline.min(tokio::time::Instant::from(save_data_deadline));
         trace!(?now, ?timeout_deadline, "polling");
 
         let sleep = tokio::time::sleep_until(timeout_deadline);
         tokio::pin!(sleep);
 
         tokio::select! {
-            res = socket.recv_from(&mut read_buf) => {
-                on_recv(&mut node, &socket, &read_buf, &mut write_buf, res, Instant::now()).await?;
+            res = recv_from(sockets.v4.as_ref(), &mut read_buf) => {
+                on_recv(&mut node, &sockets, &read_buf, &mut write_buf, res, Instant::now()).await?;
+            }
+            res = recv_from(sockets.v6.as_ref(), &mut read_buf6) => {
+                on_recv(&mut node, &sockets, &read_buf6, &mut write_buf, res, Instant::now()).await?;
             }
             cmd = cmd_rx.recv() => {
                 match cmd {
@@ -107,10 +265,80 @@ async fn dht_handler(
                             Cmd::GetConfig(tx) => {
                                 let _ = tx.send(node.config.clone());
                             }
+                            Cmd::UpdateConfig(update, tx) => {
+                                let changed = node.update_config(update);
+                                let _ = tx.send((changed, node.config.clone()));
+                            }
+                            Cmd::SetBootstrapAddrs(bootstrap_addrs) => {
+                                node.set_bootstrap_addrs(bootstrap_addrs, Instant::now());
+                            }
+                            Cmd::GetStatus(tx) => {
+                                let _ = tx.send(node.status());
+                            }
+                            Cmd::GetRoutingTable(tx) => {
+                                let _ = tx.send(node.routing_table_snapshot(Instant::now()));
+                            }
+                            Cmd::GetTransactions(tx) => {
+                                let _ = tx.send(node.transactions().collect());
+                            }
+                            Cmd::GetOps(tx) => {
+                                let _ = tx.send(node.find_node_ops().collect());
+                            }
+                            Cmd::GetPeers(info_hash, tx) => {
+                                node.get_peers(info_hash, Some(tx), Instant::now());
+                            }
+                            Cmd::Scrape(info_hash, tx) => {
+                                node.scrape(info_hash, tx, Instant::now());
+                            }
+                            Cmd::GetAnnounces(tx) => {
+                                let _ = tx.send(node.announces().collect());
+                            }
+                            Cmd::Announce(info_hash, args) => {
+                                node.announce(info_hash, args, Instant::now());
+                            }
+                            Cmd::StopAnnounce(info_hash, tx) => {
+                                let _ = tx.send(node.stop_announce(&info_hash));
+                            }
+                            Cmd::GetItem(target, tx) => {
+                                node.get_item(target, tx, Instant::now());
+                            }
+                            Cmd::GetMutableItem(k, salt, tx) => {
+                                node.get_mutable_item(&k, salt, tx, Instant::now());
+                            }
+                            Cmd::PutItem(value, tx) => {
+                                if let Err(e) = node.put_immutable_item(value, tx, Instant::now()) {
+                                    warn!(%e, "cannot put item");
+                                }
+                            }
+                            Cmd::PutMutableItem(salt, value, tx) => {
+                                if let Err(e) = node.put_mutable_item(salt, value, tx, Instant::now()) {
+                                    warn!(%e, "cannot put mutable item");
+                                }
+                            }
+                            Cmd::ResolveMutableTorrent(link, tx) => {
+                                node.get_mutable_item(&link.public_key, link.salt, tx, Instant::now());
+                            }
+                            Cmd::PublishMutableTorrent(salt, info_hash, tx) => {
+                                if let Err(e) = node.put_mutable_item(salt, mutable_torrent::value(info_hash), tx, Instant::now()) {
+                                    warn!(%e, "cannot publish mutable torrent");
+                                }
+                            }
+                            Cmd::SampleInfoHashes(max_nodes, tx) => {
+                                node.sample_infohashes(max_nodes, tx, Instant::now());
+                            }
+                            Cmd::FindNode(target_id, tx) => {
+                                node.lookup_node(target_id, tx, Instant::now());
+                            }
+                            Cmd::GetMetrics(tx) => {
+                                let _ = tx.send(node.render_metrics(Instant::now()));
+                            }
+                            Cmd::GetHealth(tx) => {
+                                let _ = tx.send(node.health(Instant::now()));
+                            }
                         }
                     }
                     None => {
-                        // TODO: Serialize the DHT state
+                        save_data(&node, &data_paths, Instant::now());
                         return Ok(());
                     }
                 }
@@ -132,15 +360,20 @@ async fn dht_handler(
                     // considered them timed out if they match the read event
                 }
 
-                send_pings_to_nodes(&mut node, &socket, &mut write_buf, now).await?;
+                send_pings_to_nodes(&mut node, &sockets, &mut write_buf, now).await?;
+
+                if save_data_deadline <= now {
+                    save_data(&node, &data_paths, now);
+                    save_data_deadline = now + SAVE_DATA_INTERVAL;
+                }
             }
         };
     }
 }
 
 async fn on_recv(
-    node: &mut Node<SocketAddrV4>,
-    socket: &UdpSocket,
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
     read_buf: &[u8],
     write_buf: &mut [u8],
     recv_from_result: io::Result<(usize, SocketAddr)>,
@@ -163,33 +396,54 @@ async fn on_recv(
     let filled_buf = &read_buf[..bytes_read];
 
     if let Ok(msg) = bt_bencode::from_slice::<Msg<'_>>(filled_buf) {
-        match src_addr {
-            SocketAddr::V6(_) => {}
-            SocketAddr::V4(src_addr) => match node.on_recv(&msg, src_addr) {
-                Ok((addr_opt_id, _existing_tx)) => {
-                    if let Ty::Query = msg.ty() {
-                        reply_to_query(node, socket, addr_opt_id, &msg, write_buf, now).await?;
+        let msg_ext = MsgExt::from_slice(filled_buf);
+        let result = node.on_recv(
+            &msg,
+            src_addr,
+            msg_ext.is_read_only(),
+            msg_ext.external_addr(),
+        );
+        let method = match (msg.ty(), &result) {
+            (Ty::Query, _) => msg.method_name(),
+            (_, Ok((_, Some((_, method))))) => Some(*method),
+            _ => None,
+        };
+        node.metrics_mut().on_recv(
+            metrics::method_label(method),
+            metrics::ty_label(msg.ty()),
+            bytes_read,
+        );
+        match result {
+            Ok((addr_opt_id, _existing_tx)) => {
+                if let Ty::Query = msg.ty() {
+                    if node.config().is_read_only_node {
+                        trace!(%src_addr, "read only node is not replying to query");
+                    } else {
+                        reply_to_query(node, sockets, addr_opt_id, &msg, write_buf, now).await?;
                     }
                 }
-                Err(e) => {
-                    error!(?e, "on_recv error");
-                }
-            },
+            }
+            Err(e) => {
+                error!(?e, "on_recv error");
+            }
         }
+    } else {
+        trace!(%src_addr, "could not parse message");
+        node.metrics_mut().on_parse_failure();
     }
     Ok(())
 }
 
 async fn reply_to_query(
-    node: &Node<SocketAddrV4>,
-    socket: &UdpSocket,
-    addr_opt_id: AddrOptId<SocketAddrV4>,
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
+    addr_opt_id: AddrOptId<SocketAddr>,
     msg: &Msg<'_>,
     write_buf: &mut [u8],
     now: Instant,
 ) -> io::Result<()> {
-    async fn send_to_socket(buf: &[u8], addr: SocketAddrV4, socket: &UdpSocket) -> io::Result<()> {
-        match socket.send_to(buf, addr).await {
+    async fn send_to_socket(buf: &[u8], addr: SocketAddr, sockets: &Sockets) -> io::Result<()> {
+        match sockets.send_to(buf, addr).await {
             Ok(_) => Ok(()),
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
@@ -202,17 +456,43 @@ async fn reply_to_query(
         }
     }
 
+    let AddrOptId { addr, id: _ } = addr_opt_id;
+    let Some((end, ty)) = write_reply(node, addr, msg, write_buf, now)? else {
+        return Ok(());
+    };
+    send_to_socket(&write_buf[..end], addr, sockets).await?;
+
+    node.metrics_mut().on_reply_sent(
+        metrics::method_label(msg.method_name()),
+        metrics::ty_label(ty),
+        end,
+    );
+    Ok(())
+}
+
+/// Writes the reply to a query into the buffer.
+///
+/// Returns the length and type of the reply, or `None` if the query should
+/// not be answered.
+fn write_reply(
+    node: &mut Node<SocketAddr>,
+    addr: SocketAddr,
+    msg: &Msg<'_>,
+    write_buf: &mut [u8],
+    now: Instant,
+) -> io::Result<Option<(usize, Ty)>> {
     let method_name = msg.method_name_str();
     debug!(?method_name, "received query");
 
     let mut cursor = Cursor::new(write_buf);
-    let AddrOptId { addr, id: _ } = addr_opt_id;
+    let mut is_error = false;
 
     match msg.method_name() {
         Some(METHOD_PING) => {
             bt_bencode::to_writer(
                 &mut cursor,
-                &krpc::ser::RespMsg {
+                &RespMsg {
+                    ip: CompactAddr::from(addr),
                     r: ping::RespValues::new(&node.config().local_id()),
                     t: Bytes::new(msg.tx_id()),
                     v: node.config().client_version(),
@@ -224,26 +504,17 @@ async fn reply_to_query(
         Some(METHOD_FIND_NODE) => {
             if let Some(Ok(query_args)) = msg.args::<find_node::QueryArgs<'_>>() {
                 if let Some(target) = query_args.target() {
-                    let mut nodes = Vec::with_capacity(8 * 26);
-                    for neighbor in node.find_neighbors(target, now).take(8) {
-                        let AddrId { addr, id } = neighbor;
-                        nodes.extend_from_slice(&id.0);
-                        nodes.extend_from_slice(&CompactAddrV4::from(addr).0);
-                    }
-
-                    if !nodes.is_empty() {
-                        while nodes.len() < 8 * 26 {
-                            nodes.extend_from_within(0..26);
-                        }
-                    }
+                    let want = Want::from_query(msg, &CompactAddr::from(addr));
+                    let (nodes, nodes6) = node.compact_neighbors(target, want);
 
                     bt_bencode::to_writer(
                         &mut cursor,
-                        &krpc::ser::RespMsg {
+                        &RespMsg {
+                            ip: CompactAddr::from(addr),
                             r: find_node::RespValues::new(
                                 &node.config().local_id(),
-                                Some(Bytes::new(&nodes)),
-                                None,
+                                nodes.as_deref().map(Bytes::new),
+                                nodes6.as_deref().map(Bytes::new),
                             ),
                             t: Bytes::new(msg.tx_id()),
                             v: node.config().client_version(),
@@ -252,13 +523,247 @@ async fn reply_to_query(
 
                     debug!(%addr, tx_id = ?msg.tx_id(), "sending find node response reply");
                 } else {
-                    return Ok(());
+                    return Ok(None);
                 }
             } else {
-                return Ok(());
+                return Ok(None);
+            }
+        }
+        Some(METHOD_GET_PEERS) => {
+            if let Some(Ok(query_args)) = msg.args::<get_peers::QueryArgs<'_>>() {
+                if let Some(info_hash) = query_args.info_hash() {
+                    let token = node.announce_token(addr.ip());
+
+                    let mut values = node
+                        .peers(&info_hash, now)
+                        .filter(|peer| addr.is_ipv4() == matches!(peer, CompactAddr::V4(_)))
+                        .collect::<Vec<_>>();
+                    if values.len() > MAX_PEER_VALUES {
+                        values.shuffle(&mut rand::thread_rng());
+                        values.truncate(MAX_PEER_VALUES);
+                    }
+
+                    let (nodes, nodes6) = if values.is_empty() {
+                        let want = Want::from_query(msg, &CompactAddr::from(addr));
+                        node.compact_neighbors(node::Id::from(info_hash.0), want)
+                    } else {
+                        (None, None)
+                    };
+
+                    let scrape =
+                        krpc_ext::is_scrape(msg).then(|| node.scrape_peers(&info_hash, now));
+
+                    bt_bencode::to_writer(
+                        &mut cursor,
+                        &RespMsg {
+                            ip: CompactAddr::from(addr),
+                            r: GetPeersRespValues::new(
+                                &node.config().local_id(),
+                                &token,
+                                (!values.is_empty()).then_some(values),
+                                nodes.as_deref().map(Bytes::new),
+                                nodes6.as_deref().map(Bytes::new),
+                                scrape.as_ref(),
+                            ),
+                            t: Bytes::new(msg.tx_id()),
+                            v: node.config().client_version(),
+                        },
+                    )?;
+
+                    debug!(%addr, tx_id = ?msg.tx_id(), "sending get peers response reply");
+                } else {
+                    return Ok(None);
+                }
+            } else {
+                return Ok(None);
+            }
+        }
+        Some(METHOD_ANNOUNCE_PEER) => {
+            if let Some(Ok(query_args)) = msg.args::<announce_peer::QueryArgs<'_>>() {
+                let Some(info_hash) = query_args.info_hash() else {
+                    return Ok(None);
+                };
+
+                let port = if query_args.implied_port() == Some(true) {
+                    Some(addr.port())
+                } else {
+                    query_args.port
+                };
+
+                if !node.is_valid_announce_token(query_args.token(), addr.ip()) {
+                    is_error = true;
+                    bt_bencode::to_writer(
+                        &mut cursor,
+                        &krpc::ser::ErrMsg {
+                            e: (ErrorCode::ProtocolError, "bad token"),
+                            t: Bytes::new(msg.tx_id()),
+                            v: node.config().client_version(),
+                        },
+                    )?;
+
+                    debug!(%addr, tx_id = ?msg.tx_id(), "sending bad token reply");
+                } else if let Some(port) = port {
+                    node.insert_peer(
+                        info_hash,
+                        CompactAddr::from(SocketAddr::new(addr.ip(), port)),
+                        krpc_ext::is_seed(msg),
+                        now,
+                    );
+
+                    bt_bencode::to_writer(
+                        &mut cursor,
+                        &RespMsg {
+                            ip: CompactAddr::from(addr),
+                            r: announce_peer::RespValues::new(&node.config().local_id()),
+                            t: Bytes::new(msg.tx_id()),
+                            v: node.config().client_version(),
+                        },
+                    )?;
+
+                    debug!(%addr, tx_id = ?msg.tx_id(), %info_hash, %port, "sending announce peer response reply");
+                } else {
+                    is_error = true;
+                    bt_bencode::to_writer(
+                        &mut cursor,
+                        &krpc::ser::ErrMsg {
+                            e: (ErrorCode::ProtocolError, "missing port"),
+                            t: Bytes::new(msg.tx_id()),
+                            v: node.config().client_version(),
+                        },
+                    )?;
+
+                    debug!(%addr, tx_id = ?msg.tx_id(), "sending missing port reply");
+                }
+            } else {
+                return Ok(None);
+            }
+        }
+        Some(METHOD_GET) => {
+            if let Some(Ok(query_args)) = msg.args::<GetQueryArgs<'_>>() {
+                let Some(target) = query_args.target() else {
+                    return Ok(None);
+                };
+
+                let token = node.announce_token(addr.ip());
+                let want = Want::from_query(msg, &CompactAddr::from(addr));
+                let (nodes, nodes6) = node.compact_neighbors(target, want);
+                let local_id = node.config().local_id();
+
+                let mut resp = GetRespValues::new(
+                    &local_id,
+                    &token,
+                    nodes.as_deref().map(Bytes::new),
+                    nodes6.as_deref().map(Bytes::new),
+                );
+                if let Some(item) = node.item(&target, now) {
+                    match (item.seq(), query_args.seq()) {
+                        (Some(seq), Some(query_seq)) if seq <= query_seq => resp.set_seq(seq),
+                        _ => resp.set_item(item),
+                    }
+                }
+
+                bt_bencode::to_writer(
+                    &mut cursor,
+                    &RespMsg {
+                        ip: CompactAddr::from(addr),
+                        r: resp,
+                        t: Bytes::new(msg.tx_id()),
+                        v: node.config().client_version(),
+                    },
+                )?;
+
+                debug!(%addr, tx_id = ?msg.tx_id(), %target, "sending get response reply");
+            } else {
+                return Ok(None);
+            }
+        }
+        Some(METHOD_PUT) => {
+            if let Some(Ok(query_args)) = msg.args::<PutQueryArgs<'_>>() {
+                if !node.is_valid_announce_token(query_args.token(), addr.ip()) {
+                    is_error = true;
+                    bt_bencode::to_writer(
+                        &mut cursor,
+                        &krpc::ser::ErrMsg {
+                            e: (ErrorCode::ProtocolError, "bad token"),
+                            t: Bytes::new(msg.tx_id()),
+                            v: node.config().client_version(),
+                        },
+                    )?;
+
+                    debug!(%addr, tx_id = ?msg.tx_id(), "sending bad token reply");
+                } else {
+                    let cas = query_args.cas();
+                    match query_args
+                        .into_item()
+                        .and_then(|item| node.insert_item(item, cas, now))
+                    {
+                        Ok(target) => {
+                            bt_bencode::to_writer(
+                                &mut cursor,
+                                &RespMsg {
+                                    ip: CompactAddr::from(addr),
+                                    r: ping::RespValues::new(&node.config().local_id()),
+                                    t: Bytes::new(msg.tx_id()),
+                                    v: node.config().client_version(),
+                                },
+                            )?;
+
+                            debug!(%addr, tx_id = ?msg.tx_id(), %target, "sending put response reply");
+                        }
+                        Err(e) => {
+                            is_error = true;
+                            bt_bencode::to_writer(
+                                &mut cursor,
+                                &krpc::ser::ErrMsg {
+                                    e: (e.code(), e.to_string()),
+                                    t: Bytes::new(msg.tx_id()),
+                                    v: node.config().client_version(),
+                                },
+                            )?;
+
+                            debug!(%addr, tx_id = ?msg.tx_id(), %e, "sending put error reply");
+                        }
+                    }
+                }
+            } else {
+                return Ok(None);
+            }
+        }
+        Some(METHOD_SAMPLE_INFOHASHES) => {
+            if let Some(Ok(query_args)) = msg.args::<find_node::QueryArgs<'_>>() {
+                let Some(target) = query_args.target() else {
+                    return Ok(None);
+                };
+
+                let want = Want::from_query(msg, &CompactAddr::from(addr));
+                let (nodes, nodes6) = node.compact_neighbors(target, want);
+                let (samples, num) = node.info_hash_samples(now);
+                let samples = samples.iter().flat_map(|s| s.0).collect::<Vec<_>>();
+
+                bt_bencode::to_writer(
+                    &mut cursor,
+                    &RespMsg {
+                        ip: CompactAddr::from(addr),
+                        r: SampleInfoHashesRespValues::new(
+                            &node.config().local_id(),
+                            i64::try_from(SAMPLE_INFOHASHES_INTERVAL.as_secs()).unwrap_or(i64::MAX),
+                            nodes.as_deref().map(Bytes::new),
+                            nodes6.as_deref().map(Bytes::new),
+                            i64::try_from(num).unwrap_or(i64::MAX),
+                            &samples,
+                        ),
+                        t: Bytes::new(msg.tx_id()),
+                        v: node.config().client_version(),
+                    },
+                )?;
+
+                debug!(%addr, tx_id = ?msg.tx_id(), %num, "sending sample infohashes response reply");
+            } else {
+                return Ok(None);
             }
         }
         Some(method_name) => {
+            is_error = true;
             bt_bencode::to_writer(
                 &mut cursor,
                 &krpc::ser::ErrMsg {
@@ -274,6 +779,7 @@ async fn reply_to_query(
             debug!(%addr, tx_id = ?msg.tx_id(), "sending unknown method reply");
         }
         None => {
+            is_error = true;
             bt_bencode::to_writer(
                 &mut cursor,
                 &krpc::ser::ErrMsg {
@@ -289,18 +795,34 @@ async fn reply_to_query(
     }
 
     let end = usize::try_from(cursor.position()).expect("wrote too much data in reply");
-    let write_buf = cursor.into_inner();
-    send_to_socket(&write_buf[..end], addr, socket).await
+    let ty = if is_error { Ty::Error } else { Ty::Response };
+    Ok(Some((end, ty)))
+}
+
+/// Encodes nodes as concatenated node IDs and compact addresses.
+fn compact_nodes<I, Addr>(neighbors: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = AddrId<Addr>>,
+    Addr: Into<CompactAddr>,
+{
+    let mut nodes = Vec::new();
+    for neighbor in neighbors {
+        let AddrId { addr, id } = neighbor;
+        nodes.extend_from_slice(&id.0);
+        nodes.extend_from_slice(addr.into().as_ref());
+    }
+    nodes
 }
 
 async fn send_pings_to_nodes(
-    node: &mut Node<SocketAddrV4>,
-    socket: &UdpSocket,
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
     mut write_buf: &mut [u8],
     now: Instant,
 ) -> io::Result<()> {
     let local_id = node.config().local_id();
     let client_version = node.config().client_version().map(<[u8]>::to_vec);
+    let is_read_only_node = node.config().is_read_only_node;
     let query_args = ping::QueryArgs::new(&local_id);
     let ping_method = Bytes::new(METHOD_PING);
     loop {
@@ -315,9 +837,10 @@ async fn send_pings_to_nodes(
 
             bt_bencode::to_writer(
                 &mut cursor,
-                &krpc::ser::QueryMsg {
+                &QueryMsg {
                     a: &query_args,
                     q: ping_method,
+                    ro: is_read_only_node,
                     t: Bytes::new(tx_id.as_ref()),
                     v: client_version.as_deref(),
                 },
@@ -326,7 +849,7 @@ async fn send_pings_to_nodes(
             let end = usize::try_from(cursor.position()).expect("wrote too much data");
             write_buf = cursor.into_inner();
 
-            match socket.send_to(&write_buf[..end], addr).await {
+            match sockets.send_to(&write_buf[..end], addr).await {
                 Ok(v) => v,
                 Err(e) => {
                     if e.kind() == io::ErrorKind::WouldBlock {
@@ -340,6 +863,11 @@ async fn send_pings_to_nodes(
 
             node_to_ping.on_ping(tx_id);
 
+            node.metrics_mut().on_sent(
+                metrics::method_label(Some(METHOD_PING)),
+                metrics::ty_label(Ty::Query),
+                end,
+            );
             node.insert_tx(Transaction::new(
                 addr_id.into(),
                 tx_id,
@@ -355,16 +883,13 @@ async fn send_pings_to_nodes(
 }
 
 async fn send_find_node_queries(
-    node: &mut Node<SocketAddrV4>,
-    socket: &UdpSocket,
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
     mut write_buf: &mut [u8],
     now: Instant,
 ) -> io::Result<()> {
     while let Some((target_id, addr_opt_id)) = node.next_find_node_query(now) {
-        let addr: SocketAddrV4 = match addr_opt_id.addr() {
-            CompactAddr::V4(addr) => (*addr).into(),
-            CompactAddr::V6(_) => continue,
-        };
+        let addr = SocketAddr::from(*addr_opt_id.addr());
 
         let tx_id = node.next_tx_id(&mut rand::thread_rng())?;
         debug!(%addr, ?tx_id, %target_id, "sending find node query");
@@ -373,9 +898,10 @@ async fn send_find_node_queries(
 
         bt_bencode::to_writer(
             &mut cursor,
-            &krpc::ser::QueryMsg {
+            &QueryMsg {
                 a: &find_node::QueryArgs::new(&node.config().local_id(), &target_id),
                 q: Bytes::new(METHOD_FIND_NODE),
+                ro: node.config().is_read_only_node,
                 t: Bytes::new(tx_id.as_ref()),
                 v: node.config().client_version(),
             },
@@ -384,7 +910,7 @@ async fn send_find_node_queries(
         let end = usize::try_from(cursor.position()).expect("wrote too much data");
         write_buf = cursor.into_inner();
 
-        match socket.send_to(&write_buf[..end], addr).await {
+        match sockets.send_to(&write_buf[..end], addr).await {
             Ok(v) => v,
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
@@ -396,6 +922,11 @@ async fn send_find_node_queries(
             }
         };
 
+        node.metrics_mut().on_sent(
+            metrics::method_label(Some(METHOD_FIND_NODE)),
+            metrics::ty_label(Ty::Query),
+            end,
+        );
         node.insert_tx(Transaction::new(
             AddrOptId::new(addr, addr_opt_id.id()),
             tx_id,
@@ -408,599 +939,2789 @@ async fn send_find_node_queries(
     Ok(())
 }
 
-/// The configuration for the local DHT node.
-#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
-pub struct Config {
-    /// Local node id
-    pub local_id: LocalId,
-    /// Client version identifier
-    pub client_version: Option<Vec<u8>>,
-    /// The default amount of time before a query without a response is considered timed out
-    pub default_query_timeout: Duration,
-    /// If the node is read only
-    pub is_read_only_node: bool,
-    /// If responses from queried nodes are strictly checked for expected node ID
-    pub is_response_queried_node_id_strictly_checked: bool,
-    pub routing_table_next_response_interval: Duration,
-    pub routing_table_next_query_interval: Duration,
-}
+async fn send_get_peers_queries(
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
+    mut write_buf: &mut [u8],
+    now: Instant,
+) -> io::Result<()> {
+    while let Some(query) = node.next_get_peers_query(now) {
+        let GetPeersQuery {
+            info_hash,
+            addr_opt_id,
+            scrape,
+        } = query;
+        let addr = SocketAddr::from(*addr_opt_id.addr());
 
-impl Config {
-    /// Instantiate a new config with a node's local Id.
-    pub fn new<I>(id: I) -> Self
-    where
-        I: Into<LocalId>,
-    {
-        Self {
-            local_id: id.into(),
-            client_version: None,
-            default_query_timeout: Duration::from_secs(5 * 60),
-            is_read_only_node: false,
-            is_response_queried_node_id_strictly_checked: true,
-            routing_table_next_response_interval: Duration::from_secs(15 * 60),
-            routing_table_next_query_interval: Duration::from_secs(15 * 60),
-        }
-    }
+        let tx_id = node.next_tx_id(&mut rand::thread_rng())?;
+        debug!(%addr, ?tx_id, %info_hash, scrape, "sending get peers query");
 
-    /// Returns the node's local Id.
-    #[must_use]
-    pub fn local_id(&self) -> LocalId {
-        self.local_id
-    }
+        let mut cursor = Cursor::new(write_buf);
 
-    /// Returns the client version.
-    #[must_use]
-    pub fn client_version(&self) -> Option<&[u8]> {
-        self.client_version.as_deref()
-    }
+        bt_bencode::to_writer(
+            &mut cursor,
+            &QueryMsg {
+                a: &GetPeersQueryArgs::new(&node.config().local_id(), &info_hash, scrape),
+                q: Bytes::new(METHOD_GET_PEERS),
+                ro: node.config().is_read_only_node,
+                t: Bytes::new(tx_id.as_ref()),
+                v: node.config().client_version(),
+            },
+        )?;
 
-    /// Sets the client version.
-    pub fn set_client_version<I>(&mut self, client_version: I)
-    where
-        I: Into<Option<Vec<u8>>>,
-    {
-        self.client_version = client_version.into();
-    }
+        let end = usize::try_from(cursor.position()).expect("wrote too much data");
+        write_buf = cursor.into_inner();
 
-    /// Returns the default query timeout.
-    #[must_use]
-    pub fn default_query_timeout(&self) -> Duration {
-        self.default_query_timeout
-    }
+        match sockets.send_to(&write_buf[..end], addr).await {
+            Ok(v) => v,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
 
-    /// Set to true if the node is read only, false otherwise.
-    pub fn set_is_read_only_node(&mut self, is_read_only_node: bool) {
-        self.is_read_only_node = is_read_only_node;
+                error!(%e, "send_to io error");
+                return Err(e);
+            }
+        };
+
+        node.metrics_mut().on_sent(
+            metrics::method_label(Some(METHOD_GET_PEERS)),
+            metrics::ty_label(Ty::Query),
+            end,
+        );
+        node.insert_tx(Transaction::new(
+            AddrOptId::new(addr, addr_opt_id.id()),
+            tx_id,
+            METHOD_GET_PEERS,
+            Instant::now() + node.config().default_query_timeout(),
+        ));
+        node.insert_tx_for_get_peers(tx_id, info_hash, addr_opt_id);
     }
+
+    Ok(())
 }
 
-const FIND_LOCAL_ID_INTERVAL: Duration = Duration::from_secs(3 * 60);
+async fn send_announce_peer_queries(
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
+    mut write_buf: &mut [u8],
+) -> io::Result<()> {
+    while let Some(query) = node.next_announce_peer_query() {
+        let addr = SocketAddr::from(*query.addr_id.addr());
+
+        let tx_id = node.next_tx_id(&mut rand::thread_rng())?;
+        debug!(%addr, ?tx_id, info_hash = %query.info_hash, "sending announce peer query");
+
+        let mut cursor = Cursor::new(write_buf);
+
+        bt_bencode::to_writer(
+            &mut cursor,
+            &QueryMsg {
+                a: &announce_peer::QueryArgs::new(
+                    &node.config().local_id(),
+                    &query.info_hash,
+                    Bytes::new(&query.token),
+                    Some(query.args.port),
+                    query.args.implied_port.then_some(true),
+                ),
+                q: Bytes::new(METHOD_ANNOUNCE_PEER),
+                ro: node.config().is_read_only_node,
+                t: Bytes::new(tx_id.as_ref()),
+                v: node.config().client_version(),
+            },
+        )?;
+
+        let end = usize::try_from(cursor.position()).expect("wrote too much data");
+        write_buf = cursor.into_inner();
+
+        match sockets.send_to(&write_buf[..end], addr).await {
+            Ok(v) => v,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+
+                error!(%e, "send_to io error");
+                return Err(e);
+            }
+        };
+
+        node.metrics_mut().on_sent(
+            metrics::method_label(Some(METHOD_ANNOUNCE_PEER)),
+            metrics::ty_label(Ty::Query),
+            end,
+        );
+        node.insert_tx(Transaction::new(
+            AddrOptId::new(addr, Some(query.addr_id.id())),
+            tx_id,
+            METHOD_ANNOUNCE_PEER,
+            Instant::now() + node.config().default_query_timeout(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn send_get_item_queries(
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
+    mut write_buf: &mut [u8],
+    now: Instant,
+) -> io::Result<()> {
+    while let Some(query) = node.next_get_item_query(now) {
+        let GetItemQuery {
+            target,
+            addr_opt_id,
+        } = query;
+        let addr = SocketAddr::from(*addr_opt_id.addr());
+
+        let tx_id = node.next_tx_id(&mut rand::thread_rng())?;
+        debug!(%addr, ?tx_id, %target, "sending get query");
+
+        let mut cursor = Cursor::new(write_buf);
+
+        bt_bencode::to_writer(
+            &mut cursor,
+            &QueryMsg {
+                a: &GetQueryArgs::new(&node.config().local_id(), &target),
+                q: Bytes::new(METHOD_GET),
+                ro: node.config().is_read_only_node,
+                t: Bytes::new(tx_id.as_ref()),
+                v: node.config().client_version(),
+            },
+        )?;
+
+        let end = usize::try_from(cursor.position()).expect("wrote too much data");
+        write_buf = cursor.into_inner();
+
+        match sockets.send_to(&write_buf[..end], addr).await {
+            Ok(v) => v,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+
+                error!(%e, "send_to io error");
+                return Err(e);
+            }
+        };
+
+        node.metrics_mut().on_sent(
+            metrics::method_label(Some(METHOD_GET)),
+            metrics::ty_label(Ty::Query),
+            end,
+        );
+        node.insert_tx(Transaction::new(
+            AddrOptId::new(addr, addr_opt_id.id()),
+            tx_id,
+            METHOD_GET,
+            Instant::now() + node.config().default_query_timeout(),
+        ));
+        node.insert_tx_for_get_item(tx_id, target, addr_opt_id);
+    }
+
+    Ok(())
+}
+
+async fn send_put_item_queries(
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
+    mut write_buf: &mut [u8],
+) -> io::Result<()> {
+    while let Some(query) = node.next_put_item_query() {
+        let PutItemQuery {
+            target,
+            addr_id,
+            token,
+            item,
+            cas,
+        } = query;
+        let addr = SocketAddr::from(*addr_id.addr());
+
+        let tx_id = node.next_tx_id(&mut rand::thread_rng())?;
+        debug!(%addr, ?tx_id, %target, "sending put query");
+
+        let mut cursor = Cursor::new(write_buf);
+
+        bt_bencode::to_writer(
+            &mut cursor,
+            &QueryMsg {
+                a: &PutQueryArgs::new(&node.config().local_id(), &token, &item, cas),
+                q: Bytes::new(METHOD_PUT),
+                ro: node.config().is_read_only_node,
+                t: Bytes::new(tx_id.as_ref()),
+                v: node.config().client_version(),
+            },
+        )?;
+
+        let end = usize::try_from(cursor.position()).expect("wrote too much data");
+        write_buf = cursor.into_inner();
+
+        match sockets.send_to(&write_buf[..end], addr).await {
+            Ok(v) => v,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+
+                error!(%e, "send_to io error");
+                return Err(e);
+            }
+        };
+
+        node.metrics_mut().on_sent(
+            metrics::method_label(Some(METHOD_PUT)),
+            metrics::ty_label(Ty::Query),
+            end,
+        );
+        node.insert_tx(Transaction::new(
+            AddrOptId::new(addr, Some(addr_id.id())),
+            tx_id,
+            METHOD_PUT,
+            Instant::now() + node.config().default_query_timeout(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn send_sample_infohashes_queries(
+    node: &mut Node<SocketAddr>,
+    sockets: &Sockets,
+    mut write_buf: &mut [u8],
+    now: Instant,
+) -> io::Result<()> {
+    while let Some(query) = node.next_sample_infohashes_query(now) {
+        let SampleInfoHashesQuery { addr_opt_id } = query;
+        let addr = SocketAddr::from(*addr_opt_id.addr());
+        let target = node::Id::rand(&mut rand::thread_rng())?;
+
+        let tx_id = node.next_tx_id(&mut rand::thread_rng())?;
+        debug!(%addr, ?tx_id, %target, "sending sample infohashes query");
+
+        let mut cursor = Cursor::new(write_buf);
+
+        bt_bencode::to_writer(
+            &mut cursor,
+            &QueryMsg {
+                a: &find_node::QueryArgs::new(&node.config().local_id(), &target),
+                q: Bytes::new(METHOD_SAMPLE_INFOHASHES),
+                ro: node.config().is_read_only_node,
+                t: Bytes::new(tx_id.as_ref()),
+                v: node.config().client_version(),
+            },
+        )?;
+
+        let end = usize::try_from(cursor.position()).expect("wrote too much data");
+        write_buf = cursor.into_inner();
+
+        match sockets.send_to(&write_buf[..end], addr).await {
+            Ok(v) => v,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+
+                error!(%e, "send_to io error");
+                return Err(e);
+            }
+        };
+
+        node.metrics_mut().on_sent(
+            metrics::method_label(Some(METHOD_SAMPLE_INFOHASHES)),
+            metrics::ty_label(Ty::Query),
+            end,
+        );
+        node.insert_tx(Transaction::new(
+            AddrOptId::new(addr, addr_opt_id.id()),
+            tx_id,
+            METHOD_SAMPLE_INFOHASHES,
+            Instant::now() + node.config().default_query_timeout(),
+        ));
+        node.insert_tx_for_sample_infohashes(tx_id, addr_opt_id);
+    }
+
+    Ok(())
+}
+
+/// The IP address families which the local node supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SupportedAddr {
+    /// Only IPv4 addresses
+    Ipv4,
+    /// Only IPv6 addresses
+    Ipv6,
+    /// Both IPv4 and IPv6 addresses
+    Ipv4AndIpv6,
+}
+
+impl SupportedAddr {
+    /// Returns true if IPv4 addresses are supported.
+    #[must_use]
+    pub fn is_ipv4_supported(self) -> bool {
+        match self {
+            SupportedAddr::Ipv4 | SupportedAddr::Ipv4AndIpv6 => true,
+            SupportedAddr::Ipv6 => false,
+        }
+    }
+
+    /// Returns true if IPv6 addresses are supported.
+    #[must_use]
+    pub fn is_ipv6_supported(self) -> bool {
+        match self {
+            SupportedAddr::Ipv6 | SupportedAddr::Ipv4AndIpv6 => true,
+            SupportedAddr::Ipv4 => false,
+        }
+    }
+
+    /// Returns true if the address's family is supported.
+    #[must_use]
+    pub fn is_supported(self, addr: &CompactAddr) -> bool {
+        match addr {
+            CompactAddr::V4(_) => self.is_ipv4_supported(),
+            CompactAddr::V6(_) => self.is_ipv6_supported(),
+        }
+    }
+}
+
+/// The configuration for the local DHT node.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Config {
+    /// Local node id
+    pub local_id: LocalId,
+    /// Client version identifier
+    pub client_version: Option<Vec<u8>>,
+    /// The default amount of time before a query without a response is considered timed out
+    pub default_query_timeout: Duration,
+    /// If the node is read only
+    pub is_read_only_node: bool,
+    /// If responses from queried nodes are strictly checked for expected node ID
+    pub is_response_queried_node_id_strictly_checked: bool,
+    pub routing_table_next_response_interval: Duration,
+    pub routing_table_next_query_interval: Duration,
+    /// The interval between announces for torrents which the local node is a peer for
+    pub announce_interval: Duration,
+    /// The address families which the local node sends and receives messages with
+    pub supported_addr: SupportedAddr,
+    /// How nodes with IDs which are not valid for their IP address are treated
+    pub node_id_policy: NodeIdPolicy,
+    /// If a new local node ID is generated when the external IP address changes
+    pub is_local_id_regenerated_for_external_ip: bool,
+}
+
+impl Config {
+    /// Instantiate a new config with a node's local Id.
+    pub fn new<I>(id: I) -> Self
+    where
+        I: Into<LocalId>,
+    {
+        Self {
+            local_id: id.into(),
+            client_version: None,
+            default_query_timeout: Duration::from_secs(5 * 60),
+            is_read_only_node: false,
+            is_response_queried_node_id_strictly_checked: true,
+            routing_table_next_response_interval: Duration::from_secs(15 * 60),
+            routing_table_next_query_interval: Duration::from_secs(15 * 60),
+            announce_interval: Duration::from_secs(15 * 60),
+            supported_addr: SupportedAddr::Ipv4AndIpv6,
+            node_id_policy: NodeIdPolicy::default(),
+            is_local_id_regenerated_for_external_ip: false,
+        }
+    }
+
+    /// Returns the node's local Id.
+    #[must_use]
+    pub fn local_id(&self) -> LocalId {
+        self.local_id
+    }
+
+    /// Returns the client version.
+    #[must_use]
+    pub fn client_version(&self) -> Option<&[u8]> {
+        self.client_version.as_deref()
+    }
+
+    /// Sets the client version.
+    pub fn set_client_version<I>(&mut self, client_version: I)
+    where
+        I: Into<Option<Vec<u8>>>,
+    {
+        self.client_version = client_version.into();
+    }
+
+    /// Returns the default query timeout.
+    #[must_use]
+    pub fn default_query_timeout(&self) -> Duration {
+        self.default_query_timeout
+    }
+
+    /// Sets the default amount of time before a query without a response is
+    /// considered timed out.
+    pub fn set_default_query_timeout(&mut self, default_query_timeout: Duration) {
+        self.default_query_timeout = default_query_timeout;
+    }
+
+    /// Sets if responses from queried nodes must have the expected node ID.
+    pub fn set_is_response_queried_node_id_strictly_checked(&mut self, value: bool) {
+        self.is_response_queried_node_id_strictly_checked = value;
+    }
+
+    /// Sets the amount of time after a routing table node's last response
+    /// before the node is questionable.
+    pub fn set_routing_table_next_response_interval(&mut self, interval: Duration) {
+        self.routing_table_next_response_interval = interval;
+    }
+
+    /// Sets the amount of time after a routing table node's last query before
+    /// the node is questionable.
+    pub fn set_routing_table_next_query_interval(&mut self, interval: Duration) {
+        self.routing_table_next_query_interval = interval;
+    }
+
+    /// Sets the interval between announces for torrents.
+    pub fn set_announce_interval(&mut self, announce_interval: Duration) {
+        self.announce_interval = announce_interval;
+    }
+
+    /// Set to true if the node is read only, false otherwise.
+    pub fn set_is_read_only_node(&mut self, is_read_only_node: bool) {
+        self.is_read_only_node = is_read_only_node;
+    }
+
+    /// Sets the supported address families.
+    pub fn set_supported_addr(&mut self, supported_addr: SupportedAddr) {
+        self.supported_addr = supported_addr;
+    }
+
+    /// Sets how nodes with IDs which are not valid for their IP address are treated.
+    pub fn set_node_id_policy(&mut self, node_id_policy: NodeIdPolicy) {
+        self.node_id_policy = node_id_policy;
+    }
+
+    /// Sets if a new local node ID is generated when the external IP address changes.
+    ///
+    /// The new ID is valid for the external IP address. If IPv4 is
+    /// supported, only the external IPv4 address is used.
+    pub fn set_is_local_id_regenerated_for_external_ip(&mut self, value: bool) {
+        self.is_local_id_regenerated_for_external_ip = value;
+    }
+}
+
+/// Changes to the settings of a running node.
+///
+/// Settings which are `None` are not changed. The local node ID and the
+/// supported address families cannot be changed without a restart.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigUpdate {
+    /// Client version identifier
+    pub client_version: Option<Vec<u8>>,
+    /// The default amount of time before a query without a response is considered timed out
+    pub default_query_timeout: Option<Duration>,
+    /// If the node is read only
+    pub is_read_only_node: Option<bool>,
+    /// If responses from queried nodes are strictly checked for expected node ID
+    pub is_response_queried_node_id_strictly_checked: Option<bool>,
+    /// The amount of time after a routing table node's last response before the node is questionable
+    pub routing_table_next_response_interval: Option<Duration>,
+    /// The amount of time after a routing table node's last query before the node is questionable
+    pub routing_table_next_query_interval: Option<Duration>,
+    /// The interval between announces for torrents which the local node is a peer for
+    pub announce_interval: Option<Duration>,
+    /// How nodes with IDs which are not valid for their IP address are treated
+    pub node_id_policy: Option<NodeIdPolicy>,
+    /// If a new local node ID is generated when the external IP address changes
+    pub is_local_id_regenerated_for_external_ip: Option<bool>,
+}
+
+impl Config {
+    /// Applies changes to the settings.
+    ///
+    /// Returns the names of the settings which changed.
+    pub fn update(&mut self, update: ConfigUpdate) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        macro_rules! update {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = update.$field {
+                        if self.$field != value {
+                            info!(setting = stringify!($field), old = ?self.$field, new = ?value, "config changed");
+                            self.$field = value;
+                            changed.push(stringify!($field));
+                        }
+                    }
+                )*
+            };
+        }
+
+        update!(
+            default_query_timeout,
+            is_read_only_node,
+            is_response_queried_node_id_strictly_checked,
+            routing_table_next_response_interval,
+            routing_table_next_query_interval,
+            announce_interval,
+            node_id_policy,
+            is_local_id_regenerated_for_external_ip,
+        );
+        if let Some(client_version) = update.client_version {
+            if self.client_version.as_ref() != Some(&client_version) {
+                info!(
+                    setting = "client_version",
+                    old = ?self.client_version.as_deref().map(String::from_utf8_lossy),
+                    new = %String::from_utf8_lossy(&client_version),
+                    "config changed"
+                );
+                self.client_version = Some(client_version);
+                changed.push("client_version");
+            }
+        }
+
+        changed
+    }
+}
+
+const FIND_LOCAL_ID_INTERVAL: Duration = Duration::from_secs(3 * 60);
+
+/// The maximum number of peers returned in a get peers response.
+const MAX_PEER_VALUES: usize = 50;
+
+/// The maximum number of `InfoHash`es returned in a `sample_infohashes` response.
+const MAX_INFO_HASH_SAMPLES: usize = 20;
+
+/// The interval which remote nodes should wait before sending another
+/// `sample_infohashes` query.
+const SAMPLE_INFOHASHES_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The interval between writes of the node's state and stored data.
+const SAVE_DATA_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The file in the data directory with the announced peers.
+const PEERS_FILE: &str = "peers.dat";
+
+/// The file in the data directory with the stored items.
+const ITEMS_FILE: &str = "items.dat";
+
+use routing::MyTable;
+pub use routing::{BucketSnapshot, NodeSnapshot, NodeState};
+
+/// A copy of the IPv4 and IPv6 routing tables.
+#[derive(Clone, Debug)]
+pub struct RoutingTableSnapshot {
+    /// The local node ID which the tables are built around
+    pub pivot: node::Id,
+    /// The buckets of the IPv4 routing table
+    pub buckets: Vec<BucketSnapshot>,
+    /// The buckets of the IPv6 routing table
+    pub buckets6: Vec<BucketSnapshot>,
+}
+
+type MethodName = &'static [u8];
+type TxWithMethod = (transaction::Id, MethodName);
+
+#[derive(Debug)]
+struct Deadlines {
+    refresh_bucket: Instant,
+    next_response: Instant,
+    next_query: Instant,
+}
+
+impl Deadlines {
+    fn new(config: &Config, now: Instant) -> Self {
+        Self {
+            refresh_bucket: now + routing::BUCKET_REFRESH_INTERVAL,
+            next_response: now + config.routing_table_next_response_interval,
+            next_query: now + config.routing_table_next_query_interval,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Announce {
+    args: AnnounceArgs,
+    next_announce: Instant,
+}
+
+/// The current status of the local node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    /// Local node id
+    pub local_id: LocalId,
+    /// The external IPv4 address which remote nodes agree on
+    pub external_ipv4: Option<Ipv4Addr>,
+    /// The external IPv6 address which remote nodes agree on
+    pub external_ipv6: Option<Ipv6Addr>,
+    /// True if a lookup for the local node ID has found a node
+    pub is_bootstrapped: bool,
+}
+
+/// What the node's readiness depends on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Health {
+    /// True if a lookup for the local node ID has found a node
+    pub is_bootstrapped: bool,
+    /// The number of good nodes in the routing tables
+    pub good_nodes: usize,
+}
+
+/// The distributed hash table.
+#[derive(Debug)]
+pub struct Node<Addr> {
+    pub config: Config,
+    /// The routing table for IPv4 nodes
+    pub routing_table: Table<routing::Node<Addr, transaction::Id, Instant>, Instant>,
+    /// The routing table for IPv6 nodes
+    pub routing_table6: Table<routing::Node<Addr, transaction::Id, Instant>, Instant>,
+    find_pivot_deadline: Instant,
+    is_bootstrapped: bool,
+    tx_manager: Transactions<Addr>,
+    ops_manager: OpsManager,
+    bootstrap_addrs: Vec<CompactAddr>,
+    peer_store: PeerStore,
+    item_store: ItemStore,
+    signing_key: SigningKey,
+    tokens: Tokens,
+    announces: BTreeMap<InfoHash, Announce>,
+    external_ip_votes: ExternalIpVotes,
+    metrics: Metrics,
+}
+
+/// Builds the IPv4 and IPv6 routing tables for the config's local ID.
+#[allow(clippy::type_complexity)]
+fn new_routing_tables<A, Addr>(
+    config: &Config,
+    addr_ids: A,
+    now: Instant,
+) -> (
+    Table<routing::Node<Addr, transaction::Id, Instant>, Instant>,
+    Table<routing::Node<Addr, transaction::Id, Instant>, Instant>,
+)
+where
+    A: IntoIterator<Item = AddrId<Addr>>,
+    Addr: Copy + Into<CompactAddr>,
+{
+    let pivot_id = node::Id::from(config.local_id);
+    let (addr_ids, addr_ids6): (Vec<_>, Vec<_>) = addr_ids
+        .into_iter()
+        .partition(|addr_id| matches!((*addr_id.addr()).into(), CompactAddr::V4(_)));
+    let routing_table = routing::new_routing_table(
+        pivot_id,
+        addr_ids,
+        config.node_id_policy,
+        now + config.routing_table_next_response_interval,
+        now + config.routing_table_next_query_interval,
+        now,
+    );
+    let routing_table6 = routing::new_routing_table(
+        pivot_id,
+        addr_ids6,
+        config.node_id_policy,
+        now + config.routing_table_next_response_interval,
+        now + config.routing_table_next_query_interval,
+        now,
+    );
+    (routing_table, routing_table6)
+}
+
+impl<Addr> Node<Addr>
+where
+    Addr: Clone + Copy + Ord,
+{
+    /// Instantiates a new node.
+    pub fn new<A, B>(config: Config, addr_ids: A, bootstrap_addrs: B, now: Instant) -> Self
+    where
+        Addr: Clone + Ord + Into<CompactAddr>,
+        A: IntoIterator<Item = AddrId<Addr>>,
+        B: IntoIterator<Item = SocketAddr>,
+    {
+        let (routing_table, routing_table6) = new_routing_tables(&config, addr_ids, now);
+        let mut dht = Self {
+            config,
+            routing_table,
+            routing_table6,
+            tx_manager: Transactions::default(),
+            find_pivot_deadline: now + FIND_LOCAL_ID_INTERVAL,
+            is_bootstrapped: false,
+            ops_manager: OpsManager::default(),
+            bootstrap_addrs: bootstrap_addrs.into_iter().map(CompactAddr::from).collect(),
+            peer_store: PeerStore::default(),
+            item_store: ItemStore::default(),
+            signing_key: SigningKey::generate(&mut rand::thread_rng()),
+            tokens: Tokens::new(&mut rand::thread_rng(), now),
+            announces: BTreeMap::new(),
+            external_ip_votes: ExternalIpVotes::default(),
+            metrics: Metrics::default(),
+        };
+        let op = dht.find_node_pivot(now);
+        dht.ops_manager.insert_op(op);
+        dht
+    }
+
+    /// Returns the config.
+    #[must_use]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Changes the settings of the running node.
+    ///
+    /// The routing table is kept. New intervals and timeouts are used for
+    /// nodes, queries, and announces from now on.
+    ///
+    /// Returns the names of the settings which changed.
+    pub fn update_config(&mut self, update: ConfigUpdate) -> Vec<&'static str> {
+        self.config.update(update)
+    }
+
+    /// Replaces the bootstrap addresses and starts a lookup for the local
+    /// node ID.
+    ///
+    /// The addresses must already be resolved so the node never waits for a
+    /// DNS lookup.
+    pub fn set_bootstrap_addrs(&mut self, bootstrap_addrs: Vec<SocketAddr>, now: Instant)
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let bootstrap_addrs = bootstrap_addrs
+            .into_iter()
+            .map(CompactAddr::from)
+            .collect::<Vec<_>>();
+        if self.bootstrap_addrs != bootstrap_addrs {
+            info!(
+                old = ?self.bootstrap_addrs,
+                new = ?bootstrap_addrs,
+                "bootstrap addresses changed"
+            );
+            self.bootstrap_addrs = bootstrap_addrs;
+        }
+
+        let op = self.find_node_pivot(now);
+        self.ops_manager.insert_op(op);
+    }
+
+    /// Sets the key which signs the mutable items put by the local node.
+    pub fn set_signing_key(&mut self, signing_key: SigningKey) {
+        self.signing_key = signing_key;
+    }
+
+    /// Returns a transaction ID which can be used in the next query.
+    ///
+    /// The ID is not reserved until [`Self::insert_tx()`] is called.
+    ///
+    /// # Errors
+    ///
+    /// If a random number cannot be generated, an error will be returned.
+    #[inline]
+    pub fn next_tx_id<R>(&self, rng: &mut R) -> Result<transaction::Id, rand::Error>
+    where
+        R: rand::Rng,
+    {
+        if self.tx_manager.len() == usize::from(u16::MAX) {
+            // Outbound transactions are full.
+            return Err(rand::Error::new("all transaction IDs are used"));
+        }
+
+        let mut num: u16 = rng.gen();
+        loop {
+            let tx_id = transaction::Id::from(num);
+            if !self.tx_manager.contains(&tx_id) {
+                return Ok(tx_id);
+            }
+            num = num.wrapping_add(1);
+        }
+    }
+
+    /// Inserts [`Transaction`] information.
+    ///
+    /// When a query is sent, a `Transaction` should be inserted into the `Node`
+    /// to track outstanding queries.
+    ///
+    /// When a message is received and processed via [`Node::on_recv()`], the
+    /// message's transaction ID and inbound socket address is checked against
+    /// existing `Transaction` data. If a matching `Transaction` exists, then
+    /// the message is considered to be valid.
+    pub fn insert_tx(&mut self, tx: Transaction<Addr, transaction::Id, Instant>) {
+        self.tx_manager.insert(tx, &Instant::now());
+    }
+
+    pub fn insert_tx_for_find_node(
+        &mut self,
+        tx_id: transaction::Id,
+        target_id: node::Id,
+        addr_opt_id: AddrOptId<CompactAddr>,
+    ) {
+        self.ops_manager.insert_tx(tx_id, target_id, addr_opt_id);
+    }
+
+    pub fn insert_tx_for_get_peers(
+        &mut self,
+        tx_id: transaction::Id,
+        info_hash: InfoHash,
+        addr_opt_id: AddrOptId<CompactAddr>,
+    ) {
+        self.ops_manager
+            .insert_get_peers_tx(tx_id, info_hash, addr_opt_id);
+    }
+
+    pub fn insert_tx_for_get_item(
+        &mut self,
+        tx_id: transaction::Id,
+        target_id: node::Id,
+        addr_opt_id: AddrOptId<CompactAddr>,
+    ) {
+        self.ops_manager
+            .insert_item_tx(tx_id, target_id, addr_opt_id);
+    }
+
+    pub fn insert_tx_for_sample_infohashes(
+        &mut self,
+        tx_id: transaction::Id,
+        addr_opt_id: AddrOptId<CompactAddr>,
+    ) {
+        self.ops_manager
+            .insert_sample_infohashes_tx(tx_id, addr_opt_id);
+    }
+
+    /// Processes a received message.
+    ///
+    /// When a message is received, use this callback method to process the data.
+    /// If a message's transaction ID and inbound socket address matches
+    /// existing `Transaction` data, then the message is considered valid.
+    ///
+    /// If the message is a query from a read-only node, the querying node is
+    /// not added to the routing table.
+    ///
+    /// If the message is a response with the local node's external address,
+    /// the address is counted as a vote for the local node's external IP
+    /// address.
+    ///
+    /// # Errors
+    ///
+    /// If the message is malformed, then an error is returned. If a response or
+    /// error message does not have a matching `Transaction`, then the message is
+    /// considered invalid and an error is returned.
+    pub fn on_recv(
+        &mut self,
+        msg: &Msg<'_>,
+        addr: Addr,
+        is_read_only: bool,
+        external_addr: Option<CompactAddr>,
+    ) -> anyhow::Result<(AddrOptId<Addr>, Option<TxWithMethod>)>
+    where
+        Addr: fmt::Debug + Clone + PartialEq + Into<CompactAddr>,
+    {
+        self.on_recv_with_now(msg, addr, is_read_only, external_addr, Instant::now())
+    }
+
+    fn on_recv_with_now(
+        &mut self,
+        msg: &Msg<'_>,
+        addr: Addr,
+        is_read_only: bool,
+        external_addr: Option<CompactAddr>,
+        now: Instant,
+    ) -> anyhow::Result<(AddrOptId<Addr>, Option<TxWithMethod>)>
+    where
+        Addr: fmt::Debug + Clone + PartialEq + Into<CompactAddr>,
+    {
+        trace!(
+            addr = ?addr,
+            tx_id = ?msg.t,
+            ty = ?msg.y,
+            client_version = ?msg.v,
+            // node_id = ?tx.addr_opt_id().id(),
+            // method= ?core::str::from_utf8(tx.method).unwrap_or("non-UTF-8 method"),
+            // queried_node_id = ?value.queried_node_id(),
+            "received message"
+        );
+        let kind = msg.ty();
+        match kind {
+            Ty::Response => {
+                let tx_id = transaction::Id::try_from(msg.tx_id())
+                    .context("unrecognized transaction id format in response")?;
+                let (
+                    Transaction {
+                        addr_opt_id,
+                        tx_id,
+                        method,
+                        timeout_deadline: _timeout_deadline,
+                    },
+                    sent_at,
+                ) = self
+                    .tx_manager
+                    .on_recv(&tx_id)
+                    .context("unknown transaction for response")?;
+                self.metrics.on_rtt(
+                    metrics::method_label(Some(method)),
+                    now.saturating_duration_since(sent_at),
+                );
+
+                if let Some(external_addr) = external_addr {
+                    self.on_external_addr(addr, external_addr, now);
+                }
+
+                if let Some(node_id) = addr_opt_id.id().or_else(|| {
+                    msg.values::<RespValues<'_>>()
+                        .and_then(|values| values.map(|values| values.id()).ok())
+                        .flatten()
+                }) {
+                    let deadlines = Deadlines::new(&self.config, now);
+                    let node_id_policy = self.config.node_id_policy;
+                    routing::on_recv(
+                        self.routing_table_mut(addr),
+                        AddrId::new(addr, node_id),
+                        kind,
+                        Some(&tx_id),
+                        &deadlines,
+                        node_id_policy,
+                        now,
+                    );
+                }
+
+                self.ops_manager.on_recv(
+                    AddrOptId::new((*addr_opt_id.addr()).into(), addr_opt_id.id()),
+                    tx_id,
+                    msg,
+                    now,
+                );
+                self.on_finished_find_node_ops(now);
+                if !self.is_bootstrapped
+                    && self
+                        .ops_manager
+                        .has_closest_nodes(self.routing_table.pivot())
+                {
+                    self.is_bootstrapped = true;
+                    info!("bootstrap finished");
+                }
+
+                Ok((addr_opt_id, Some((tx_id, method))))
+            }
+            Ty::Error => {
+                let tx_id = transaction::Id::try_from(msg.tx_id())
+                    .context("unrecognized transaction id format in error")?;
+                let (
+                    Transaction {
+                        addr_opt_id,
+                        tx_id,
+                        method,
+                        timeout_deadline: _timeout_deadline,
+                    },
+                    _sent_at,
+                ) = self
+                    .tx_manager
+                    .on_recv(&tx_id)
+                    .context("unknown transaction for error")?;
+
+                if let Some(node_id) = addr_opt_id.id() {
+                    let deadlines = Deadlines::new(&self.config, now);
+                    let node_id_policy = self.config.node_id_policy;
+                    routing::on_recv(
+                        self.routing_table_mut(addr),
+                        AddrId::new(addr, node_id),
+                        kind,
+                        Some(&tx_id),
+                        &deadlines,
+                        node_id_policy,
+                        now,
+                    );
+                }
+
+                self.ops_manager.on_error(
+                    AddrOptId::new((*addr_opt_id.addr()).into(), addr_opt_id.id()),
+                    tx_id,
+                    now,
+                );
+                self.on_finished_find_node_ops(now);
+
+                Ok((addr_opt_id, Some((tx_id, method))))
+            }
+            Ty::Query | Ty::Unknown => {
+                let querying_node_id = msg
+                    .args::<QueryArgs<'_>>()
+                    .and_then(|args| args.map(|args| args.id()).ok())
+                    .flatten();
+                let addr_opt_id = AddrOptId::new(addr, querying_node_id);
+                if is_read_only {
+                    trace!(?addr, "not adding read only node to routing table");
+                } else if let Some(node_id) = querying_node_id {
+                    let deadlines = Deadlines::new(&self.config, now);
+                    let node_id_policy = self.config.node_id_policy;
+                    routing::on_recv(
+                        self.routing_table_mut(addr),
+                        AddrId::new(addr, node_id),
+                        kind,
+                        None,
+                        &deadlines,
+                        node_id_policy,
+                        now,
+                    );
+                }
+
+                Ok((addr_opt_id, None))
+            }
+            _ => {
+                unreachable!()
+            }
+        }
+    }
+
+    /// Counts the external address in a response as a vote for the local
+    /// node's external IP address.
+    ///
+    /// If the consensus changes and the local node ID is not valid for the
+    /// new address, a new local node ID may be generated.
+    fn on_external_addr(&mut self, addr: Addr, external_addr: CompactAddr, now: Instant)
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let responder = SocketAddr::from(addr.into()).ip();
+        let Some(external_ip) =
+            self.external_ip_votes
+                .insert(responder, SocketAddr::from(external_addr).ip(), now)
+        else {
+            return;
+        };
+
+        if !self.config.is_local_id_regenerated_for_external_ip
+            || external_ip.is_ipv4() != self.config.supported_addr.is_ipv4_supported()
+            || node_id::is_valid(node::Id::from(self.config.local_id), external_ip)
+        {
+            return;
+        }
+
+        let local_id = node_id::secure_id(external_ip, &mut rand::thread_rng());
+        info!(%external_ip, %local_id, "generated new local node ID for external IP address");
+        self.set_local_id(local_id, now);
+    }
+
+    /// Changes the local node ID.
+    ///
+    /// The routing tables are rebuilt around the new ID with the known nodes.
+    /// The nodes keep their state so good nodes are not pinged again.
+    fn set_local_id(&mut self, local_id: node::Id, now: Instant)
+    where
+        Addr: Into<CompactAddr>,
+    {
+        self.config.local_id = LocalId::from(local_id);
+        let node_id_policy = self.config.node_id_policy;
+        for table in [&mut self.routing_table, &mut self.routing_table6] {
+            let nodes = table
+                .iter()
+                .flat_map(Bucket::iter)
+                .cloned()
+                .collect::<Vec<_>>();
+            *table = routing::new_routing_table_with_nodes(local_id, nodes, node_id_policy, now);
+        }
+
+        let op = self.find_node_pivot(now);
+        self.ops_manager.insert_op(op);
+    }
+
+    /// Returns the local node ID and the good nodes in the routing tables.
+    #[must_use]
+    pub fn state(&self, now: Instant) -> DhtState
+    where
+        Addr: Into<CompactAddr>,
+    {
+        DhtState {
+            local_id: node::Id::from(self.config.local_id),
+            addr_ids: self
+                .routing_table
+                .iter()
+                .chain(self.routing_table6.iter())
+                .flat_map(Bucket::iter)
+                .filter(|n| n.is_good(now))
+                .map(|n| {
+                    let AddrId { addr, id } = *n.addr_id();
+                    AddrId::new(addr.into(), id)
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the announced peers and stored items to files in a directory.
+    ///
+    /// # Errors
+    ///
+    /// If a file cannot be written, an error is returned.
+    pub fn save_stores(&self, dir: &Path, now: Instant) -> io::Result<()> {
+        let unix_now = snapshot::unix_now();
+        snapshot::write(
+            &dir.join(PEERS_FILE),
+            &self.peer_store.to_bytes(now, unix_now),
+        )?;
+        snapshot::write(
+            &dir.join(ITEMS_FILE),
+            &self.item_store.to_bytes(now, unix_now),
+        )
+    }
+
+    /// Reads the announced peers and stored items from files in a directory.
+    ///
+    /// Missing files are ignored. Expired peers and items are dropped.
+    ///
+    /// # Errors
+    ///
+    /// If a file cannot be read or is invalid, an error is returned.
+    pub fn load_stores(&mut self, dir: &Path, now: Instant) -> io::Result<()> {
+        let unix_now = snapshot::unix_now();
+        if let Some(bytes) = snapshot::read(&dir.join(PEERS_FILE))? {
+            self.peer_store = PeerStore::from_bytes(&bytes, now, unix_now)?;
+        }
+        if let Some(bytes) = snapshot::read(&dir.join(ITEMS_FILE))? {
+            self.item_store = ItemStore::from_bytes(&bytes, now, unix_now)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of every bucket and node in the routing tables.
+    #[must_use]
+    pub fn routing_table_snapshot(&self, now: Instant) -> RoutingTableSnapshot
+    where
+        Addr: Into<CompactAddr>,
+    {
+        RoutingTableSnapshot {
+            pivot: self.routing_table.pivot(),
+            buckets: routing::snapshot(&self.routing_table, now),
+            buckets6: routing::snapshot(&self.routing_table6, now),
+        }
+    }
+
+    /// Returns the outstanding queries sent by the local node.
+    pub fn transactions(
+        &self,
+    ) -> impl Iterator<Item = Transaction<CompactAddr, transaction::Id, Instant>> + '_
+    where
+        Addr: Into<CompactAddr>,
+    {
+        self.tx_manager.iter().map(|tx| {
+            Transaction::new(
+                AddrOptId::new((*tx.addr_opt_id().addr()).into(), tx.addr_opt_id().id()),
+                *tx.tx_id(),
+                tx.method,
+                *tx.timeout_deadline(),
+            )
+        })
+    }
+
+    /// Returns the progress of the running `find_node` lookups.
+    pub fn find_node_ops(&self) -> impl Iterator<Item = FindNodeOpSnapshot> + '_ {
+        self.ops_manager.find_node_ops().map(FindNodeOp::snapshot)
+    }
+
+    /// Returns the counters which are updated as messages are sent and received.
+    pub fn metrics_mut(&mut self) -> &mut Metrics {
+        &mut self.metrics
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    #[must_use]
+    pub fn render_metrics(&self, now: Instant) -> String
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let snapshot = self.routing_table_snapshot(now);
+        let mut gauges = Gauges {
+            active_ops: self.ops_manager.active_ops().to_vec(),
+            outstanding_transactions: self.tx_manager.len(),
+            ..Gauges::default()
+        };
+        for (family, buckets) in [("ipv4", &snapshot.buckets), ("ipv6", &snapshot.buckets6)] {
+            gauges.routing_table_buckets.push((family, buckets.len()));
+            for (state, label) in [
+                (NodeState::Good, "good"),
+                (NodeState::Questionable, "questionable"),
+                (NodeState::Bad, "bad"),
+            ] {
+                let count = buckets
+                    .iter()
+                    .flat_map(|bucket| &bucket.nodes)
+                    .filter(|node| node.state == state)
+                    .count();
+                gauges.routing_table_nodes.push((family, label, count));
+            }
+        }
+        self.metrics.render(&gauges)
+    }
+
+    /// Returns the bootstrap state and the number of good nodes.
+    #[must_use]
+    pub fn health(&self, now: Instant) -> Health {
+        Health {
+            is_bootstrapped: self.is_bootstrapped,
+            good_nodes: self
+                .routing_table
+                .iter()
+                .chain(self.routing_table6.iter())
+                .flat_map(Bucket::iter)
+                .filter(|n| n.is_good(now))
+                .count(),
+        }
+    }
+
+    /// Returns the current status of the node.
+    #[must_use]
+    pub fn status(&self) -> Status {
+        Status {
+            local_id: self.config.local_id,
+            external_ipv4: self.external_ip_votes.ipv4(),
+            external_ipv6: self.external_ip_votes.ipv6(),
+            is_bootstrapped: self.is_bootstrapped,
+        }
+    }
+
+    /// Returns the token which a remote node must use to announce a peer.
+    ///
+    /// The token is given in a `get_peers` response and must be sent back in
+    /// an `announce_peer` query from the same IP address.
+    #[must_use]
+    pub fn announce_token(&self, ip: IpAddr) -> [u8; TOKEN_LEN] {
+        self.tokens.token(ip)
+    }
+
+    /// Returns true if the token was recently given to the IP address.
+    #[must_use]
+    pub fn is_valid_announce_token(&self, token: &[u8], ip: IpAddr) -> bool {
+        self.tokens.is_valid(token, ip)
+    }
+
+    /// Stores a peer which announced itself for a torrent.
+    pub fn insert_peer(
+        &mut self,
+        info_hash: InfoHash,
+        addr: CompactAddr,
+        seed: bool,
+        now: Instant,
+    ) {
+        self.peer_store.insert(info_hash, addr, seed, now);
+    }
+
+    /// Returns the stored peers for a torrent.
+    pub fn peers(
+        &self,
+        info_hash: &InfoHash,
+        now: Instant,
+    ) -> impl Iterator<Item = CompactAddr> + '_ {
+        self.peer_store.peers(info_hash, now)
+    }
+
+    /// Returns bloom filters of the stored seeds and of the other stored peers for a torrent.
+    #[must_use]
+    pub fn scrape_peers(&self, info_hash: &InfoHash, now: Instant) -> (BloomFilter, BloomFilter) {
+        self.peer_store.scrape(info_hash, now)
+    }
+
+    /// Returns a random sample of the `InfoHash`es with stored peers and the
+    /// total number of those `InfoHash`es.
+    #[must_use]
+    pub fn info_hash_samples(&self, now: Instant) -> (Vec<InfoHash>, usize) {
+        self.peer_store
+            .sample_info_hashes(&mut rand::thread_rng(), MAX_INFO_HASH_SAMPLES, now)
+    }
+
+    /// Stores an item which was put by a remote node.
+    ///
+    /// # Errors
+    ///
+    /// If the item is invalid or cannot replace the stored item, an error is
+    /// returned.
+    pub fn insert_item(
+        &mut self,
+        item: Item,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> Result<node::Id, PutError> {
+        self.item_store.insert(item, cas, now)
+    }
+
+    /// Returns the stored item for a target.
+    #[must_use]
+    pub fn item(&self, target: &node::Id, now: Instant) -> Option<&Item> {
+        self.item_store.get(target, now)
+    }
+
+    /// Returns the next timeout deadline.
+    ///
+    /// When the timeout deadline has passed, the following methods should be called:
+    ///
+    /// * [`Node::on_timeout()`]
+    /// * [`Node::pop_timed_out_tx()`]
+    /// * [`Node::find_node_to_ping()`]
+    /// * [`Node::find_bucket_to_refresh()`]
+    ///
+    /// The timeout deadline may change if other methods are called on this
+    /// instance.
+    #[must_use]
+    pub fn timeout(&self) -> Option<Instant> {
+        [
+            self.tx_manager.timeout(),
+            Some(self.find_pivot_deadline),
+            self.routing_table.timeout(),
+            self.routing_table6.timeout(),
+            Some(self.tokens.timeout()),
+            self.announces.values().map(|a| a.next_announce).min(),
+            self.ops_manager.timeout(),
+        ]
+        .iter()
+        .filter_map(|&deadline| deadline)
+        .min()
+    }
+
+    /// Processes timeout events.
+    ///
+    /// This method should be called after the deadline returned by [`Node::timeout()`].
+    ///
+    /// # Errors
+    ///
+    /// An error is returned in the random number generator cannot generate random data.
+    pub fn on_timeout<R>(&mut self, rng: &mut R)
+    where
+        Addr: Into<CompactAddr>,
+        R: rand::Rng,
+    {
+        self.on_timeout_with_now(rng, Instant::now());
+    }
+
+    fn on_timeout_with_now<R>(&mut self, rng: &mut R, now: Instant)
+    where
+        Addr: Into<CompactAddr>,
+        R: rand::Rng,
+    {
+        self.ops_manager.cleanup(now);
+        self.on_finished_find_node_ops(now);
+
+        if self.find_pivot_deadline <= now {
+            self.find_pivot_deadline = now + FIND_LOCAL_ID_INTERVAL;
+            let op = self.find_node_pivot(now);
+            self.ops_manager.insert_op(op);
+        }
+        self.peer_store.cleanup(now);
+        self.item_store.cleanup(now);
+        self.external_ip_votes.cleanup(now);
+        self.tokens.on_timeout(rng, now);
+
+        let due_announces = self
+            .announces
+            .iter_mut()
+            .filter(|(_, announce)| announce.next_announce <= now)
+            .map(|(info_hash, announce)| {
+                announce.next_announce = now + self.config.announce_interval;
+                (*info_hash, announce.args)
+            })
+            .collect::<Vec<_>>();
+        for (info_hash, args) in due_announces {
+            self.start_announce(info_hash, args, now);
+        }
+
+        while let Some(bucket) = self.find_bucket_to_refresh(now) {
+            bucket.set_refresh_deadline(now + Duration::from_secs(15 * 60));
+            let target_id = bucket.rand_id(rng);
+            let neighbors = self
+                .find_neighbors(target_id, now)
+                .take(8)
+                .map(|a| AddrOptId::new((*a.addr()).into(), Some(a.id())));
+            let find_node_op =
+                FindNodeOp::new(target_id, 8, self.config.supported_addr, neighbors, now);
+            self.ops_manager.insert_op(find_node_op);
+        }
+    }
+
+    /// Acts on the results of finished `find_node` lookups.
+    ///
+    /// When the lookup for the local node ID finishes, the next one is
+    /// scheduled. A lookup for the local node ID which finds a node finishes
+    /// the bootstrap if its first response did not already.
+    fn on_finished_find_node_ops(&mut self, now: Instant) {
+        while let Some(result) = self.ops_manager.pop_finished_op() {
+            debug!(
+                target_id = %result.target_id,
+                found_nodes = result.closest_nodes.len(),
+                queries_sent = result.queries_sent,
+                failures = result.failures,
+                elapsed = ?result.elapsed,
+                "find node lookup finished"
+            );
+
+            if result.target_id != self.routing_table.pivot() {
+                continue;
+            }
+            self.find_pivot_deadline = now + FIND_LOCAL_ID_INTERVAL;
+            if !self.is_bootstrapped && !result.closest_nodes.is_empty() {
+                self.is_bootstrapped = true;
+                info!(
+                    found_nodes = result.closest_nodes.len(),
+                    elapsed = ?result.elapsed,
+                    "bootstrap finished"
+                );
+            }
+        }
+    }
+
+    /// Finds a bucket to refresh.
+    ///
+    /// To refresh a bucket, find a random node with an `Id` in the bucket's range.
+    ///
+    /// ```no_run
+    /// # use waynode::dht::{Node, SupportedAddr, find_node_op::FindNodeOp};
+    /// # use std::{time::{Duration, Instant}, net::SocketAddr};
+    /// # use cloudburst::dht::{krpc::CompactAddr, node::AddrOptId};
+    /// #
+    /// # let node: Node<SocketAddr> = todo!();
+    /// # let now = Instant::now();
+    /// if let Some(bucket) = node.find_bucket_to_refresh(now) {
+    ///     bucket.set_refresh_deadline(now + Duration::from_secs(15 * 60));
+    ///     let target_id = bucket.rand_id(&mut rand::thread_rng());
+    ///     let neighbors = node.find_neighbors(target_id, now).take(8).map(|a| AddrOptId::new((*a.addr()).into(), Some(a.id())));
+    ///     let find_node_op = FindNodeOp::new(
+    ///          target_id,
+    ///          8,
+    ///          SupportedAddr::Ipv4,
+    ///          neighbors,
+    ///          now,
+    ///    );
+    /// }
+    /// ```
+    pub fn find_bucket_to_refresh(
+        &mut self,
+        now: Instant,
+    ) -> Option<&mut Bucket<routing::Node<Addr, transaction::Id, Instant>, Instant>> {
+        self.routing_table
+            .find_bucket_to_refresh(&now)
+            .or_else(|| self.routing_table6.find_bucket_to_refresh(&now))
+    }
+
+    /// Finds and processes a transaction which has timed out.
+    ///
+    /// Returns information about the transaction which has timed out.
+    pub fn pop_timed_out_tx(
+        &mut self,
+        now: Instant,
+    ) -> Option<Transaction<Addr, transaction::Id, Instant>>
+    where
+        Addr: Into<CompactAddr>,
+    {
+        if let Some(tx) = self.tx_manager.pop_timed_out_tx(&now) {
+            self.metrics
+                .on_tx_timeout(metrics::method_label(Some(tx.method)));
+            if let Some(node_id) = tx.addr_opt_id().id() {
+                routing::on_timeout(
+                    self.routing_table_mut(*tx.addr_opt_id().addr()),
+                    &AddrId::new(*tx.addr_opt_id().addr(), node_id),
+                    *tx.tx_id(),
+                );
+            }
+
+            self.ops_manager.on_tx_timeout(
+                AddrOptId::new((*tx.addr_opt_id().addr()).into(), tx.addr_opt_id().id()),
+                *tx.tx_id(),
+                now,
+            );
+            self.on_finished_find_node_ops(now);
+
+            Some(tx)
+        } else {
+            None
+        }
+    }
+
+    /// Finds a node to query for a find node target.
+    pub fn next_find_node_query(
+        &mut self,
+        now: Instant,
+    ) -> Option<(node::Id, AddrOptId<CompactAddr>)> {
+        self.ops_manager.next_addr_to_query(now)
+    }
+
+    /// Finds a node to query for a get peers lookup.
+    pub fn next_get_peers_query(&mut self, now: Instant) -> Option<GetPeersQuery> {
+        self.ops_manager.next_get_peers_query(now)
+    }
+
+    /// Starts a lookup for peers of a torrent.
+    ///
+    /// When the lookup is done, the result is sent to the subscriber.
+    pub fn get_peers(
+        &mut self,
+        info_hash: InfoHash,
+        subscriber: Option<oneshot::Sender<GetPeersResult>>,
+        now: Instant,
+    ) where
+        Addr: Into<CompactAddr>,
+    {
+        let mut op = self.get_peers_op(info_hash, now);
+        if let Some(subscriber) = subscriber {
+            op.subscribe(subscriber);
+        }
+        self.ops_manager.insert_get_peers_op(op);
+    }
+
+    /// Starts a lookup which estimates the number of seeders and leechers for
+    /// a torrent.
+    ///
+    /// When the lookup is done, the result is sent to the subscriber.
+    pub fn scrape(
+        &mut self,
+        info_hash: InfoHash,
+        subscriber: oneshot::Sender<GetPeersResult>,
+        now: Instant,
+    ) where
+        Addr: Into<CompactAddr>,
+    {
+        let mut op = self.get_peers_op(info_hash, now);
+        op.set_scrape();
+        op.subscribe(subscriber);
+        self.ops_manager.insert_get_peers_op(op);
+    }
+
+    fn get_peers_op(&self, info_hash: InfoHash, now: Instant) -> GetPeersOp
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let target_id = node::Id::from(info_hash.0);
+        GetPeersOp::new(
+            info_hash,
+            8,
+            self.config.supported_addr,
+            self.initial_addrs(target_id),
+            now,
+        )
+    }
+
+    /// Returns the torrents which the local node announces itself as a peer for.
+    pub fn announces(&self) -> impl Iterator<Item = (InfoHash, AnnounceArgs)> + '_ {
+        self.announces
+            .iter()
+            .map(|(info_hash, announce)| (*info_hash, announce.args))
+    }
+
+    /// Announces the local node as a peer for a torrent.
+    ///
+    /// The announce is sent immediately and then repeated every
+    /// [`Config::announce_interval`] until [`Node::stop_announce()`] is called.
+    pub fn announce(&mut self, info_hash: InfoHash, args: AnnounceArgs, now: Instant)
+    where
+        Addr: Into<CompactAddr>,
+    {
+        self.announces.insert(
+            info_hash,
+            Announce {
+                args,
+                next_announce: now + self.config.announce_interval,
+            },
+        );
+        self.start_announce(info_hash, args, now);
+    }
+
+    /// Stops announcing the local node as a peer for a torrent.
+    ///
+    /// A running lookup for the torrent does not announce when it is done.
+    ///
+    /// Returns true if the torrent was being announced.
+    pub fn stop_announce(&mut self, info_hash: &InfoHash) -> bool {
+        self.ops_manager.stop_announce(*info_hash);
+        self.announces.remove(info_hash).is_some()
+    }
+
+    fn start_announce(&mut self, info_hash: InfoHash, args: AnnounceArgs, now: Instant)
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let mut op = self.get_peers_op(info_hash, now);
+        op.set_announce(args);
+        self.ops_manager.insert_get_peers_op(op);
+    }
+
+    /// Returns an announce peer query to send for a finished announce.
+    ///
+    /// The query should be sent to the node with the token from the node's
+    /// get peers response.
+    pub fn next_announce_peer_query(&mut self) -> Option<AnnouncePeerQuery> {
+        self.ops_manager.pop_announce_peer_query()
+    }
+
+    /// Starts a lookup for an item.
+    ///
+    /// When the lookup is done, the result is sent to the subscriber.
+    pub fn get_item(
+        &mut self,
+        target: node::Id,
+        subscriber: oneshot::Sender<GetItemResult>,
+        now: Instant,
+    ) where
+        Addr: Into<CompactAddr>,
+    {
+        let mut op = self.item_op(target, now);
+        op.subscribe(subscriber);
+        self.ops_manager.insert_item_op(op);
+    }
+
+    /// Starts a lookup for the mutable item with the highest sequence number
+    /// for a public key and salt.
+    ///
+    /// When the lookup is done, the result is sent to the subscriber.
+    pub fn get_mutable_item(
+        &mut self,
+        k: &[u8; PUBLIC_KEY_LENGTH],
+        salt: Vec<u8>,
+        subscriber: oneshot::Sender<GetItemResult>,
+        now: Instant,
+    ) where
+        Addr: Into<CompactAddr>,
+    {
+        let mut op = self.item_op(item_op::mutable_target(k, &salt), now);
+        op.set_salt(salt);
+        op.subscribe(subscriber);
+        self.ops_manager.insert_item_op(op);
+    }
 
-use routing::MyTable;
+    /// Puts an immutable item to the nodes closest to the item's target.
+    ///
+    /// Returns the item's target. When the lookup for the closest nodes is
+    /// done, the result is sent to the subscriber and the item is put.
+    ///
+    /// # Errors
+    ///
+    /// If the item's value is invalid, an error is returned.
+    pub fn put_immutable_item(
+        &mut self,
+        value: Value,
+        subscriber: oneshot::Sender<GetItemResult>,
+        now: Instant,
+    ) -> Result<node::Id, PutError>
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let target = item_op::immutable_target(&value)?;
+        let mut op = self.item_op(target, now);
+        op.set_put_immutable(value);
+        op.subscribe(subscriber);
+        self.ops_manager.insert_item_op(op);
+        Ok(target)
+    }
 
-type MethodName = &'static [u8];
-type TxWithMethod = (transaction::Id, MethodName);
+    /// Puts a mutable item signed by the local node's key to the nodes closest
+    /// to the item's target.
+    ///
+    /// The item's sequence number is one greater than the highest sequence
+    /// number found during the lookup. Returns the item's target. When the
+    /// lookup for the closest nodes is done, the result is sent to the
+    /// subscriber and the item is put.
+    ///
+    /// # Errors
+    ///
+    /// If the item's value or salt is invalid, an error is returned.
+    pub fn put_mutable_item(
+        &mut self,
+        salt: Vec<u8>,
+        value: Value,
+        subscriber: oneshot::Sender<GetItemResult>,
+        now: Instant,
+    ) -> Result<node::Id, PutError>
+    where
+        Addr: Into<CompactAddr>,
+    {
+        if salt.len() > item_op::MAX_SALT_LEN {
+            return Err(PutError::SaltTooBig);
+        }
+        item_op::encode_value(&value)?;
+
+        let target = item_op::mutable_target(&self.signing_key.verifying_key().to_bytes(), &salt);
+        let mut op = self.item_op(target, now);
+        op.set_put_mutable(self.signing_key.clone(), salt, value);
+        op.subscribe(subscriber);
+        self.ops_manager.insert_item_op(op);
+        Ok(target)
+    }
 
-#[derive(Debug)]
-struct Deadlines {
-    refresh_bucket: Instant,
-    next_response: Instant,
-    next_query: Instant,
-}
+    fn item_op(&self, target: node::Id, now: Instant) -> ItemOp
+    where
+        Addr: Into<CompactAddr>,
+    {
+        ItemOp::new(
+            target,
+            8,
+            self.config.supported_addr,
+            self.initial_addrs(target),
+            now,
+        )
+    }
 
-impl Deadlines {
-    fn new(config: &Config, now: Instant) -> Self {
-        Self {
-            refresh_bucket: now + routing::BUCKET_REFRESH_INTERVAL,
-            next_response: now + config.routing_table_next_response_interval,
-            next_query: now + config.routing_table_next_query_interval,
-        }
+    /// Finds a node to query for an item lookup.
+    pub fn next_get_item_query(&mut self, now: Instant) -> Option<GetItemQuery> {
+        self.ops_manager.next_get_item_query(now)
     }
-}
 
-/// The distributed hash table.
-#[derive(Debug)]
-pub struct Node<Addr> {
-    pub config: Config,
-    pub routing_table: Table<routing::Node<Addr, transaction::Id, Instant>, Instant>,
-    find_pivot_deadline: Instant,
-    tx_manager: Transactions<Addr, transaction::Id, Instant>,
-    ops_manager: OpsManager,
-    bootstrap_addrs: Vec<String>,
-}
+    /// Returns a put query to send for a finished item lookup.
+    ///
+    /// The query should be sent to the node with the token from the node's
+    /// get response.
+    pub fn next_put_item_query(&mut self) -> Option<PutItemQuery> {
+        self.ops_manager.pop_put_item_query()
+    }
 
-impl<Addr> Node<Addr>
-where
-    Addr: Clone + Copy + Ord,
-{
-    /// Instantiates a new node.
-    pub fn new<A, B>(config: Config, addr_ids: A, bootstrap_addrs: B, now: Instant) -> Self
-    where
-        Addr: Clone + Ord + Into<CompactAddr>,
-        A: IntoIterator<Item = AddrId<Addr>>,
-        B: IntoIterator<Item = String>,
+    /// Starts a crawl which collects `InfoHash`es from up to `max_nodes` nodes.
+    ///
+    /// `max_nodes` is limited to [`sample_infohashes_op::MAX_NODES`].
+    ///
+    /// When the crawl is done, the result is sent to the subscriber.
+    pub fn sample_infohashes(
+        &mut self,
+        max_nodes: usize,
+        subscriber: oneshot::Sender<SampleInfoHashesResult>,
+        now: Instant,
+    ) where
+        Addr: Into<CompactAddr>,
     {
-        let pivot_id = node::Id::from(config.local_id);
-        let routing_table = routing::new_routing_table(
-            pivot_id,
-            addr_ids,
-            now + config.routing_table_next_response_interval,
-            now + config.routing_table_next_query_interval,
+        let mut op = SampleInfoHashesOp::new(
+            max_nodes,
+            self.config.supported_addr,
+            self.initial_addrs(self.routing_table.pivot()),
             now,
         );
-        let mut dht = Self {
-            config,
-            routing_table,
-            tx_manager: Transactions::default(),
-            find_pivot_deadline: now + FIND_LOCAL_ID_INTERVAL,
-            ops_manager: OpsManager::default(),
-            bootstrap_addrs: bootstrap_addrs.into_iter().collect(),
-        };
-        let op = dht.find_node_pivot(now);
-        dht.ops_manager.insert_op(op);
-        dht
+        op.subscribe(subscriber);
+        self.ops_manager.insert_sample_infohashes_op(op);
     }
 
-    /// Returns the config.
-    #[must_use]
-    pub fn config(&self) -> &Config {
-        &self.config
+    /// Starts a `find_node` lookup for the nodes closest to `target_id`.
+    ///
+    /// When the lookup is done, the result is sent to the subscriber.
+    pub fn lookup_node(
+        &mut self,
+        target_id: node::Id,
+        subscriber: oneshot::Sender<FindNodeResult>,
+        now: Instant,
+    ) where
+        Addr: Into<CompactAddr>,
+    {
+        let mut op = self.find_node(target_id, now);
+        op.subscribe(subscriber);
+        self.ops_manager.insert_op(op);
     }
 
-    /// Returns a transaction ID which can be used in the next query.
+    /// Finds a node to send a `sample_infohashes` query to for a crawl.
+    pub fn next_sample_infohashes_query(&mut self, now: Instant) -> Option<SampleInfoHashesQuery> {
+        self.ops_manager.next_sample_infohashes_query(now)
+    }
+
+    /// Finds a node to ping.
     ///
-    /// The ID is not reserved until [`Self::insert_tx()`] is called.
+    /// # Important
     ///
-    /// # Errors
+    /// [`Node::on_ping()`] must be called to indicate the node was pinged.
+    pub fn find_node_to_ping(
+        &mut self,
+        now: Instant,
+    ) -> Option<&mut routing::Node<Addr, transaction::Id, Instant>> {
+        self.routing_table
+            .find_node_to_ping(now)
+            .or_else(|| self.routing_table6.find_node_to_ping(now))
+    }
+
+    /// Finds the cloesst neighbors for a given `Id`.
     ///
-    /// If a random number cannot be generated, an error will be returned.
+    /// Usually a query is directed towards a target hash value. Nodes with
+    /// `Id`s which are "closer" to the target value are more likely to have the
+    /// data than other nodes.
+    ///
+    /// Neighbors from both the IPv4 and the IPv6 routing tables are returned.
+    pub fn find_neighbors(&self, id: node::Id, _now: Instant) -> impl Iterator<Item = AddrId<Addr>>
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let node_id_policy = self.config.node_id_policy;
+        let mut neighbors = routing::find_neighbors(&self.routing_table, id, node_id_policy)
+            .chain(routing::find_neighbors(
+                &self.routing_table6,
+                id,
+                node_id_policy,
+            ))
+            .collect::<Vec<_>>();
+        routing::sort_neighbors(&mut neighbors, id, node_id_policy);
+        neighbors.into_iter()
+    }
+
+    /// Returns the closest neighbors for an `Id` in compact form.
+    ///
+    /// The IPv4 and IPv6 nodes are returned for the `nodes` and `nodes6`
+    /// response values respectively if the address family is wanted.
+    fn compact_neighbors(&self, id: node::Id, want: Want) -> (Option<Vec<u8>>, Option<Vec<u8>>)
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let node_id_policy = self.config.node_id_policy;
+        let nodes = want.n4.then(|| {
+            compact_nodes(routing::find_neighbors(&self.routing_table, id, node_id_policy).take(8))
+        });
+        let nodes6 = want.n6.then(|| {
+            compact_nodes(routing::find_neighbors(&self.routing_table6, id, node_id_policy).take(8))
+        });
+        (nodes, nodes6)
+    }
+
+    fn routing_table_mut(
+        &mut self,
+        addr: Addr,
+    ) -> &mut Table<routing::Node<Addr, transaction::Id, Instant>, Instant>
+    where
+        Addr: Into<CompactAddr>,
+    {
+        match addr.into() {
+            CompactAddr::V4(_) => &mut self.routing_table,
+            CompactAddr::V6(_) => &mut self.routing_table6,
+        }
+    }
+
+    fn bootstrap_addrs(&self) -> impl Iterator<Item = AddrOptId<CompactAddr>> + '_ {
+        let supported_addr = self.config.supported_addr;
+        self.bootstrap_addrs
+            .iter()
+            .filter(move |addr| supported_addr.is_supported(addr))
+            .map(|addr| AddrOptId::with_addr(*addr))
+    }
+
+    /// Returns the addresses to start a lookup with.
+    ///
+    /// The closest nodes from each routing table and the bootstrap nodes are used.
+    fn initial_addrs(
+        &self,
+        target_id: node::Id,
+    ) -> impl Iterator<Item = AddrOptId<CompactAddr>> + '_
+    where
+        Addr: Into<CompactAddr>,
+    {
+        let node_id_policy = self.config.node_id_policy;
+        routing::find_neighbors(&self.routing_table, target_id, node_id_policy)
+            .take(8)
+            .chain(routing::find_neighbors(&self.routing_table6, target_id, node_id_policy).take(8))
+            .map(|a| AddrOptId::new((*a.addr()).into(), Some(a.id())))
+            .chain(self.bootstrap_addrs())
+    }
+
+    #[must_use]
+    fn find_node(&mut self, target_id: node::Id, now: Instant) -> FindNodeOp
+    where
+        Addr: Into<CompactAddr>,
+    {
+        FindNodeOp::new(
+            target_id,
+            8,
+            self.config.supported_addr,
+            self.initial_addrs(target_id),
+            now,
+        )
+    }
+
+    #[must_use]
     #[inline]
-    pub fn next_tx_id<R>(&self, rng: &mut R) -> Result<transaction::Id, rand::Error>
+    fn find_node_pivot(&mut self, now: Instant) -> FindNodeOp
     where
-        R: rand::Rng,
+        Addr: Into<CompactAddr>,
     {
-        if self.tx_manager.len() == usize::from(u16::MAX) {
-            // Outbound transactions are full.
-            return Err(rand::Error::new("all transaction IDs are used"));
+        self.find_node(self.routing_table.pivot(), now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cloudburst::dht::krpc::ping::METHOD_PING;
+
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+    fn new_config() -> Result<Config, rand::Error> {
+        Ok(Config {
+            local_id: LocalId::from(node::Id::rand(&mut rand::thread_rng())?),
+            client_version: None,
+            default_query_timeout: Duration::from_secs(60),
+            is_read_only_node: true,
+            is_response_queried_node_id_strictly_checked: true,
+            routing_table_next_response_interval: Duration::from_secs(15 * 60),
+            routing_table_next_query_interval: Duration::from_secs(15 * 60),
+            announce_interval: Duration::from_secs(15 * 60),
+            supported_addr: SupportedAddr::Ipv4AndIpv6,
+            node_id_policy: NodeIdPolicy::default(),
+            is_local_id_regenerated_for_external_ip: false,
+        })
+    }
+
+    fn remote_addr() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6532))
+    }
+
+    fn node_id() -> node::Id {
+        node::Id::rand(&mut rand::thread_rng()).unwrap()
+    }
+
+    #[test]
+    fn test_send_message() {
+        let id = node_id();
+        let remote_addr = remote_addr();
+        let addr_opt_id = AddrOptId::new(remote_addr, Some(id));
+
+        let config = new_config().unwrap();
+
+        let mut node: Node<SocketAddr> = Node::new(
+            config,
+            std::iter::empty(),
+            std::iter::empty(),
+            Instant::now(),
+        );
+        let tx_id = node.next_tx_id(&mut rand::thread_rng()).unwrap();
+        node.insert_tx(Transaction::new(
+            addr_opt_id,
+            tx_id,
+            METHOD_PING,
+            Instant::now() + node.config().default_query_timeout,
+        ));
+    }
+
+    #[test]
+    fn test_announce_token_expires() {
+        let now = Instant::now();
+        let ip = remote_addr().ip();
+        let config = new_config().unwrap();
+
+        let mut node: Node<SocketAddr> =
+            Node::new(config, std::iter::empty(), std::iter::empty(), now);
+        let token = node.announce_token(ip);
+        assert!(node.is_valid_announce_token(&token, ip));
+
+        let now = now + Duration::from_secs(5 * 60);
+        node.on_timeout_with_now(&mut rand::thread_rng(), now);
+        assert!(node.is_valid_announce_token(&token, ip));
+
+        let now = now + Duration::from_secs(5 * 60);
+        node.on_timeout_with_now(&mut rand::thread_rng(), now);
+        assert!(!node.is_valid_announce_token(&token, ip));
+    }
+
+    /// Sends the pending get peers queries and answers each with a token
+    /// made from the last byte of the queried node's IP address.
+    fn answer_get_peers_queries(
+        node: &mut Node<SocketAddr>,
+        info_hash: InfoHash,
+        now: Instant,
+    ) -> usize {
+        let mut count = 0;
+        while let Some(query) = node.next_get_peers_query(now) {
+            assert_eq!(query.info_hash, info_hash);
+            let addr = SocketAddr::from(*query.addr_opt_id.addr());
+            let id = query.addr_opt_id.id().unwrap();
+
+            let tx_id = node.next_tx_id(&mut rand::thread_rng()).unwrap();
+            node.insert_tx(Transaction::new(
+                AddrOptId::new(addr, Some(id)),
+                tx_id,
+                METHOD_GET_PEERS,
+                now + node.config().default_query_timeout,
+            ));
+            node.insert_tx_for_get_peers(tx_id, info_hash, query.addr_opt_id);
+
+            let token = token_for(addr);
+            let resp = bt_bencode::to_vec(&krpc::ser::RespMsg {
+                r: get_peers::RespValues::new(
+                    &LocalId::from(id),
+                    &token,
+                    None::<Vec<CompactAddr>>,
+                    None::<&Bytes>,
+                    None,
+                ),
+                t: Bytes::new(tx_id.as_ref()),
+                v: None,
+            })
+            .unwrap();
+            let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+            node.on_recv_with_now(&msg, addr, false, None, now).unwrap();
+            count += 1;
         }
+        count
+    }
 
-        let mut num: u16 = rng.gen();
-        loop {
-            let tx_id = transaction::Id::from(num);
-            if !self.tx_manager.contains(&tx_id) {
-                return Ok(tx_id);
+    fn token_for(addr: SocketAddr) -> Vec<u8> {
+        match addr.ip() {
+            IpAddr::V4(ip) => vec![ip.octets()[3]; 4],
+            IpAddr::V6(ip) => vec![ip.octets()[15]; 4],
+        }
+    }
+
+    #[test]
+    fn test_get_peers_and_announce_peer_replies() {
+        let now = Instant::now();
+        let config = new_config().unwrap();
+        let addr = remote_addr();
+        let info_hash = InfoHash::from([1; 20]);
+        let routing_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 10), 6881));
+
+        let mut node: Node<SocketAddr> = Node::new(
+            config,
+            [AddrId::new(routing_addr, node_id())],
+            std::iter::empty(),
+            now,
+        );
+        let querying_id = LocalId::from(node_id());
+        let mut write_buf = vec![0; 4096];
+
+        let get_peers_query = bt_bencode::to_vec(&krpc::ser::QueryMsg {
+            a: &get_peers::QueryArgs::new(&querying_id, &info_hash),
+            q: Bytes::new(METHOD_GET_PEERS),
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&get_peers_query).unwrap();
+        let (len, ty) = write_reply(&mut node, addr, &msg, &mut write_buf, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ty, Ty::Response);
+        let reply: Msg<'_> = bt_bencode::from_slice(&write_buf[..len]).unwrap();
+        let resp = reply
+            .values::<get_peers::RespValues<'_, Vec<&[u8]>>>()
+            .unwrap()
+            .unwrap();
+        let token = node.announce_token(addr.ip());
+        assert_eq!(resp.token(), token);
+        assert!(resp.values().is_none());
+        assert_eq!(resp.nodes().unwrap().unwrap().count(), 1);
+
+        let bad_token = [0; TOKEN_LEN];
+        for (token, port, implied_port) in [
+            (&bad_token[..], Some(7000), None),
+            (&token[..], Some(7000), Some(true)),
+            (&token[..], Some(7001), None),
+        ] {
+            let announce_peer_query = bt_bencode::to_vec(&krpc::ser::QueryMsg {
+                a: &announce_peer::QueryArgs::new(
+                    &querying_id,
+                    &info_hash,
+                    Bytes::new(token),
+                    port,
+                    implied_port,
+                ),
+                q: Bytes::new(METHOD_ANNOUNCE_PEER),
+                t: Bytes::new(&[0, 2]),
+                v: None,
+            })
+            .unwrap();
+            let msg: Msg<'_> = bt_bencode::from_slice(&announce_peer_query).unwrap();
+            let (len, ty) = write_reply(&mut node, addr, &msg, &mut write_buf, now)
+                .unwrap()
+                .unwrap();
+            let reply: Msg<'_> = bt_bencode::from_slice(&write_buf[..len]).unwrap();
+            if token == bad_token {
+                assert_eq!(ty, Ty::Error);
+                assert_eq!(reply.error(), Some((ErrorCode::ProtocolError, "bad token")));
+                assert_eq!(node.peers(&info_hash, now).count(), 0);
+            } else {
+                assert_eq!(ty, Ty::Response);
             }
-            num = num.wrapping_add(1);
         }
-    }
 
-    /// Inserts [`Transaction`] information.
-    ///
-    /// When a query is sent, a `Transaction` should be inserted into the `Node`
-    /// to track outstanding queries.
-    ///
-    /// When a message is received and processed via [`Node::on_recv()`], the
-    /// message's transaction ID and inbound socket address is checked against
-    /// existing `Transaction` data. If a matching `Transaction` exists, then
-    /// the message is considered to be valid.
-    pub fn insert_tx(&mut self, tx: Transaction<Addr, transaction::Id, Instant>) {
-        self.tx_manager.insert(tx);
-    }
+        let mut peers = node.peers(&info_hash, now).collect::<Vec<_>>();
+        peers.sort();
+        assert_eq!(
+            peers,
+            vec![
+                CompactAddr::from(addr),
+                CompactAddr::from(SocketAddr::new(addr.ip(), 7001)),
+            ]
+        );
 
-    pub fn insert_tx_for_find_node(
-        &mut self,
-        tx_id: transaction::Id,
-        target_id: node::Id,
-        addr_opt_id: AddrOptId<CompactAddr>,
-    ) {
-        self.ops_manager.insert_tx(tx_id, target_id, addr_opt_id);
+        let msg: Msg<'_> = bt_bencode::from_slice(&get_peers_query).unwrap();
+        let (len, ty) = write_reply(&mut node, addr, &msg, &mut write_buf, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ty, Ty::Response);
+        let reply: Msg<'_> = bt_bencode::from_slice(&write_buf[..len]).unwrap();
+        let resp = reply
+            .values::<get_peers::RespValues<'_, Vec<&[u8]>>>()
+            .unwrap()
+            .unwrap();
+        assert_eq!(resp.token(), token);
+        let mut values = resp.values().unwrap().collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, peers);
+        assert!(resp.nodes().is_none());
     }
 
-    /// Processes a received message.
-    ///
-    /// When a message is received, use this callback method to process the data.
-    /// If a message's transaction ID and inbound socket address matches
-    /// existing `Transaction` data, then the message is considered valid.
-    ///
-    /// # Errors
-    ///
-    /// If the message is malformed, then an error is returned. If a response or
-    /// error message does not have a matching `Transaction`, then the message is
-    /// considered invalid and an error is returned.
-    pub fn on_recv(
-        &mut self,
-        msg: &Msg<'_>,
-        addr: Addr,
-    ) -> anyhow::Result<(AddrOptId<Addr>, Option<TxWithMethod>)>
-    where
-        Addr: fmt::Debug + Clone + PartialEq + Into<CompactAddr>,
-    {
-        self.on_recv_with_now(msg, addr, Instant::now())
+    #[test]
+    fn test_bootstrap_addrs_seed_lookups() {
+        let now = Instant::now();
+        let mut config = new_config().unwrap();
+        config.supported_addr = SupportedAddr::Ipv4;
+        let addr = remote_addr();
+        let addr6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 6532, 0, 0));
+        let info_hash = InfoHash::from([1; 20]);
+
+        let mut node: Node<SocketAddr> = Node::new(config, std::iter::empty(), [addr, addr6], now);
+        node.get_peers(info_hash, None, now);
+        let addr_opt_id = AddrOptId::with_addr(CompactAddr::from(addr));
+        assert_eq!(
+            node.next_get_peers_query(now)
+                .map(|query| (query.info_hash, query.addr_opt_id)),
+            Some((info_hash, addr_opt_id))
+        );
+
+        let tx_id = node.next_tx_id(&mut rand::thread_rng()).unwrap();
+        node.insert_tx_for_get_peers(tx_id, info_hash, addr_opt_id);
+        assert!(node.next_get_peers_query(now).is_none());
     }
 
-    fn on_recv_with_now(
-        &mut self,
-        msg: &Msg<'_>,
-        addr: Addr,
-        now: Instant,
-    ) -> anyhow::Result<(AddrOptId<Addr>, Option<TxWithMethod>)>
-    where
-        Addr: fmt::Debug + Clone + PartialEq + Into<CompactAddr>,
-    {
-        trace!(
-            addr = ?addr,
-            tx_id = ?msg.t,
-            ty = ?msg.y,
-            client_version = ?msg.v,
-            // node_id = ?tx.addr_opt_id().id(),
-            // method= ?core::str::from_utf8(tx.method).unwrap_or("non-UTF-8 method"),
-            // queried_node_id = ?value.queried_node_id(),
-            "received message"
+    #[test]
+    fn test_announce_repeats() {
+        let now = Instant::now();
+        let info_hash = InfoHash::from([1; 20]);
+        let args = AnnounceArgs {
+            port: 6881,
+            implied_port: false,
+        };
+        let config = new_config().unwrap();
+        let announce_interval = config.announce_interval;
+        let responders = (1..=2)
+            .map(|i| {
+                AddrId::new(
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, i), 6881)),
+                    node_id(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut node: Node<SocketAddr> =
+            Node::new(config, responders.iter().copied(), std::iter::empty(), now);
+        node.announce(info_hash, args, now);
+        assert_eq!(
+            node.announces().collect::<Vec<_>>(),
+            vec![(info_hash, args)]
         );
-        let kind = msg.ty();
-        match kind {
-            Ty::Response => {
-                let tx_id = transaction::Id::try_from(msg.tx_id())
-                    .context("unrecognized transaction id format in response")?;
-                let Transaction {
-                    addr_opt_id,
-                    tx_id,
-                    method,
-                    timeout_deadline: _timeout_deadline,
-                } = self
-                    .tx_manager
-                    .on_recv(&tx_id)
-                    .context("unknown transaction for response")?;
+        assert!(node.timeout().unwrap() <= now + announce_interval);
 
-                if let Some(node_id) = addr_opt_id.id().or_else(|| {
-                    msg.values::<RespValues<'_>>()
-                        .and_then(|values| values.map(|values| values.id()).ok())
-                        .flatten()
-                }) {
-                    routing::on_recv(
-                        &mut self.routing_table,
-                        AddrId::new(addr, node_id),
-                        kind,
-                        Some(&tx_id),
-                        &Deadlines::new(&self.config, now),
-                        now,
-                    );
-                }
+        assert_eq!(
+            answer_get_peers_queries(&mut node, info_hash, now),
+            responders.len()
+        );
+        let mut queries = std::iter::from_fn(|| node.next_announce_peer_query())
+            .map(|query| {
+                assert_eq!(query.info_hash, info_hash);
+                assert_eq!(query.args, args);
+                (query.addr_id, query.token)
+            })
+            .collect::<Vec<_>>();
+        queries.sort();
+        let mut expected = responders
+            .iter()
+            .map(|responder| {
+                (
+                    AddrId::new(CompactAddr::from(*responder.addr()), responder.id()),
+                    token_for(*responder.addr()),
+                )
+            })
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(queries, expected);
+
+        let now = now + announce_interval;
+        node.on_timeout_with_now(&mut rand::thread_rng(), now);
+        assert_eq!(
+            node.announces.get(&info_hash).unwrap().next_announce,
+            now + announce_interval
+        );
 
-                self.ops_manager.on_recv(
-                    AddrOptId::new((*addr_opt_id.addr()).into(), addr_opt_id.id()),
-                    tx_id,
-                    msg,
-                    now,
-                );
+        assert!(node.stop_announce(&info_hash));
+        assert!(!node.stop_announce(&info_hash));
 
-                Ok((addr_opt_id, Some((tx_id, method))))
-            }
-            Ty::Error => {
-                let tx_id = transaction::Id::try_from(msg.tx_id())
-                    .context("unrecognized transaction id format in error")?;
-                let Transaction {
-                    addr_opt_id,
-                    tx_id,
-                    method,
-                    timeout_deadline: _timeout_deadline,
-                } = self
-                    .tx_manager
-                    .on_recv(&tx_id)
-                    .context("unknown transaction for error")?;
+        assert_eq!(
+            answer_get_peers_queries(&mut node, info_hash, now),
+            responders.len()
+        );
+        assert!(node.next_announce_peer_query().is_none());
+    }
 
-                if let Some(node_id) = addr_opt_id.id() {
-                    routing::on_recv(
-                        &mut self.routing_table,
-                        AddrId::new(addr, node_id),
-                        kind,
-                        Some(&tx_id),
-                        &Deadlines::new(&self.config, now),
-                        now,
-                    );
-                }
+    #[test]
+    fn test_routing_table_per_addr_family() {
+        let now = Instant::now();
+        let config = new_config().unwrap();
+        let addr = remote_addr();
+        let addr6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 6532, 0, 0));
+        let id = node_id();
+        let id6 = node_id();
 
-                self.ops_manager.on_error(
-                    AddrOptId::new((*addr_opt_id.addr()).into(), addr_opt_id.id()),
-                    tx_id,
-                    now,
-                );
+        let mut node: Node<SocketAddr> = Node::new(
+            config,
+            [AddrId::new(addr, id), AddrId::new(addr6, id6)],
+            std::iter::empty(),
+            now,
+        );
+        assert_eq!(
+            routing::find_neighbors(&node.routing_table, id, node.config.node_id_policy)
+                .collect::<Vec<_>>(),
+            vec![AddrId::new(addr, id)]
+        );
+        assert_eq!(
+            routing::find_neighbors(&node.routing_table6, id6, node.config.node_id_policy)
+                .collect::<Vec<_>>(),
+            vec![AddrId::new(addr6, id6)]
+        );
+        assert_eq!(node.find_neighbors(id, now).count(), 2);
+
+        let other_id6 = node_id();
+        let query = bt_bencode::to_vec(&QueryMsg {
+            a: &ping::QueryArgs::new(&LocalId::from(other_id6)),
+            q: Bytes::new(METHOD_PING),
+            ro: false,
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&query).unwrap();
+        let other_addr6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 6533, 0, 0));
+        node.on_recv_with_now(&msg, other_addr6, false, None, now)
+            .unwrap();
+        assert_eq!(
+            routing::find_neighbors(&node.routing_table6, other_id6, node.config.node_id_policy)
+                .count(),
+            2
+        );
+        assert_eq!(
+            routing::find_neighbors(&node.routing_table, id, node.config.node_id_policy).count(),
+            1
+        );
 
-                Ok((addr_opt_id, Some((tx_id, method))))
-            }
-            Ty::Query | Ty::Unknown => {
-                let querying_node_id = msg
-                    .values::<QueryArgs<'_>>()
-                    .and_then(|args| args.map(|args| args.id()).ok())
-                    .flatten();
-                let addr_opt_id = AddrOptId::new(addr, querying_node_id);
-                if let Some(node_id) = querying_node_id {
-                    routing::on_recv(
-                        &mut self.routing_table,
-                        AddrId::new(addr, node_id),
-                        kind,
-                        None,
-                        &Deadlines::new(&self.config, now),
-                        now,
-                    );
-                }
+        let snapshot = node.routing_table_snapshot(now);
+        assert_eq!(snapshot.buckets.len(), 1);
+        assert_eq!(snapshot.buckets[0].nodes.len(), 1);
+        assert_eq!(
+            snapshot.buckets[0].nodes[0].addr_id,
+            AddrId::new(CompactAddr::from(addr), id)
+        );
+        assert_eq!(snapshot.buckets[0].nodes[0].state, NodeState::Good);
+        assert_eq!(
+            snapshot
+                .buckets6
+                .iter()
+                .map(|b| b.nodes.len())
+                .sum::<usize>(),
+            2
+        );
+    }
 
-                Ok((addr_opt_id, None))
-            }
-            _ => {
-                unreachable!()
-            }
-        }
+    #[test]
+    fn test_query_adds_querying_node_to_routing_table() {
+        let now = Instant::now();
+        let config = new_config().unwrap();
+        let addr = remote_addr();
+        let id = node_id();
+
+        let mut node: Node<SocketAddr> =
+            Node::new(config, std::iter::empty(), std::iter::empty(), now);
+
+        let target_id = node_id();
+        let query = bt_bencode::to_vec(&QueryMsg {
+            a: &find_node::QueryArgs::new(&LocalId::from(id), &target_id),
+            q: Bytes::new(METHOD_FIND_NODE),
+            ro: false,
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&query).unwrap();
+        let (addr_opt_id, tx) = node.on_recv_with_now(&msg, addr, false, None, now).unwrap();
+        assert_eq!(addr_opt_id, AddrOptId::new(addr, Some(id)));
+        assert!(tx.is_none());
+        assert_eq!(
+            routing::find_neighbors(&node.routing_table, id, node.config.node_id_policy)
+                .collect::<Vec<_>>(),
+            vec![AddrId::new(addr, id)]
+        );
     }
 
-    /// Returns the next timeout deadline.
-    ///
-    /// When the timeout deadline has passed, the following methods should be called:
-    ///
-    /// * [`Node::on_timeout()`]
-    /// * [`Node::pop_timed_out_tx()`]
-    /// * [`Node::find_node_to_ping()`]
-    /// * [`Node::find_bucket_to_refresh()`]
-    ///
-    /// The timeout deadline may change if other methods are called on this
-    /// instance.
-    #[must_use]
-    pub fn timeout(&self) -> Option<Instant> {
-        [self.tx_manager.timeout(), self.routing_table.timeout()]
-            .iter()
-            .filter_map(|&deadline| deadline)
-            .min()
+    #[test]
+    fn test_read_only_query_not_in_routing_table() {
+        let now = Instant::now();
+        let config = new_config().unwrap();
+        let addr = remote_addr();
+        let id = node_id();
+
+        let mut node: Node<SocketAddr> =
+            Node::new(config, std::iter::empty(), std::iter::empty(), now);
+
+        let query = bt_bencode::to_vec(&QueryMsg {
+            a: &ping::QueryArgs::new(&LocalId::from(id)),
+            q: Bytes::new(METHOD_PING),
+            ro: true,
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        assert!(MsgExt::from_slice(&query).is_read_only());
+
+        let msg: Msg<'_> = bt_bencode::from_slice(&query).unwrap();
+        let (addr_opt_id, _) = node.on_recv_with_now(&msg, addr, true, None, now).unwrap();
+        assert_eq!(addr_opt_id, AddrOptId::new(addr, Some(id)));
+        assert_eq!(node.find_neighbors(id, now).count(), 0);
+
+        node.on_recv_with_now(&msg, addr, false, None, now).unwrap();
+        assert_eq!(node.find_neighbors(id, now).count(), 1);
     }
 
-    /// Processes timeout events.
-    ///
-    /// This method should be called after the deadline returned by [`Node::timeout()`].
-    ///
-    /// # Errors
-    ///
-    /// An error is returned in the random number generator cannot generate random data.
-    pub fn on_timeout<R>(&mut self, rng: &mut R)
-    where
-        Addr: Into<CompactAddr>,
-        R: rand::Rng,
-    {
-        self.on_timeout_with_now(rng, Instant::now());
+    #[test]
+    fn test_update_config_keeps_routing_table() {
+        let now = Instant::now();
+        let config = new_config().unwrap();
+        let addr = remote_addr();
+        let id = node_id();
+
+        let mut node: Node<SocketAddr> =
+            Node::new(config, [AddrId::new(addr, id)], std::iter::empty(), now);
+
+        let changed = node.update_config(ConfigUpdate {
+            client_version: Some(b"ab12".to_vec()),
+            is_read_only_node: Some(true),
+            announce_interval: Some(Duration::from_secs(60)),
+            ..ConfigUpdate::default()
+        });
+        assert_eq!(changed, vec!["announce_interval", "client_version"]);
+        assert_eq!(node.config().client_version(), Some(&b"ab12"[..]));
+        assert_eq!(node.config().announce_interval, Duration::from_secs(60));
+        assert_eq!(node.find_neighbors(id, now).count(), 1);
     }
 
-    fn on_timeout_with_now<R>(&mut self, rng: &mut R, now: Instant)
-    where
-        Addr: Into<CompactAddr>,
-        R: rand::Rng,
-    {
-        if self.find_pivot_deadline <= now {
-            let op = self.find_node_pivot(now);
-            self.ops_manager.insert_op(op);
-        }
+    #[test]
+    fn test_pivot_lookup_deadline_moves_when_started() {
+        let now = Instant::now();
+        let config = new_config().unwrap();
 
-        self.ops_manager.cleanup();
+        let mut node: Node<SocketAddr> = Node::new(
+            config,
+            [AddrId::new(remote_addr(), node_id())],
+            std::iter::empty(),
+            now,
+        );
+        let pivot = node.routing_table.pivot();
+        let is_pivot_op_running = |node: &Node<SocketAddr>| {
+            node.find_node_ops()
+                .filter(|op| op.target_id == pivot)
+                .count()
+                == 1
+        };
+        assert!(is_pivot_op_running(&node));
 
-        while let Some(bucket) = self.find_bucket_to_refresh(now) {
-            bucket.set_refresh_deadline(now + Duration::from_secs(15 * 60));
-            let target_id = bucket.rand_id(rng);
-            let neighbors = self
-                .find_neighbors(target_id, now)
-                .take(8)
-                .map(|a| AddrOptId::new((*a.addr()).into(), Some(a.id())));
-            let find_node_op = FindNodeOp::new(target_id, 8, neighbors, now);
-            self.ops_manager.insert_op(find_node_op);
+        let now = now + lookup::MAX_DURATION;
+        node.on_timeout_with_now(&mut rand::thread_rng(), now);
+        assert!(!is_pivot_op_running(&node));
+        assert_eq!(node.find_pivot_deadline, now + FIND_LOCAL_ID_INTERVAL);
+
+        let now = now + FIND_LOCAL_ID_INTERVAL;
+        node.on_timeout_with_now(&mut rand::thread_rng(), now);
+        assert!(is_pivot_op_running(&node));
+        assert!(node.timeout().unwrap() > now);
+    }
+
+    #[test]
+    fn test_health_after_bootstrap() {
+        let now = Instant::now();
+        let mut config = new_config().unwrap();
+        config.default_query_timeout = Duration::from_secs(5 * 60);
+        let addr = remote_addr();
+        let id = node_id();
+        let silent = AddrId::new(
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 6881)),
+            node_id(),
+        );
+
+        let mut node: Node<SocketAddr> = Node::new(
+            config,
+            [AddrId::new(addr, id), silent],
+            std::iter::empty(),
+            now,
+        );
+        assert_eq!(
+            node.health(now),
+            Health {
+                is_bootstrapped: false,
+                good_nodes: 2
+            }
+        );
+
+        let mut responses = Vec::new();
+        while let Some((target_id, addr_opt_id)) = node.next_find_node_query(now) {
+            assert_eq!(target_id, node.routing_table.pivot());
+            let query_addr = SocketAddr::from(*addr_opt_id.addr());
+            let tx_id = node.next_tx_id(&mut rand::thread_rng()).unwrap();
+            node.insert_tx(Transaction::new(
+                AddrOptId::new(query_addr, addr_opt_id.id()),
+                tx_id,
+                METHOD_FIND_NODE,
+                now + node.config().default_query_timeout,
+            ));
+            node.insert_tx_for_find_node(tx_id, target_id, addr_opt_id);
+            if query_addr == addr {
+                responses.push(tx_id);
+            }
         }
-    }
+        assert_eq!(responses.len(), 1);
 
-    /// Finds a bucket to refresh.
-    ///
-    /// To refresh a bucket, find a random node with an `Id` in the bucket's range.
-    ///
-    /// ```no_run
-    /// # use waynode::dht::{Node, SupportedAddr, find_node_op::FindNodeOp};
-    /// # use std::{time::{Duration, Instant}, net::SocketAddr};
-    /// # use cloudburst::dht::{krpc::CompactAddr, node::AddrOptId};
-    /// #
-    /// # let node: Node<SocketAddr> = todo!();
-    /// # let now = Instant::now();
-    /// if let Some(bucket) = node.find_bucket_to_refresh(now) {
-    ///     bucket.set_refresh_deadline(now + Duration::from_secs(15 * 60));
-    ///     let target_id = bucket.rand_id(&mut rand::thread_rng());
-    ///     let neighbors = node.find_neighbors(target_id, now).take(8).map(|a| AddrOptId::new((*a.addr()).into(), Some(a.id())));
-    ///     let find_node_op = FindNodeOp::new(
-    ///          target_id,
-    ///          8,
-    ///          SupportedAddr::Ipv4,
-    ///          neighbors
-    ///    );
-    /// }
-    /// ```
-    pub fn find_bucket_to_refresh(
-        &mut self,
-        now: Instant,
-    ) -> Option<&mut Bucket<routing::Node<Addr, transaction::Id, Instant>, Instant>> {
-        self.routing_table.find_bucket_to_refresh(&now)
+        let local_id = LocalId::from(id);
+        let resp = bt_bencode::to_vec(&krpc::ser::RespMsg {
+            r: find_node::RespValues::new(&local_id, Some(Bytes::new(&[])), None),
+            t: Bytes::new(responses[0].as_ref()),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+        node.on_recv_with_now(&msg, addr, false, None, now).unwrap();
+        assert_eq!(node.find_node_ops().count(), 1);
+        assert_eq!(
+            node.health(now),
+            Health {
+                is_bootstrapped: true,
+                good_nodes: 2
+            }
+        );
     }
 
-    /// Finds and processes a transaction which has timed out.
-    ///
-    /// Returns information about the transaction which has timed out.
-    pub fn pop_timed_out_tx(
-        &mut self,
-        now: Instant,
-    ) -> Option<Transaction<Addr, transaction::Id, Instant>>
-    where
-        Addr: Into<CompactAddr>,
-    {
-        if let Some(tx) = self.tx_manager.pop_timed_out_tx(&now) {
-            if let Some(node_id) = tx.addr_opt_id().id() {
-                routing::on_timeout(
-                    &mut self.routing_table,
-                    &AddrId::new(*tx.addr_opt_id().addr(), node_id),
-                    *tx.tx_id(),
-                );
-            }
+    #[test]
+    fn test_lookup_finishes_at_deadline() {
+        let now = Instant::now();
+        let mut config = new_config().unwrap();
+        config.default_query_timeout = Duration::from_secs(5 * 60);
+        let responder = AddrId::new(
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881)),
+            node_id(),
+        );
+        let silent = AddrId::new(
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 6881)),
+            node_id(),
+        );
 
-            self.ops_manager.on_tx_timeout(
-                AddrOptId::new((*tx.addr_opt_id().addr()).into(), tx.addr_opt_id().id()),
-                *tx.tx_id(),
-                now,
-            );
+        let mut node: Node<SocketAddr> =
+            Node::new(config, [responder, silent], std::iter::empty(), now);
+        let target_id = node_id();
+        let (tx, mut rx) = oneshot::channel();
+        node.lookup_node(target_id, tx, now);
 
-            Some(tx)
-        } else {
-            None
+        while let Some((query_target_id, addr_opt_id)) = node.next_find_node_query(now) {
+            let addr = SocketAddr::from(*addr_opt_id.addr());
+            let tx_id = node.next_tx_id(&mut rand::thread_rng()).unwrap();
+            node.insert_tx(Transaction::new(
+                AddrOptId::new(addr, addr_opt_id.id()),
+                tx_id,
+                METHOD_FIND_NODE,
+                now + node.config().default_query_timeout,
+            ));
+            node.insert_tx_for_find_node(tx_id, query_target_id, addr_opt_id);
+
+            if addr == *responder.addr() {
+                let resp = bt_bencode::to_vec(&krpc::ser::RespMsg {
+                    r: find_node::RespValues::new(
+                        &LocalId::from(responder.id()),
+                        Some(Bytes::new(&[])),
+                        None,
+                    ),
+                    t: Bytes::new(tx_id.as_ref()),
+                    v: None,
+                })
+                .unwrap();
+                let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+                node.on_recv_with_now(&msg, addr, false, None, now).unwrap();
+            }
         }
+        assert!(rx.try_recv().is_err());
+        assert!(node.timeout().unwrap() <= now + lookup::MAX_DURATION);
+
+        let now = now + lookup::MAX_DURATION;
+        node.on_timeout_with_now(&mut rand::thread_rng(), now);
+        let result = rx.try_recv().unwrap();
+        assert_eq!(
+            result.closest_nodes,
+            vec![AddrId::new(
+                CompactAddr::from(*responder.addr()),
+                responder.id()
+            )]
+        );
+        assert_eq!(result.queries_sent, 2);
+        assert_eq!(result.failures, 0);
+        assert_eq!(result.elapsed, lookup::MAX_DURATION);
     }
 
-    /// Finds a node to query for a find node target.
-    pub fn next_find_node_query(
-        &mut self,
-        now: Instant,
-    ) -> Option<(node::Id, AddrOptId<CompactAddr>)> {
-        self.ops_manager.next_addr_to_query(now)
-    }
+    #[test]
+    fn test_external_ip_consensus_regenerates_local_id() {
+        let now = Instant::now();
+        let mut config = new_config().unwrap();
+        config.is_local_id_regenerated_for_external_ip = true;
+        let old_local_id = node::Id::from(config.local_id);
+        let external_addr =
+            CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), 6881));
+        let external_ip = SocketAddr::from(external_addr).ip();
+        let responders = (1..=3)
+            .map(|i| {
+                AddrId::new(
+                    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, i), 6881)),
+                    node_id(),
+                )
+            })
+            .collect::<Vec<_>>();
 
-    /// Finds a node to ping.
-    ///
-    /// # Important
-    ///
-    /// [`Node::on_ping()`] must be called to indicate the node was pinged.
-    pub fn find_node_to_ping(
-        &mut self,
-        now: Instant,
-    ) -> Option<&mut routing::Node<Addr, transaction::Id, Instant>> {
-        self.routing_table.find_node_to_ping(now)
-    }
+        let mut node: Node<SocketAddr> =
+            Node::new(config, responders.iter().copied(), std::iter::empty(), now);
 
-    /// Finds the cloesst neighbors for a given `Id`.
-    ///
-    /// Usually a query is directed towards a target hash value. Nodes with
-    /// `Id`s which are "closer" to the target value are more likely to have the
-    /// data than other nodes.
-    pub fn find_neighbors(&self, id: node::Id, _now: Instant) -> impl Iterator<Item = AddrId<Addr>>
-    where
-        Addr: Clone,
-    {
-        routing::find_neighbors(&self.routing_table, id)
-    }
+        for (i, responder) in responders.iter().enumerate() {
+            assert_eq!(node::Id::from(node.config().local_id), old_local_id);
 
-    #[must_use]
-    fn find_node(&mut self, target_id: node::Id, now: Instant) -> FindNodeOp
-    where
-        Addr: Into<CompactAddr>,
-    {
-        let bootstrap_addrs = self
-            .bootstrap_addrs
-            .iter()
-            .filter_map(|s| {
-                use std::net::ToSocketAddrs;
-                s.to_socket_addrs().ok()
+            let tx_id = node.next_tx_id(&mut rand::thread_rng()).unwrap();
+            node.insert_tx(Transaction::new(
+                AddrOptId::new(*responder.addr(), Some(responder.id())),
+                tx_id,
+                METHOD_PING,
+                now + node.config().default_query_timeout,
+            ));
+
+            let resp = bt_bencode::to_vec(&RespMsg {
+                ip: external_addr,
+                r: ping::RespValues::new(&LocalId::from(responder.id())),
+                t: tx_id.as_ref(),
+                v: None,
             })
-            .flatten()
-            .filter_map(|socket_addr| match socket_addr {
-                SocketAddr::V4(socket_addr) => Some(AddrOptId::with_addr(socket_addr.into())),
-                SocketAddr::V6(_) => None,
-            });
-        FindNodeOp::new(
-            target_id,
-            8,
-            routing::find_neighbors(&self.routing_table, target_id)
-                .take(8)
-                .map(|a| AddrOptId::new((*a.addr()).into(), Some(a.id())))
-                .chain(bootstrap_addrs),
-            now,
-        )
-    }
+            .unwrap();
+            let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+            let msg_ext = MsgExt::from_slice(&resp);
+            node.on_recv_with_now(
+                &msg,
+                *responder.addr(),
+                msg_ext.is_read_only(),
+                msg_ext.external_addr(),
+                now,
+            )
+            .unwrap();
 
-    #[must_use]
-    #[inline]
-    fn find_node_pivot(&mut self, now: Instant) -> FindNodeOp
-    where
-        Addr: Into<CompactAddr>,
-    {
-        self.find_node(self.routing_table.pivot(), now)
+            if i < 2 {
+                assert_eq!(node.external_ip_votes.ipv4(), None);
+            }
+        }
+
+        let local_id = node::Id::from(node.config().local_id);
+        assert_ne!(local_id, old_local_id);
+        assert!(node_id::is_valid(local_id, external_ip));
+        assert_eq!(node.routing_table.pivot(), local_id);
+
+        let snapshot = node.routing_table_snapshot(now);
+        let nodes = snapshot
+            .buckets
+            .iter()
+            .flat_map(|b| b.nodes.iter())
+            .collect::<Vec<_>>();
+        assert_eq!(nodes.len(), responders.len());
+        for node in nodes {
+            assert_eq!(node.karma, 1);
+            assert_eq!(node.state, NodeState::Good);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use cloudburst::dht::krpc::ping::METHOD_PING;
+    #[test]
+    fn test_replies_include_ip() {
+        let now = Instant::now();
+        let config = new_config().unwrap();
+        let addr = remote_addr();
 
-    use super::*;
-    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+        let mut node: Node<SocketAddr> =
+            Node::new(config, std::iter::empty(), std::iter::empty(), now);
 
-    fn new_config() -> Result<Config, rand::Error> {
-        Ok(Config {
-            local_id: LocalId::from(node::Id::rand(&mut rand::thread_rng())?),
-            client_version: None,
-            default_query_timeout: Duration::from_secs(60),
-            is_read_only_node: true,
-            is_response_queried_node_id_strictly_checked: true,
-            routing_table_next_response_interval: Duration::from_secs(15 * 60),
-            routing_table_next_query_interval: Duration::from_secs(15 * 60),
+        let query = bt_bencode::to_vec(&QueryMsg {
+            a: &ping::QueryArgs::new(&LocalId::from(node_id())),
+            q: Bytes::new(METHOD_PING),
+            ro: false,
+            t: Bytes::new(&[0, 1]),
+            v: None,
         })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&query).unwrap();
+
+        let mut write_buf = vec![0; 4096];
+        let (len, ty) = write_reply(&mut node, addr, &msg, &mut write_buf, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ty, Ty::Response);
+        assert_eq!(
+            MsgExt::from_slice(&write_buf[..len]).external_addr(),
+            Some(CompactAddr::from(addr))
+        );
     }
 
-    fn remote_addr() -> SocketAddr {
-        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6532))
-    }
+    #[test]
+    fn test_enforced_node_id_policy() {
+        let now = Instant::now();
+        let mut config = new_config().unwrap();
+        config.set_node_id_policy(NodeIdPolicy::Enforce);
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(124, 31, 75, 21), 6881));
+
+        let mut node: Node<SocketAddr> =
+            Node::new(config, std::iter::empty(), std::iter::empty(), now);
+
+        let invalid_id = node::Id::from([0; 20]);
+        let valid_id = node_id::secure_id(addr.ip(), &mut rand::thread_rng());
+        for id in [invalid_id, valid_id] {
+            let query = bt_bencode::to_vec(&QueryMsg {
+                a: &ping::QueryArgs::new(&LocalId::from(id)),
+                q: Bytes::new(METHOD_PING),
+                ro: false,
+                t: Bytes::new(&[0, 1]),
+                v: None,
+            })
+            .unwrap();
+            let msg: Msg<'_> = bt_bencode::from_slice(&query).unwrap();
+            node.on_recv_with_now(&msg, addr, false, None, now).unwrap();
+        }
 
-    fn node_id() -> node::Id {
-        node::Id::rand(&mut rand::thread_rng()).unwrap()
+        assert_eq!(
+            node.find_neighbors(valid_id, now).collect::<Vec<_>>(),
+            vec![AddrId::new(addr, valid_id)]
+        );
     }
 
     #[test]
-    fn test_send_message() {
-        let id = node_id();
-        let remote_addr = remote_addr();
-        let addr_opt_id = AddrOptId::new(remote_addr, Some(id));
+    fn test_deprioritized_node_ids_sort_last() {
+        let now = Instant::now();
+        let mut config = new_config().unwrap();
+        config.set_node_id_policy(NodeIdPolicy::Deprioritize);
+        let invalid_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(124, 31, 75, 21), 6881));
+        let valid_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(21, 75, 31, 124), 6881));
+        let invalid_id = node::Id::from([0; 20]);
+        let valid_id = node_id::secure_id(valid_addr.ip(), &mut rand::thread_rng());
+        assert!(!node_id::is_valid(invalid_id, invalid_addr.ip()));
+
+        let node: Node<SocketAddr> = Node::new(
+            config,
+            [
+                AddrId::new(invalid_addr, invalid_id),
+                AddrId::new(valid_addr, valid_id),
+            ],
+            std::iter::empty(),
+            now,
+        );
 
+        let expected = vec![
+            AddrId::new(valid_addr, valid_id),
+            AddrId::new(invalid_addr, invalid_id),
+        ];
+        assert_eq!(
+            node.find_neighbors(invalid_id, now).collect::<Vec<_>>(),
+            expected
+        );
+        let (nodes, _) = node.compact_neighbors(
+            invalid_id,
+            Want {
+                n4: true,
+                n6: false,
+            },
+        );
+        assert_eq!(nodes, Some(compact_nodes(expected)));
+    }
+
+    #[test]
+    fn test_compact_neighbors_for_want() {
+        let now = Instant::now();
         let config = new_config().unwrap();
+        let addr = remote_addr();
+        let addr6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 6532, 0, 0));
+        let id = node_id();
+        let id6 = node_id();
 
-        let mut node: Node<SocketAddr> = Node::new(
+        let node: Node<SocketAddr> = Node::new(
             config,
+            [AddrId::new(addr, id), AddrId::new(addr6, id6)],
             std::iter::empty(),
-            std::iter::empty(),
-            Instant::now(),
+            now,
         );
-        let tx_id = node.next_tx_id(&mut rand::thread_rng()).unwrap();
-        node.insert_tx(Transaction::new(
-            addr_opt_id,
-            tx_id,
-            METHOD_PING,
-            Instant::now() + node.config().default_query_timeout,
-        ));
+
+        let (nodes, nodes6) = node.compact_neighbors(
+            id,
+            Want {
+                n4: true,
+                n6: false,
+            },
+        );
+        assert_eq!(nodes, Some(compact_nodes([AddrId::new(addr, id)])));
+        assert_eq!(nodes.unwrap().len(), 26);
+        assert_eq!(nodes6, None);
+
+        let (nodes, nodes6) = node.compact_neighbors(id, Want { n4: true, n6: true });
+        assert_eq!(nodes.unwrap().len(), 26);
+        assert_eq!(nodes6.unwrap().len(), 38);
     }
 }
 
 mod routing {
     const MAX_BUCKET_SIZE: usize = 8;
 
-    use std::time::{Duration, Instant};
+    use std::{
+        ops::RangeInclusive,
+        time::{Duration, Instant},
+    };
 
     use cloudburst::dht::{
-        krpc::{transaction, Ty},
+        krpc::{transaction, CompactAddr, Ty},
         node::{self, AddrId},
         routing::{Bucket, Table},
     };
+    use std::net::SocketAddr;
+    use tracing::trace;
 
-    use super::Deadlines;
+    use super::{node_id, Deadlines, NodeIdPolicy};
 
     pub(super) const BUCKET_REFRESH_INTERVAL: Duration = Duration::from_secs(3 * 60);
 
+    /// Returns true if the node ID is valid for the node's IP address.
+    fn is_valid_id<Addr>(addr_id: &AddrId<Addr>) -> bool
+    where
+        Addr: Copy + Into<CompactAddr>,
+    {
+        let addr = SocketAddr::from((*addr_id.addr()).into());
+        node_id::is_valid(addr_id.id(), addr.ip())
+    }
+
     pub(super) fn new_routing_table<A, Addr, TxId>(
         pivot_id: node::Id,
         addr_ids: A,
+        node_id_policy: NodeIdPolicy,
         next_response_deadline: Instant,
         next_query_deadline: Instant,
         now: Instant,
     ) -> Table<Node<Addr, TxId, Instant>, Instant>
     where
         A: IntoIterator<Item = AddrId<Addr>>,
+        Addr: Copy + Into<CompactAddr>,
+    {
+        new_routing_table_with_nodes(
+            pivot_id,
+            addr_ids
+                .into_iter()
+                .map(|addr_id| Node::new(addr_id, next_response_deadline, next_query_deadline)),
+            node_id_policy,
+            now,
+        )
+    }
+
+    /// Builds a routing table around a pivot from existing nodes.
+    ///
+    /// The nodes keep their karma, deadlines, and outstanding ping.
+    pub(super) fn new_routing_table_with_nodes<N, Addr, TxId>(
+        pivot_id: node::Id,
+        nodes: N,
+        node_id_policy: NodeIdPolicy,
+        now: Instant,
+    ) -> Table<Node<Addr, TxId, Instant>, Instant>
+    where
+        N: IntoIterator<Item = Node<Addr, TxId, Instant>>,
+        Addr: Copy + Into<CompactAddr>,
     {
         let mut routing_table = Table::new(pivot_id, now + BUCKET_REFRESH_INTERVAL);
         let pivot_id = routing_table.pivot();
-        for addr_id in addr_ids {
+        for node in nodes {
+            let addr_id = *node.addr_id();
+            if node_id_policy == NodeIdPolicy::Enforce && !is_valid_id(&addr_id) {
+                continue;
+            }
+
             let mut bucket = routing_table.find_mut(&addr_id.id());
 
             let mut bucket_len = bucket.len();
@@ -1017,11 +3738,7 @@ mod routing {
             }
 
             if bucket_len < MAX_BUCKET_SIZE {
-                bucket.insert(Node::new(
-                    addr_id,
-                    next_response_deadline,
-                    next_query_deadline,
-                ));
+                bucket.insert(node);
                 bucket.set_refresh_deadline(now + BUCKET_REFRESH_INTERVAL);
             }
         }
@@ -1029,16 +3746,27 @@ mod routing {
         routing_table
     }
 
+    /// Updates or inserts a node which a message was received from.
+    ///
+    /// Nodes with IDs which are not valid for their IP address are refused or
+    /// deprioritized depending on the node ID policy.
     pub(super) fn on_recv<Addr>(
         table: &mut Table<Node<Addr, transaction::Id, Instant>, Instant>,
         addr_id: AddrId<Addr>,
         kind: Ty,
         tx_id: Option<&transaction::Id>,
         deadlines: &Deadlines,
+        node_id_policy: NodeIdPolicy,
         now: Instant,
     ) where
-        Addr: PartialEq,
+        Addr: Copy + PartialEq + Into<CompactAddr>,
     {
+        let is_valid = node_id_policy == NodeIdPolicy::Ignore || is_valid_id(&addr_id);
+        if !is_valid && node_id_policy == NodeIdPolicy::Enforce {
+            trace!(node_id = %addr_id.id(), "node ID is not valid for IP address");
+            return;
+        }
+
         let pivot_id = table.pivot();
         let mut bucket = table.find_mut(&addr_id.id());
         if let Some(node) = bucket.iter_mut().find(|node| *node.addr_id() == addr_id) {
@@ -1075,6 +3803,20 @@ mod routing {
             NodeState::Bad => false,
         });
 
+        if bucket.len() == MAX_BUCKET_SIZE
+            && is_valid
+            && node_id_policy == NodeIdPolicy::Deprioritize
+        {
+            let mut is_replaced = false;
+            bucket.retain(|node| {
+                if is_replaced || is_valid_id(node.addr_id()) {
+                    return true;
+                }
+                is_replaced = true;
+                false
+            });
+        }
+
         if bucket.len() < MAX_BUCKET_SIZE {
             bucket.insert(Node::new(
                 addr_id,
@@ -1100,13 +3842,63 @@ mod routing {
         }
     }
 
-    #[derive(Debug, PartialEq)]
-    enum NodeState {
+    /// The liveliness of a routing table node.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum NodeState {
+        /// The node recently responded or sent a query
         Good,
+        /// The node has not been heard from recently
         Questionable,
+        /// The node has failed to respond to multiple queries
         Bad,
     }
 
+    /// A copy of a routing table node's information.
+    #[derive(Clone, Debug)]
+    pub struct NodeSnapshot {
+        /// The node's address and ID
+        pub addr_id: AddrId<CompactAddr>,
+        /// The count of responses minus the count of failed queries
+        pub karma: i8,
+        /// The node's liveliness
+        pub state: NodeState,
+        /// The deadline for a response before the node is questionable
+        pub next_response_deadline: Instant,
+        /// The deadline for a query before the node is questionable
+        pub next_query_deadline: Instant,
+        /// If a ping to the node is outstanding
+        pub is_pinging: bool,
+    }
+
+    /// A copy of a routing table bucket's information.
+    #[derive(Clone, Debug)]
+    pub struct BucketSnapshot {
+        /// The node IDs which the bucket holds
+        pub range: RangeInclusive<node::Id>,
+        /// The deadline to refresh the bucket with a lookup
+        pub refresh_deadline: Instant,
+        /// The nodes in the bucket
+        pub nodes: Vec<NodeSnapshot>,
+    }
+
+    /// Returns a copy of every bucket in a routing table.
+    pub(super) fn snapshot<Addr>(
+        table: &Table<Node<Addr, transaction::Id, Instant>, Instant>,
+        now: Instant,
+    ) -> Vec<BucketSnapshot>
+    where
+        Addr: Copy + Into<CompactAddr>,
+    {
+        table
+            .iter()
+            .map(|bucket| BucketSnapshot {
+                range: bucket.range().clone(),
+                refresh_deadline: *bucket.refresh_deadline(),
+                nodes: bucket.iter().map(|node| node.snapshot(now)).collect(),
+            })
+            .collect()
+    }
+
     /// Contains the address and [`Id`] for a node with metadata about the last response.
     ///
     /// Used to store a node's information for routing queries to. Contains
@@ -1126,6 +3918,23 @@ mod routing {
         }
     }
 
+    impl<A, TxId> Node<A, TxId, Instant> {
+        /// Returns a copy of the node's information.
+        fn snapshot(&self, now: Instant) -> NodeSnapshot
+        where
+            A: Copy + Into<CompactAddr>,
+        {
+            NodeSnapshot {
+                addr_id: AddrId::new((*self.addr_id.addr()).into(), self.addr_id.id()),
+                karma: self.karma,
+                state: self.state_with_now(&now),
+                next_response_deadline: self.next_response_deadline,
+                next_query_deadline: self.next_query_deadline,
+                is_pinging: self.ping_tx_id.is_some(),
+            }
+        }
+    }
+
     impl<A, TxId, Instant> Node<A, TxId, Instant>
     where
         Instant: cloudburst::time::Instant,
@@ -1161,6 +3970,11 @@ mod routing {
             core::cmp::max(&self.next_response_deadline, &self.next_query_deadline)
         }
 
+        /// Returns true if the node has recently responded or sent a query.
+        pub fn is_good(&self, now: Instant) -> bool {
+            self.state_with_now(&now) == NodeState::Good
+        }
+
         fn state_with_now(&self, now: &Instant) -> NodeState {
             if *now < self.next_response_deadline {
                 return NodeState::Good;
@@ -1327,20 +4141,39 @@ mod routing {
     pub(super) fn find_neighbors<Addr>(
         table: &Table<Node<Addr, transaction::Id, Instant>, Instant>,
         id: node::Id,
+        node_id_policy: NodeIdPolicy,
     ) -> impl Iterator<Item = AddrId<Addr>>
     where
-        Addr: Clone,
+        Addr: Copy + Into<CompactAddr>,
     {
         let mut nodes = table
             .iter()
             .flat_map(Bucket::iter)
-            .map(|n| n.addr_id().clone())
+            .map(|n| *n.addr_id())
             // .flat_map(|b| b.prioritized_nodes(now.clone()).cloned())
             .collect::<Vec<_>>();
-        nodes.sort_by_key(|a| a.id().distance(id));
+        sort_neighbors(&mut nodes, id, node_id_policy);
         nodes.into_iter()
     }
 
+    /// Sorts nodes by distance to the ID.
+    ///
+    /// With [`NodeIdPolicy::Deprioritize`], nodes with IDs which are not valid
+    /// for their IP address are sorted after the nodes with valid IDs.
+    pub(super) fn sort_neighbors<Addr>(
+        nodes: &mut [AddrId<Addr>],
+        id: node::Id,
+        node_id_policy: NodeIdPolicy,
+    ) where
+        Addr: Copy + Into<CompactAddr>,
+    {
+        if node_id_policy == NodeIdPolicy::Deprioritize {
+            nodes.sort_by_key(|a| (!is_valid_id(a), a.id().distance(id)));
+        } else {
+            nodes.sort_by_key(|a| a.id().distance(id));
+        }
+    }
+
     impl<Addr> MyTable<Node<Addr, transaction::Id, Instant>>
         for Table<Node<Addr, transaction::Id, Instant>, Instant>
     where
@@ -1351,7 +4184,6 @@ mod routing {
         ///
         /// Once a timeout is reached, call [`Table::find_refreshable_bucket()`] to
         /// find a bucket to refresh.
-        #[must_use]
         fn timeout(&self) -> Option<Instant> {
             self.iter().map(Bucket::timeout).min().copied()
         }
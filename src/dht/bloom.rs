@@ -0,0 +1,112 @@
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+
+/// The number of bytes in a bloom filter.
+pub const BLOOM_FILTER_LEN: usize = 256;
+
+/// The number of bits in a bloom filter.
+const M: usize = BLOOM_FILTER_LEN * 8;
+
+/// The number of hash functions.
+const K: f64 = 2.0;
+
+/// A bloom filter of peer IP addresses used to estimate the number of peers
+/// for a torrent.
+///
+/// See [BEP 33](http://bittorrent.org/beps/bep_0033.html).
+#[derive(Clone, PartialEq, Eq)]
+pub struct BloomFilter([u8; BLOOM_FILTER_LEN]);
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self([0; BLOOM_FILTER_LEN])
+    }
+}
+
+impl core::fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("BloomFilter")
+            .field(&self.count_zero_bits())
+            .finish()
+    }
+}
+
+impl AsRef<[u8]> for BloomFilter {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for BloomFilter {
+    type Error = core::array::TryFromSliceError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        <[u8; BLOOM_FILTER_LEN]>::try_from(value).map(Self)
+    }
+}
+
+impl BloomFilter {
+    /// Inserts an IP address.
+    pub fn insert(&mut self, ip: IpAddr) {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        let hash = hasher.finalize();
+
+        let index1 = (usize::from(hash[0]) | usize::from(hash[1]) << 8) % M;
+        let index2 = (usize::from(hash[2]) | usize::from(hash[3]) << 8) % M;
+        self.0[index1 / 8] |= 1 << (index1 % 8);
+        self.0[index2 / 8] |= 1 << (index2 % 8);
+    }
+
+    /// Merges the addresses in another filter into this filter.
+    pub fn union(&mut self, other: &BloomFilter) {
+        for (b, o) in self.0.iter_mut().zip(other.0.iter()) {
+            *b |= o;
+        }
+    }
+
+    /// Returns the estimated number of addresses inserted into the filter.
+    #[must_use]
+    pub fn estimate(&self) -> f64 {
+        let c = self.count_zero_bits().max(1);
+        #[allow(clippy::cast_precision_loss)]
+        let (c, m) = (c as f64, M as f64);
+        (c / m).ln() / (K * (1.0 - 1.0 / m).ln())
+    }
+
+    fn count_zero_bits(&self) -> usize {
+        M - self
+            .0
+            .iter()
+            .map(|b| b.count_ones() as usize)
+            .sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_estimate_bep_33_example() {
+        let mut filter = BloomFilter::default();
+        assert!(filter.estimate().abs() < f64::EPSILON);
+
+        for i in 0..=255 {
+            filter.insert(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)));
+        }
+        for i in 0..=0x3E7 {
+            filter.insert(IpAddr::V6(Ipv6Addr::new(0x2001, 0xDB8, 0, 0, 0, 0, 0, i)));
+        }
+
+        assert!((filter.estimate() - 1224.93).abs() < 0.01);
+
+        let mut other = BloomFilter::default();
+        other.union(&filter);
+        assert_eq!(other, filter);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+use tracing::{debug, trace};
+
+/// The amount of time a vote is counted.
+const VOTE_EXPIRATION: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of responders whose votes are kept.
+const MAX_VOTES: usize = 256;
+
+/// The minimum number of distinct responders which must agree on an address.
+const MIN_VOTES: usize = 3;
+
+#[derive(Debug)]
+struct Vote {
+    ip: IpAddr,
+    expiration: Instant,
+}
+
+/// Finds the local node's external IP addresses from the `ip` values in
+/// responses.
+///
+/// Each responding IP address has one vote. An external address is only
+/// accepted when enough distinct responders agree on it.
+///
+/// See [BEP 42](http://bittorrent.org/beps/bep_0042.html).
+#[derive(Debug, Default)]
+pub struct ExternalIpVotes {
+    votes: HashMap<IpAddr, Vote>,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+}
+
+impl ExternalIpVotes {
+    /// Records the external IP address which a responder saw.
+    ///
+    /// Returns the new consensus address if the consensus changed.
+    pub fn insert(&mut self, responder: IpAddr, ip: IpAddr, now: Instant) -> Option<IpAddr> {
+        if responder.is_ipv4() != ip.is_ipv4() {
+            trace!(%responder, %ip, "external IP address is not the same family as responder");
+            return None;
+        }
+
+        if !self.votes.contains_key(&responder) && self.votes.len() >= MAX_VOTES {
+            self.cleanup(now);
+            if self.votes.len() >= MAX_VOTES {
+                if let Some(oldest) = self
+                    .votes
+                    .iter()
+                    .min_by_key(|(_, vote)| vote.expiration)
+                    .map(|(responder, _)| *responder)
+                {
+                    self.votes.remove(&oldest);
+                }
+            }
+        }
+
+        self.votes.insert(
+            responder,
+            Vote {
+                ip,
+                expiration: now + VOTE_EXPIRATION,
+            },
+        );
+
+        let consensus = self.new_consensus(ip.is_ipv4(), now)?;
+        match consensus {
+            IpAddr::V4(consensus) => self.ipv4 = Some(consensus),
+            IpAddr::V6(consensus) => self.ipv6 = Some(consensus),
+        }
+        debug!(%consensus, "external IP address changed");
+        Some(consensus)
+    }
+
+    /// Returns the consensus external IPv4 address.
+    #[must_use]
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.ipv4
+    }
+
+    /// Returns the consensus external IPv6 address.
+    #[must_use]
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        self.ipv6
+    }
+
+    /// Removes expired votes.
+    ///
+    /// The consensus addresses are kept.
+    pub fn cleanup(&mut self, now: Instant) {
+        self.votes.retain(|_, vote| now < vote.expiration);
+    }
+
+    /// Returns an address for an address family which enough responders
+    /// agree on and which has more votes than the current consensus address.
+    fn new_consensus(&self, is_ipv4: bool, now: Instant) -> Option<IpAddr> {
+        let mut tally = HashMap::<IpAddr, usize>::new();
+        for vote in self
+            .votes
+            .values()
+            .filter(|vote| vote.ip.is_ipv4() == is_ipv4 && now < vote.expiration)
+        {
+            *tally.entry(vote.ip).or_default() += 1;
+        }
+
+        let current = if is_ipv4 {
+            self.ipv4.map(IpAddr::V4)
+        } else {
+            self.ipv6.map(IpAddr::V6)
+        };
+        let current_count = current
+            .and_then(|ip| tally.get(&ip).copied())
+            .unwrap_or_default();
+
+        tally
+            .into_iter()
+            .filter(|(_, count)| *count >= MIN_VOTES && *count > current_count)
+            .max_by_key(|(_, count)| *count)
+            .map(|(ip, _)| ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consensus_from_distinct_responders() {
+        let now = Instant::now();
+        let external = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2));
+        let responder = |n| IpAddr::V4(Ipv4Addr::new(192, 0, 2, n));
+
+        let mut votes = ExternalIpVotes::default();
+        assert_eq!(votes.insert(responder(1), external, now), None);
+        assert_eq!(votes.insert(responder(1), external, now), None);
+        assert_eq!(votes.insert(responder(2), external, now), None);
+        assert_eq!(votes.insert(responder(3), external, now), Some(external));
+        assert_eq!(votes.ipv4(), Some(Ipv4Addr::new(198, 51, 100, 1)));
+        assert_eq!(votes.ipv6(), None);
+
+        assert_eq!(votes.insert(responder(4), other, now), None);
+        assert_eq!(votes.insert(responder(5), other, now), None);
+        assert_eq!(votes.insert(responder(6), other, now), None);
+        assert_eq!(votes.insert(responder(7), other, now), Some(other));
+        assert_eq!(votes.ipv4(), Some(Ipv4Addr::new(198, 51, 100, 2)));
+    }
+}
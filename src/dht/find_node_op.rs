@@ -1,101 +1,172 @@
-use cloudburst::dht::{
-    krpc::{find_node::RespValues, transaction, CompactAddr, Msg},
-    node::{self, AddrId, AddrOptId},
+use cloudburst::{
+    dht::{
+        krpc::{find_node::RespValues, get_peers, transaction, CompactAddr, Msg},
+        node::{self, AddrId, AddrOptId},
+    },
+    metainfo::InfoHash,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     time::{Duration, Instant},
 };
+use tokio::sync::oneshot;
 use tracing::{error, trace};
 
-#[derive(Debug, PartialEq, Eq)]
-enum State {
-    NotQueried(u8, Instant),
-    Querying(u8, transaction::Id),
-    SuccessfulQuery,
-    DoNotQuery,
+use super::{
+    get_peers_op::{AnnouncePeerQuery, GetPeersOp, GetPeersQuery},
+    item_op::{GetItemQuery, ItemOp, PutItemQuery},
+    krpc_ext::{GetRespValues, SampleInfoHashesRespValues, ScrapeValues},
+    lookup::{resp_nodes, Lookup, State},
+    sample_infohashes_op::{SampleInfoHashesOp, SampleInfoHashesQuery},
+    SupportedAddr,
+};
+
+/// The progress of a `find_node` lookup.
+#[derive(Debug, Clone)]
+pub struct FindNodeOpSnapshot {
+    /// The node ID which is looked up
+    pub target_id: node::Id,
+    /// The closest nodes which have responded so far
+    pub closest_nodes: Vec<AddrId<CompactAddr>>,
+    /// The number of addresses which have not been queried yet
+    pub not_queried: usize,
+    /// The number of addresses with an outstanding query
+    pub querying: usize,
+    /// The number of addresses which responded
+    pub successful_query: usize,
+    /// The number of addresses which will not be queried
+    pub do_not_query: usize,
+}
+
+/// The result of a finished `find_node` lookup.
+#[derive(Debug, Clone)]
+pub struct FindNodeResult {
+    /// The node ID which was looked up
+    pub target_id: node::Id,
+    /// The closest nodes which responded, sorted by distance to the target
+    pub closest_nodes: Vec<AddrId<CompactAddr>>,
+    /// The number of queries which were sent
+    pub queries_sent: usize,
+    /// The number of queries which timed out or received an error or invalid response
+    pub failures: usize,
+    /// The time from the start of the lookup until it was done
+    pub elapsed: Duration,
 }
 
 #[derive(Debug)]
 pub struct FindNodeOp {
-    target_id: node::Id,
-    closest_nodes: Vec<AddrId<CompactAddr>>,
-    max_found_nodes: usize,
-    addrs: HashMap<AddrOptId<CompactAddr>, State>,
+    lookup: Lookup<()>,
+    subscribers: Vec<oneshot::Sender<FindNodeResult>>,
+    started_at: Instant,
+    queries_sent: usize,
+    failures: usize,
 }
 
 impl FindNodeOp {
-    pub fn new<T>(target_id: node::Id, max_found_nodes: usize, addrs: T, now: Instant) -> Self
+    pub fn new<T>(
+        target_id: node::Id,
+        max_found_nodes: usize,
+        supported_addr: SupportedAddr,
+        addrs: T,
+        now: Instant,
+    ) -> Self
     where
         T: IntoIterator<Item = AddrOptId<CompactAddr>>,
     {
         Self {
-            target_id,
-            closest_nodes: Vec::new(),
-            max_found_nodes,
-            addrs: addrs
-                .into_iter()
-                .map(|addr| (addr, State::NotQueried(0, now)))
-                .collect(),
+            lookup: Lookup::new(target_id, max_found_nodes, supported_addr, addrs, now),
+            subscribers: Vec::new(),
+            started_at: now,
+            queries_sent: 0,
+            failures: 0,
         }
     }
 
+    /// Adds a subscriber which is sent the result when the op is done.
+    pub fn subscribe(&mut self, tx: oneshot::Sender<FindNodeResult>) {
+        self.subscribers.push(tx);
+    }
+
+    /// Moves the subscribers from another op to this op.
+    pub fn merge(&mut self, other: &mut FindNodeOp) {
+        self.subscribers.append(&mut other.subscribers);
+    }
+
     /// Returns the target ID.
     #[must_use]
     #[inline]
     pub fn target_id(&self) -> node::Id {
-        self.target_id
+        self.lookup.target_id()
     }
 
     /// Returns if the space is done.
     #[must_use]
     #[inline]
     pub fn is_done(&self) -> bool {
-        self.addrs.values().all(|s| match *s {
-            State::SuccessfulQuery | State::DoNotQuery => true,
-            State::NotQueried(_, _) | State::Querying(_, _) => false,
-        })
+        self.lookup.queries().is_done()
     }
 
+    /// Returns when the lookup finishes with the closest nodes found so far.
     #[must_use]
     #[inline]
-    fn max_distance(&self) -> node::Id {
-        if self.closest_nodes.len() < self.max_found_nodes {
-            node::Id::max()
-        } else {
-            self.closest_nodes
-                .last()
-                .map_or(node::Id::max(), |addr_id| {
-                    addr_id.id().distance(self.target_id)
-                })
-        }
+    pub fn deadline(&self) -> Instant {
+        self.lookup.deadline()
     }
 
-    fn try_replace_closest_nodes(&mut self, addr_id: AddrId<CompactAddr>) {
-        let new_distance = addr_id.id().distance(self.target_id);
-        let is_max_found_nodes = self.closest_nodes.len() == self.max_found_nodes;
-        if is_max_found_nodes {
-            let max_distance = self.max_distance();
-            if new_distance < max_distance {
-                self.closest_nodes.pop();
-            } else {
-                return;
+    /// Finishes the lookup with the closest nodes found so far if the
+    /// deadline has passed.
+    pub fn on_timeout(&mut self, now: Instant) {
+        self.lookup.on_timeout(now);
+    }
+
+    fn closest_nodes(&self) -> Vec<AddrId<CompactAddr>> {
+        self.lookup
+            .closest_nodes()
+            .iter()
+            .map(|(addr_id, ())| *addr_id)
+            .collect()
+    }
+
+    /// Returns the op's progress.
+    #[must_use]
+    pub fn snapshot(&self) -> FindNodeOpSnapshot {
+        let mut snapshot = FindNodeOpSnapshot {
+            target_id: self.target_id(),
+            closest_nodes: self.closest_nodes(),
+            not_queried: 0,
+            querying: 0,
+            successful_query: 0,
+            do_not_query: 0,
+        };
+        for state in self.lookup.queries().states() {
+            match state {
+                State::NotQueried(_, _) => snapshot.not_queried += 1,
+                State::Querying(_, _) => snapshot.querying += 1,
+                State::SuccessfulQuery => snapshot.successful_query += 1,
+                State::DoNotQuery => snapshot.do_not_query += 1,
             }
         }
+        snapshot
+    }
 
-        self.closest_nodes.push(addr_id);
-        let target_id = self.target_id;
-        self.closest_nodes
-            .sort_by_key(|a| a.id().distance(target_id));
-
-        if is_max_found_nodes {
-            let max_distance = self.max_distance();
-            self.addrs.retain(|potential_addr_opt_id, _| {
-                potential_addr_opt_id
-                    .id()
-                    .map_or(true, |id| id.distance(target_id) < max_distance)
-            });
+    /// Sends the result to all subscribers and returns the result.
+    pub fn finish(&mut self, now: Instant) -> FindNodeResult {
+        let result = FindNodeResult {
+            target_id: self.target_id(),
+            closest_nodes: self.closest_nodes(),
+            queries_sent: self.queries_sent,
+            failures: self.failures,
+            elapsed: now.saturating_duration_since(self.started_at),
+        };
+        for tx in self.subscribers.drain(..) {
+            let _ = tx.send(result.clone());
         }
+        result
+    }
+
+    fn on_failure(&mut self, addr_opt_id: AddrOptId<CompactAddr>, now: Instant) {
+        self.failures += 1;
+        self.lookup.queries_mut().on_failure(addr_opt_id, now);
     }
 }
 
@@ -103,14 +174,62 @@ impl FindNodeOp {
 pub struct OpsManager {
     ops: Vec<FindNodeOp>,
     tx_to_op: HashMap<transaction::Id, node::Id>,
+    get_peers_ops: Vec<GetPeersOp>,
+    tx_to_get_peers_op: HashMap<transaction::Id, InfoHash>,
+    announce_peer_queries: Vec<AnnouncePeerQuery>,
+    item_ops: Vec<ItemOp>,
+    tx_to_item_op: HashMap<transaction::Id, node::Id>,
+    put_item_queries: Vec<PutItemQuery>,
+    finished_ops: Vec<FindNodeResult>,
+    sample_infohashes_op: Option<SampleInfoHashesOp>,
+    sample_infohashes_txs: HashSet<transaction::Id>,
 }
 
 impl OpsManager {
-    pub fn insert_op(&mut self, new_op: FindNodeOp) {
+    /// Returns the running `find_node` ops.
+    pub fn find_node_ops(&self) -> impl Iterator<Item = &FindNodeOp> {
+        self.ops.iter()
+    }
+
+    /// Returns the number of running ops by kind.
+    pub fn active_ops(&self) -> [(&'static str, usize); 4] {
+        [
+            ("find_node", self.ops.len()),
+            ("get_peers", self.get_peers_ops.len()),
+            ("item", self.item_ops.len()),
+            (
+                "sample_infohashes",
+                usize::from(self.sample_infohashes_op.is_some()),
+            ),
+        ]
+    }
+
+    /// Returns if a running `find_node` op for the target has received a
+    /// response.
+    #[must_use]
+    pub fn has_closest_nodes(&self, target_id: node::Id) -> bool {
+        self.ops
+            .iter()
+            .any(|op| op.target_id() == target_id && !op.lookup.closest_nodes().is_empty())
+    }
+
+    /// Inserts a `find_node` op.
+    ///
+    /// If an op for the same target is already running, the new op's
+    /// subscribers are moved to the existing op.
+    pub fn insert_op(&mut self, mut new_op: FindNodeOp) {
         let target_id = new_op.target_id();
-        if self.ops.iter().any(|op| op.target_id == target_id) {
+        if let Some(op) = self.ops.iter_mut().find(|op| op.target_id() == target_id) {
+            op.merge(&mut new_op);
             return;
         }
+
+        if new_op.is_done() {
+            let now = new_op.started_at;
+            self.finished_ops.push(new_op.finish(now));
+            return;
+        }
+
         self.ops.push(new_op);
     }
 
@@ -120,46 +239,170 @@ impl OpsManager {
         target_id: node::Id,
         addr_opt_id: AddrOptId<CompactAddr>,
     ) {
-        if let Some(pos) = self.ops.iter().position(|op| op.target_id == target_id) {
-            if let Some(op) = self.ops.get_mut(pos) {
-                if let Some(state) = op.addrs.get_mut(&addr_opt_id) {
-                    match state {
-                        State::NotQueried(attempts, _) => {
-                            *state = State::Querying(*attempts + 1, tx_id);
-                        }
-                        State::Querying(_, _) | State::SuccessfulQuery | State::DoNotQuery => {
-                            panic!("unexpected state")
-                        }
-                    }
-                } else {
-                    panic!("started tx for op which does not know about address");
-                }
-                self.tx_to_op.insert(tx_id, target_id);
-            } else {
-                unreachable!();
+        if let Some(op) = self.ops.iter_mut().find(|op| op.target_id() == target_id) {
+            if op.lookup.queries_mut().on_query_sent(addr_opt_id, tx_id) {
+                op.queries_sent += 1;
             }
+            self.tx_to_op.insert(tx_id, target_id);
         } else {
             debug_assert!(false);
         }
     }
 
+    /// Inserts a get peers op.
+    ///
+    /// If an op for the same `InfoHash` is already running, the new op's
+    /// subscribers and announce are moved to the existing op.
+    pub fn insert_get_peers_op(&mut self, mut new_op: GetPeersOp) {
+        let info_hash = new_op.info_hash();
+        if let Some(op) = self
+            .get_peers_ops
+            .iter_mut()
+            .find(|op| op.info_hash() == info_hash)
+        {
+            op.merge(&mut new_op);
+            return;
+        }
+
+        if new_op.is_done() {
+            self.announce_peer_queries.extend(new_op.finish());
+            return;
+        }
+
+        self.get_peers_ops.push(new_op);
+    }
+
+    pub fn insert_get_peers_tx(
+        &mut self,
+        tx_id: transaction::Id,
+        info_hash: InfoHash,
+        addr_opt_id: AddrOptId<CompactAddr>,
+    ) {
+        if let Some(op) = self
+            .get_peers_ops
+            .iter_mut()
+            .find(|op| op.info_hash() == info_hash)
+        {
+            op.on_query_sent(addr_opt_id, tx_id);
+            self.tx_to_get_peers_op.insert(tx_id, info_hash);
+        } else {
+            debug_assert!(false);
+        }
+    }
+
+    /// Stops announcing the local node as a peer for a torrent.
+    ///
+    /// The running get peers op for the `InfoHash` still finishes, and the
+    /// announce peer queries which are not sent yet are dropped.
+    pub fn stop_announce(&mut self, info_hash: InfoHash) {
+        if let Some(op) = self
+            .get_peers_ops
+            .iter_mut()
+            .find(|op| op.info_hash() == info_hash)
+        {
+            op.clear_announce();
+        }
+        self.announce_peer_queries
+            .retain(|query| query.info_hash != info_hash);
+    }
+
+    pub fn next_get_peers_query(&mut self, now: Instant) -> Option<GetPeersQuery> {
+        self.get_peers_ops.iter().find_map(|op| op.next_query(now))
+    }
+
+    /// Inserts an item op.
+    ///
+    /// If an op for the same target is already running, the new op's
+    /// subscribers and put are moved to the existing op.
+    pub fn insert_item_op(&mut self, mut new_op: ItemOp) {
+        let target_id = new_op.target_id();
+        if let Some(op) = self
+            .item_ops
+            .iter_mut()
+            .find(|op| op.target_id() == target_id)
+        {
+            op.merge(&mut new_op);
+            return;
+        }
+
+        if new_op.is_done() {
+            self.put_item_queries.extend(new_op.finish());
+            return;
+        }
+
+        self.item_ops.push(new_op);
+    }
+
+    pub fn insert_item_tx(
+        &mut self,
+        tx_id: transaction::Id,
+        target_id: node::Id,
+        addr_opt_id: AddrOptId<CompactAddr>,
+    ) {
+        if let Some(op) = self
+            .item_ops
+            .iter_mut()
+            .find(|op| op.target_id() == target_id)
+        {
+            op.on_query_sent(addr_opt_id, tx_id);
+            self.tx_to_item_op.insert(tx_id, target_id);
+        } else {
+            debug_assert!(false);
+        }
+    }
+
+    pub fn next_get_item_query(&mut self, now: Instant) -> Option<GetItemQuery> {
+        self.item_ops.iter().find_map(|op| op.next_query(now))
+    }
+
+    /// Inserts a `sample_infohashes` op.
+    ///
+    /// Only one crawl runs at a time. If an op is already running, the new
+    /// op's subscribers are moved to the existing op.
+    pub fn insert_sample_infohashes_op(&mut self, mut new_op: SampleInfoHashesOp) {
+        if let Some(op) = &mut self.sample_infohashes_op {
+            op.merge(&mut new_op);
+            return;
+        }
+
+        if new_op.is_done() {
+            new_op.finish();
+            return;
+        }
+
+        self.sample_infohashes_op = Some(new_op);
+    }
+
+    pub fn insert_sample_infohashes_tx(
+        &mut self,
+        tx_id: transaction::Id,
+        addr_opt_id: AddrOptId<CompactAddr>,
+    ) {
+        if let Some(op) = &mut self.sample_infohashes_op {
+            op.on_query_sent(addr_opt_id, tx_id);
+            self.sample_infohashes_txs.insert(tx_id);
+        } else {
+            debug_assert!(false);
+        }
+    }
+
+    pub fn next_sample_infohashes_query(&mut self, now: Instant) -> Option<SampleInfoHashesQuery> {
+        self.sample_infohashes_op
+            .as_ref()
+            .and_then(|op| op.next_query(now))
+    }
+
     pub fn next_addr_to_query(
         &mut self,
         now: Instant,
     ) -> Option<(node::Id, AddrOptId<CompactAddr>)> {
-        for op in &mut self.ops {
-            for (addr_opt_id, state) in &op.addrs {
-                match state {
-                    State::NotQueried(_, deadline) => {
-                        if *deadline <= now {
-                            trace!(addr = %addr_opt_id.addr, node_id = ?addr_opt_id.id, target_id = %op.target_id, "returning address to send find node query to");
-                            return Some((op.target_id, *addr_opt_id));
-                        }
-                    }
-                    State::Querying(_, _) | State::SuccessfulQuery | State::DoNotQuery => {}
-                }
+        for op in &self.ops {
+            let target_id = op.target_id();
+            if let Some(addr_opt_id) = op.lookup.queries().next_addr(now) {
+                trace!(addr = %addr_opt_id.addr, node_id = ?addr_opt_id.id, %target_id, "returning address to send find node query to");
+                return Some((target_id, addr_opt_id));
             }
-            trace!(target_id = %op.target_id, "no more addresses to send find node query to");
+            trace!(%target_id, "no more addresses to send find node query to");
         }
 
         None
@@ -172,23 +415,89 @@ impl OpsManager {
         msg: &Msg<'_>,
         now: Instant,
     ) {
-        if let Some(target_id) = self.tx_to_op.remove(&tx_id) {
-            if let Some(pos) = self.ops.iter().position(|op| op.target_id == target_id) {
+        if let Some(info_hash) = self.tx_to_get_peers_op.remove(&tx_id) {
+            if let Some(pos) = self
+                .get_peers_ops
+                .iter()
+                .position(|op| op.info_hash() == info_hash)
+            {
+                let op = &mut self.get_peers_ops[pos];
+                if let Some(Ok(resp)) = msg.values::<get_peers::RespValues<'_, Vec<&[u8]>>>() {
+                    if let Some(Ok(scrape)) = msg.values::<ScrapeValues<'_>>() {
+                        op.on_scrape_resp(&scrape);
+                    }
+                    op.on_resp(addr_opt_id, &resp, now);
+                    trace!(?tx_id, ?info_hash, "processed get peers response");
+                } else {
+                    error!(?op, "Could not try_from response message");
+                    op.on_failure(addr_opt_id, now);
+                }
+
+                if op.is_done() {
+                    let mut op = self.get_peers_ops.remove(pos);
+                    self.announce_peer_queries.extend(op.finish());
+                    trace!(?info_hash, "removed get peers op");
+                }
+            } else {
+                error!(?tx_id, ?info_hash, "Could not find op for info_hash");
+            }
+        } else if let Some(target_id) = self.tx_to_item_op.remove(&tx_id) {
+            if let Some(pos) = self
+                .item_ops
+                .iter()
+                .position(|op| op.target_id() == target_id)
+            {
+                let op = &mut self.item_ops[pos];
+                if let (Some(Ok(resp)), Some(Ok(item))) = (
+                    msg.values::<RespValues<'_>>(),
+                    msg.values::<GetRespValues<'_>>(),
+                ) {
+                    op.on_resp(addr_opt_id, &resp, &item, now);
+                    trace!(?tx_id, ?target_id, "processed get item response");
+                } else {
+                    error!(?op, "Could not try_from response message");
+                    op.on_failure(addr_opt_id, now);
+                }
+
+                if op.is_done() {
+                    let mut op = self.item_ops.remove(pos);
+                    self.put_item_queries.extend(op.finish());
+                    trace!(?target_id, "removed item op");
+                }
+            } else {
+                error!(?tx_id, ?target_id, "Could not find op for target_id");
+            }
+        } else if self.sample_infohashes_txs.remove(&tx_id) {
+            if let Some(op) = &mut self.sample_infohashes_op {
+                if let (Some(Ok(resp)), Some(Ok(sample))) = (
+                    msg.values::<RespValues<'_>>(),
+                    msg.values::<SampleInfoHashesRespValues<'_>>(),
+                ) {
+                    op.on_resp(addr_opt_id, &resp, &sample, now);
+                    trace!(?tx_id, "processed sample infohashes response");
+                } else {
+                    error!(?op, "Could not try_from response message");
+                    op.on_failure(addr_opt_id, now);
+                }
+
+                self.finish_sample_infohashes_op_if_done();
+            } else {
+                error!(?tx_id, "Could not find sample infohashes op");
+            }
+        } else if let Some(target_id) = self.tx_to_op.remove(&tx_id) {
+            if let Some(pos) = self.ops.iter().position(|op| op.target_id() == target_id) {
                 if let Some(op) = self.ops.get_mut(pos) {
                     if let Some(Ok(resp)) = msg.values::<RespValues<'_>>() {
                         on_resp(op, addr_opt_id, &resp, now);
+                        op.lookup.queries_mut().on_success(addr_opt_id);
                         trace!(?tx_id, ?target_id, "processed find node response");
                     } else {
                         error!(?op, "Could not try_from response message");
-                    }
-
-                    if let Some(state) = op.addrs.get_mut(&addr_opt_id) {
-                        *state = State::SuccessfulQuery;
+                        op.on_failure(addr_opt_id, now);
                     }
 
                     if op.is_done() {
-                        self.ops.remove(pos);
-                        trace!(?target_id, "Removed op");
+                        self.finish_op(pos, now);
                     }
                 }
             } else {
@@ -205,33 +514,14 @@ impl OpsManager {
         tx_id: transaction::Id,
         now: Instant,
     ) {
-        if let Some(target_id) = self.tx_to_op.remove(&tx_id) {
-            if let Some(pos) = self.ops.iter().position(|op| op.target_id == target_id) {
-                if let Some(op) = self.ops.get_mut(pos) {
-                    if let Some(state) = op.addrs.get_mut(&addr_opt_id) {
-                        match state {
-                            State::Querying(attempts, _) => {
-                                if *attempts < 3 {
-                                    *state =
-                                        State::NotQueried(*attempts, now + Duration::from_secs(60));
-                                } else {
-                                    *state = State::DoNotQuery;
-                                }
-                            }
-                            State::DoNotQuery
-                            | State::NotQueried(_, _)
-                            | State::SuccessfulQuery => {
-                                panic!();
-                            }
-                        }
-                    }
-
-                    if op.is_done() {
-                        self.ops.remove(pos);
-                        trace!(?target_id, "removed find node op");
-                    }
-                }
-            }
+        if let Some(info_hash) = self.tx_to_get_peers_op.remove(&tx_id) {
+            self.on_get_peers_failure(addr_opt_id, info_hash, now);
+        } else if let Some(target_id) = self.tx_to_item_op.remove(&tx_id) {
+            self.on_item_failure(addr_opt_id, target_id, now);
+        } else if self.sample_infohashes_txs.remove(&tx_id) {
+            self.on_sample_infohashes_failure(addr_opt_id, now);
+        } else if let Some(target_id) = self.tx_to_op.remove(&tx_id) {
+            self.on_find_node_failure(addr_opt_id, target_id, now);
         }
     }
 
@@ -241,38 +531,162 @@ impl OpsManager {
         tx_id: transaction::Id,
         now: Instant,
     ) {
-        if let Some(target_id) = self.tx_to_op.remove(&tx_id) {
-            if let Some(pos) = self.ops.iter().position(|op| op.target_id == target_id) {
-                if let Some(op) = self.ops.get_mut(pos) {
-                    if let Some(state) = op.addrs.get_mut(&addr_opt_id) {
-                        match state {
-                            State::Querying(attempts, _) => {
-                                if *attempts < 3 {
-                                    *state =
-                                        State::NotQueried(*attempts, now + Duration::from_secs(60));
-                                } else {
-                                    *state = State::DoNotQuery;
-                                }
-                            }
-                            State::DoNotQuery
-                            | State::NotQueried(_, _)
-                            | State::SuccessfulQuery => {
-                                panic!();
-                            }
-                        }
-                    }
+        if let Some(info_hash) = self.tx_to_get_peers_op.remove(&tx_id) {
+            self.on_get_peers_failure(addr_opt_id, info_hash, now);
+        } else if let Some(target_id) = self.tx_to_item_op.remove(&tx_id) {
+            self.on_item_failure(addr_opt_id, target_id, now);
+        } else if self.sample_infohashes_txs.remove(&tx_id) {
+            self.on_sample_infohashes_failure(addr_opt_id, now);
+        } else if let Some(target_id) = self.tx_to_op.remove(&tx_id) {
+            self.on_find_node_failure(addr_opt_id, target_id, now);
+        }
+    }
 
-                    if op.is_done() {
-                        self.ops.remove(pos);
-                        trace!(?target_id, "removed find node op");
-                    }
-                }
+    fn on_find_node_failure(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        target_id: node::Id,
+        now: Instant,
+    ) {
+        if let Some(pos) = self.ops.iter().position(|op| op.target_id() == target_id) {
+            let op = &mut self.ops[pos];
+            op.on_failure(addr_opt_id, now);
+
+            if op.is_done() {
+                self.finish_op(pos, now);
+            }
+        }
+    }
+
+    fn finish_op(&mut self, pos: usize, now: Instant) {
+        let mut op = self.ops.remove(pos);
+        self.finished_ops.push(op.finish(now));
+        trace!(target_id = ?op.target_id(), "removed find node op");
+    }
+
+    fn on_get_peers_failure(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        info_hash: InfoHash,
+        now: Instant,
+    ) {
+        if let Some(pos) = self
+            .get_peers_ops
+            .iter()
+            .position(|op| op.info_hash() == info_hash)
+        {
+            let op = &mut self.get_peers_ops[pos];
+            op.on_failure(addr_opt_id, now);
+
+            if op.is_done() {
+                let mut op = self.get_peers_ops.remove(pos);
+                self.announce_peer_queries.extend(op.finish());
+                trace!(?info_hash, "removed get peers op");
+            }
+        }
+    }
+
+    fn on_item_failure(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        target_id: node::Id,
+        now: Instant,
+    ) {
+        if let Some(pos) = self
+            .item_ops
+            .iter()
+            .position(|op| op.target_id() == target_id)
+        {
+            let op = &mut self.item_ops[pos];
+            op.on_failure(addr_opt_id, now);
+
+            if op.is_done() {
+                let mut op = self.item_ops.remove(pos);
+                self.put_item_queries.extend(op.finish());
+                trace!(?target_id, "removed item op");
+            }
+        }
+    }
+
+    fn on_sample_infohashes_failure(&mut self, addr_opt_id: AddrOptId<CompactAddr>, now: Instant) {
+        if let Some(op) = &mut self.sample_infohashes_op {
+            op.on_failure(addr_opt_id, now);
+            self.finish_sample_infohashes_op_if_done();
+        }
+    }
+
+    fn finish_sample_infohashes_op_if_done(&mut self) {
+        if self
+            .sample_infohashes_op
+            .as_ref()
+            .is_some_and(SampleInfoHashesOp::is_done)
+        {
+            if let Some(mut op) = self.sample_infohashes_op.take() {
+                op.finish();
+                trace!("removed sample infohashes op");
             }
         }
     }
 
-    pub fn cleanup(&mut self) {
-        self.ops.retain(|op| !op.is_done());
+    /// Returns the earliest deadline of the running lookups.
+    #[must_use]
+    pub fn timeout(&self) -> Option<Instant> {
+        self.ops
+            .iter()
+            .map(FindNodeOp::deadline)
+            .chain(self.get_peers_ops.iter().map(GetPeersOp::deadline))
+            .chain(self.item_ops.iter().map(ItemOp::deadline))
+            .min()
+    }
+
+    /// Removes the finished ops.
+    ///
+    /// Lookups which have passed their deadline are finished with the
+    /// closest nodes found so far.
+    pub fn cleanup(&mut self, now: Instant) {
+        let finished_ops = &mut self.finished_ops;
+        self.ops.retain_mut(|op| {
+            op.on_timeout(now);
+            if op.is_done() {
+                finished_ops.push(op.finish(now));
+                return false;
+            }
+            true
+        });
+        let announce_peer_queries = &mut self.announce_peer_queries;
+        self.get_peers_ops.retain_mut(|op| {
+            op.on_timeout(now);
+            if op.is_done() {
+                announce_peer_queries.extend(op.finish());
+                return false;
+            }
+            true
+        });
+        let put_item_queries = &mut self.put_item_queries;
+        self.item_ops.retain_mut(|op| {
+            op.on_timeout(now);
+            if op.is_done() {
+                put_item_queries.extend(op.finish());
+                return false;
+            }
+            true
+        });
+        self.finish_sample_infohashes_op_if_done();
+    }
+
+    /// Returns an announce peer query from a finished get peers op.
+    pub fn pop_announce_peer_query(&mut self) -> Option<AnnouncePeerQuery> {
+        self.announce_peer_queries.pop()
+    }
+
+    /// Returns the result of a finished `find_node` op.
+    pub fn pop_finished_op(&mut self) -> Option<FindNodeResult> {
+        self.finished_ops.pop()
+    }
+
+    /// Returns a put query from a finished item op.
+    pub fn pop_put_item_query(&mut self) -> Option<PutItemQuery> {
+        self.put_item_queries.pop()
     }
 }
 
@@ -283,53 +697,152 @@ pub(crate) fn on_resp(
     now: Instant,
 ) {
     if let Some(node_id) = addr_opt_id.id() {
-        op.try_replace_closest_nodes(AddrId::new(*addr_opt_id.addr(), node_id));
-    }
-
-    let max_distance = op.max_distance();
-
-    if let Some(Ok(nodes)) = resp.nodes() {
-        for node in nodes {
-            let node_id = node.id();
-            let node_distance = node.id().distance(op.target_id);
-            if node_distance >= max_distance {
-                trace!(
-                    ?node_id,
-                    ?node_distance,
-                    ?max_distance,
-                    "distance is greater than maximum distance"
-                );
-                continue;
-            }
+        op.lookup
+            .try_replace_closest_nodes(AddrId::new(*addr_opt_id.addr(), node_id), ());
+    }
+
+    op.lookup
+        .insert_nodes(resp_nodes(resp.nodes(), resp.nodes6()), now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudburst::dht::{krpc::ser, node::LocalId};
+    use serde_bytes::Bytes;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn test_subscribers_receive_closest_nodes() {
+        let now = Instant::now();
+        let target_id = node::Id::rand(&mut rand::thread_rng()).unwrap();
+        let remote_id = node::Id::rand(&mut rand::thread_rng()).unwrap();
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let addr_opt_id = AddrOptId::new(addr, Some(remote_id));
+
+        let mut ops_manager = OpsManager::default();
+        let mut op = FindNodeOp::new(target_id, 8, SupportedAddr::Ipv4, [addr_opt_id], now);
+        let (tx, mut rx) = oneshot::channel();
+        op.subscribe(tx);
+        ops_manager.insert_op(op);
+
+        let mut op = FindNodeOp::new(target_id, 8, SupportedAddr::Ipv4, [addr_opt_id], now);
+        let (tx2, mut rx2) = oneshot::channel();
+        op.subscribe(tx2);
+        ops_manager.insert_op(op);
+        assert_eq!(ops_manager.find_node_ops().count(), 1);
+
+        assert_eq!(
+            ops_manager.next_addr_to_query(now),
+            Some((target_id, addr_opt_id))
+        );
+        let tx_id = transaction::Id::from(1);
+        ops_manager.insert_tx(tx_id, target_id, addr_opt_id);
+        assert!(rx.try_recv().is_err());
+
+        let local_id = LocalId::from(remote_id);
+        let resp = bt_bencode::to_vec(&ser::RespMsg {
+            r: RespValues::new(&local_id, Some(Bytes::new(&[])), None),
+            t: Bytes::new(tx_id.as_ref()),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+        let done = now + Duration::from_secs(2);
+        ops_manager.on_recv(addr_opt_id, tx_id, &msg, done);
+        assert_eq!(ops_manager.find_node_ops().count(), 0);
+
+        for rx in [&mut rx, &mut rx2] {
+            let result = rx.try_recv().unwrap();
+            assert_eq!(result.target_id, target_id);
+            assert_eq!(result.closest_nodes, vec![AddrId::new(addr, remote_id)]);
+            assert_eq!(result.queries_sent, 1);
+            assert_eq!(result.failures, 0);
+            assert_eq!(result.elapsed, Duration::from_secs(2));
+        }
+        assert!(ops_manager.pop_finished_op().is_some());
+        assert!(ops_manager.pop_finished_op().is_none());
+    }
+
+    #[test]
+    fn test_failures_are_counted() {
+        let now = Instant::now();
+        let target_id = node::Id::rand(&mut rand::thread_rng()).unwrap();
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let addr_opt_id = AddrOptId::with_addr(addr);
+
+        let mut ops_manager = OpsManager::default();
+        ops_manager.insert_op(FindNodeOp::new(
+            target_id,
+            8,
+            SupportedAddr::Ipv4,
+            [addr_opt_id],
+            now,
+        ));
 
-            let addr = CompactAddr::from(*node.addr());
-            op.addrs
-                .entry(AddrOptId::new(addr, Some(node.id())))
-                .or_insert(State::NotQueried(0, now));
+        let mut now = now;
+        for tx_id in 1..=3 {
+            if tx_id > 1 {
+                now += Duration::from_secs(60);
+            }
+            assert_eq!(
+                ops_manager.next_addr_to_query(now),
+                Some((target_id, addr_opt_id))
+            );
+            let tx_id = transaction::Id::from(tx_id);
+            ops_manager.insert_tx(tx_id, target_id, addr_opt_id);
+            now += Duration::from_secs(60);
+            ops_manager.on_tx_timeout(addr_opt_id, tx_id, now);
         }
+
+        let result = ops_manager.pop_finished_op().unwrap();
+        assert!(result.closest_nodes.is_empty());
+        assert_eq!(result.queries_sent, 3);
+        assert_eq!(result.failures, 3);
+        assert_eq!(result.elapsed, Duration::from_secs(300));
+
+        ops_manager.insert_op(FindNodeOp::new(
+            target_id,
+            8,
+            SupportedAddr::Ipv4,
+            std::iter::empty(),
+            now,
+        ));
+        assert_eq!(ops_manager.find_node_ops().count(), 0);
+        assert_eq!(ops_manager.pop_finished_op().unwrap().queries_sent, 0);
     }
 
-    // if let Some(nodes) = resp.nodes6() {
-    //     for node in nodes {
-    //         let node_id = node.id();
-    //         let node_distance = node.id().distance(op.target_id);
-    //         if node_distance >= max_distance {
-    //             trace!(
-    //                 ?node_id,
-    //                 ?node_distance,
-    //                 ?max_distance,
-    //                 "distance is greater than maximum distance"
-    //             );
-    //             continue;
-    //         }
-
-    //         let addr = CompactAddr::from(*node.addr());
-    //         if op.queried_addrs.contains(&addr) {
-    //             trace!(?addr, ?node_id, "already saw address");
-    //             continue;
-    //         }
-    //         op.nodes_to_query
-    //             .insert(AddrOptId::new(addr, Some(node.id())));
-    //     }
-    // }
+    #[test]
+    fn test_malformed_response_is_retried() {
+        let now = Instant::now();
+        let target_id = node::Id::rand(&mut rand::thread_rng()).unwrap();
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let addr_opt_id = AddrOptId::with_addr(addr);
+
+        let mut ops_manager = OpsManager::default();
+        ops_manager.insert_op(FindNodeOp::new(
+            target_id,
+            8,
+            SupportedAddr::Ipv4,
+            [addr_opt_id],
+            now,
+        ));
+
+        let tx_id = transaction::Id::from(1);
+        ops_manager.insert_tx(tx_id, target_id, addr_opt_id);
+        let mut resp = b"d1:rde1:t".to_vec();
+        resp.extend(format!("{}:", tx_id.as_ref().len()).as_bytes());
+        resp.extend(tx_id.as_ref());
+        resp.extend(b"1:y1:re");
+        let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+        ops_manager.on_recv(addr_opt_id, tx_id, &msg, now);
+
+        let op = ops_manager.find_node_ops().next().unwrap();
+        assert_eq!(op.failures, 1);
+        assert_eq!(op.snapshot().not_queried, 1);
+        assert_eq!(
+            ops_manager.next_addr_to_query(now + Duration::from_secs(60)),
+            Some((target_id, addr_opt_id))
+        );
+    }
 }
@@ -0,0 +1,369 @@
+use cloudburst::{
+    dht::{
+        krpc::{get_peers::RespValues, transaction, CompactAddr},
+        node::{self, AddrId, AddrOptId},
+    },
+    metainfo::InfoHash,
+};
+use std::{collections::BTreeSet, time::Instant};
+use tokio::sync::oneshot;
+
+use super::{
+    bloom::BloomFilter,
+    krpc_ext::ScrapeValues,
+    lookup::{resp_nodes, Lookup},
+    SupportedAddr,
+};
+
+/// The result of a get peers lookup.
+#[derive(Debug, Clone)]
+pub struct GetPeersResult {
+    /// The torrent's `InfoHash`
+    pub info_hash: InfoHash,
+    /// Peers returned by the queried nodes
+    pub peers: Vec<CompactAddr>,
+    /// The closest nodes found with the token each one returned
+    ///
+    /// The nodes are sorted by distance to the `InfoHash`. The tokens can be
+    /// used to announce a peer to the nodes.
+    pub closest_nodes: Vec<(AddrId<CompactAddr>, Vec<u8>)>,
+    /// The estimated number of seeders and leechers if the lookup was a scrape
+    pub scrape: Option<ScrapeEstimate>,
+}
+
+/// The estimated number of peers for a torrent from the BEP 33 bloom filters
+/// returned by the queried nodes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrapeEstimate {
+    /// The estimated number of seeders
+    pub seeders: f64,
+    /// The estimated number of leechers
+    pub leechers: f64,
+}
+
+/// A get peers query to send for a lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetPeersQuery {
+    /// The torrent's `InfoHash`
+    pub info_hash: InfoHash,
+    /// The node to query
+    pub addr_opt_id: AddrOptId<CompactAddr>,
+    /// If the query should request the BEP 33 bloom filters
+    pub scrape: bool,
+}
+
+/// The arguments used to announce the local node as a peer for a torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnnounceArgs {
+    /// The port which peers should connect to
+    pub port: u16,
+    /// If the remote node should use the source port of the announce query instead of `port`
+    pub implied_port: bool,
+}
+
+/// An announce peer query to send to a node which returned a token.
+#[derive(Debug, Clone)]
+pub struct AnnouncePeerQuery {
+    /// The torrent's `InfoHash`
+    pub info_hash: InfoHash,
+    /// The node to announce to
+    pub addr_id: AddrId<CompactAddr>,
+    /// The token returned by the node in a get peers response
+    pub token: Vec<u8>,
+    /// The announce arguments
+    pub args: AnnounceArgs,
+}
+
+/// Finds peers for a torrent by iteratively querying nodes closer to the `InfoHash`.
+#[derive(Debug)]
+pub struct GetPeersOp {
+    info_hash: InfoHash,
+    lookup: Lookup<Vec<u8>>,
+    peers: BTreeSet<CompactAddr>,
+    subscribers: Vec<oneshot::Sender<GetPeersResult>>,
+    announce: Option<AnnounceArgs>,
+    scrape: Option<(BloomFilter, BloomFilter)>,
+}
+
+impl GetPeersOp {
+    pub fn new<T>(
+        info_hash: InfoHash,
+        max_found_nodes: usize,
+        supported_addr: SupportedAddr,
+        addrs: T,
+        now: Instant,
+    ) -> Self
+    where
+        T: IntoIterator<Item = AddrOptId<CompactAddr>>,
+    {
+        Self {
+            info_hash,
+            lookup: Lookup::new(
+                node::Id::from(info_hash.0),
+                max_found_nodes,
+                supported_addr,
+                addrs,
+                now,
+            ),
+            peers: BTreeSet::new(),
+            subscribers: Vec::new(),
+            announce: None,
+            scrape: None,
+        }
+    }
+
+    /// Requests the BEP 33 bloom filters from the queried nodes.
+    pub fn set_scrape(&mut self) {
+        if self.scrape.is_none() {
+            self.scrape = Some((BloomFilter::default(), BloomFilter::default()));
+        }
+    }
+
+    /// Announces the local node to the closest nodes when the op is done.
+    pub fn set_announce(&mut self, announce: AnnounceArgs) {
+        self.announce = Some(announce);
+    }
+
+    /// Stops the op from announcing the local node when it is done.
+    pub fn clear_announce(&mut self) {
+        self.announce = None;
+    }
+
+    /// Adds a subscriber which is sent the result when the op is done.
+    pub fn subscribe(&mut self, tx: oneshot::Sender<GetPeersResult>) {
+        self.subscribers.push(tx);
+    }
+
+    /// Moves the subscribers, the announce, and the scrape from another op to this op.
+    ///
+    /// If this op was not a scrape, only the nodes queried after the merge
+    /// are scraped.
+    pub fn merge(&mut self, other: &mut GetPeersOp) {
+        self.subscribers.append(&mut other.subscribers);
+        if let Some(announce) = other.announce.take() {
+            self.announce = Some(announce);
+        }
+        if other.scrape.is_some() {
+            self.set_scrape();
+        }
+    }
+
+    /// Returns the `InfoHash`.
+    #[must_use]
+    #[inline]
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
+
+    /// Returns if the space is done.
+    #[must_use]
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.lookup.queries().is_done()
+    }
+
+    /// Returns when the lookup finishes with the closest nodes found so far.
+    #[must_use]
+    #[inline]
+    pub fn deadline(&self) -> Instant {
+        self.lookup.deadline()
+    }
+
+    /// Finishes the lookup with the closest nodes found so far if the
+    /// deadline has passed.
+    pub fn on_timeout(&mut self, now: Instant) {
+        self.lookup.on_timeout(now);
+    }
+
+    /// Returns the current result of the lookup.
+    #[must_use]
+    pub fn result(&self) -> GetPeersResult {
+        GetPeersResult {
+            info_hash: self.info_hash,
+            peers: self.peers.iter().copied().collect(),
+            closest_nodes: self.lookup.closest_nodes().to_vec(),
+            scrape: self.scrape.as_ref().map(|(seeds, peers)| ScrapeEstimate {
+                seeders: seeds.estimate(),
+                leechers: peers.estimate(),
+            }),
+        }
+    }
+
+    /// Sends the result to all subscribers.
+    ///
+    /// Returns the announce peer queries to send if the op should announce.
+    pub fn finish(&mut self) -> impl Iterator<Item = AnnouncePeerQuery> + '_ {
+        let result = self.result();
+        for tx in self.subscribers.drain(..) {
+            let _ = tx.send(result.clone());
+        }
+
+        let info_hash = self.info_hash;
+        let closest_nodes = self.lookup.closest_nodes();
+        self.announce.take().into_iter().flat_map(move |args| {
+            closest_nodes
+                .iter()
+                .map(move |(addr_id, token)| AnnouncePeerQuery {
+                    info_hash,
+                    addr_id: *addr_id,
+                    token: token.clone(),
+                    args,
+                })
+        })
+    }
+
+    pub(crate) fn next_query(&self, now: Instant) -> Option<GetPeersQuery> {
+        self.lookup
+            .queries()
+            .next_addr(now)
+            .map(|addr_opt_id| GetPeersQuery {
+                info_hash: self.info_hash,
+                addr_opt_id,
+                scrape: self.scrape.is_some(),
+            })
+    }
+
+    pub(crate) fn on_query_sent(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        tx_id: transaction::Id,
+    ) {
+        self.lookup.queries_mut().on_query_sent(addr_opt_id, tx_id);
+    }
+
+    pub(crate) fn on_resp(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        resp: &RespValues<'_, Vec<&[u8]>>,
+        now: Instant,
+    ) {
+        self.lookup.queries_mut().on_success(addr_opt_id);
+
+        if let Some(values) = resp.values() {
+            self.peers.extend(values);
+        }
+
+        if let Some(node_id) = addr_opt_id.id().or_else(|| resp.id()) {
+            self.lookup.try_replace_closest_nodes(
+                AddrId::new(*addr_opt_id.addr(), node_id),
+                resp.token().to_vec(),
+            );
+        }
+
+        self.lookup
+            .insert_nodes(resp_nodes(resp.nodes(), resp.nodes6()), now);
+    }
+
+    pub(crate) fn on_scrape_resp(&mut self, resp: &ScrapeValues<'_>) {
+        if let (Some((seeds, peers)), Some((resp_seeds, resp_peers))) =
+            (self.scrape.as_mut(), resp.filters())
+        {
+            seeds.union(&resp_seeds);
+            peers.union(&resp_peers);
+        }
+    }
+
+    pub(crate) fn on_failure(&mut self, addr_opt_id: AddrOptId<CompactAddr>, now: Instant) {
+        self.lookup.queries_mut().on_failure(addr_opt_id, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::krpc_ext::GetPeersRespValues;
+    use cloudburst::dht::{
+        krpc::{ser, Msg},
+        node::LocalId,
+    };
+    use serde_bytes::Bytes;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn test_collects_peers_and_tokens() {
+        let now = Instant::now();
+        let info_hash = InfoHash::from([0xAB; 20]);
+        let node_id = node::Id::rand(&mut rand::thread_rng()).unwrap();
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let peer = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 51413));
+        let addr_opt_id = AddrOptId::with_addr(addr);
+
+        let mut op = GetPeersOp::new(info_hash, 8, SupportedAddr::Ipv4, [addr_opt_id], now);
+        let (tx, mut rx) = oneshot::channel();
+        op.subscribe(tx);
+
+        assert_eq!(
+            op.next_query(now),
+            Some(GetPeersQuery {
+                info_hash,
+                addr_opt_id,
+                scrape: false
+            })
+        );
+        op.on_query_sent(addr_opt_id, transaction::Id::from(1));
+        assert_eq!(op.next_query(now), None);
+
+        let resp = bt_bencode::to_vec(&ser::RespMsg {
+            r: RespValues::new(
+                &LocalId::from(node_id),
+                b"token",
+                Some(vec![peer]),
+                None::<&Bytes>,
+                None,
+            ),
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+        let values = msg.values().unwrap().unwrap();
+        op.on_resp(addr_opt_id, &values, now);
+
+        assert!(op.is_done());
+        assert_eq!(op.finish().count(), 0);
+
+        let result = rx.try_recv().unwrap();
+        assert_eq!(result.peers, vec![peer]);
+        assert_eq!(
+            result.closest_nodes,
+            vec![(AddrId::new(addr, node_id), b"token".to_vec())]
+        );
+        assert_eq!(result.scrape, None);
+    }
+
+    #[test]
+    fn test_scrape_merges_filters() {
+        let now = Instant::now();
+        let info_hash = InfoHash::from([0xAB; 20]);
+        let local_id = LocalId::from(node::Id::rand(&mut rand::thread_rng()).unwrap());
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let addr_opt_id = AddrOptId::with_addr(addr);
+
+        let mut op = GetPeersOp::new(info_hash, 8, SupportedAddr::Ipv4, [addr_opt_id], now);
+        op.set_scrape();
+        assert!(op.next_query(now).unwrap().scrape);
+
+        let mut seeds = BloomFilter::default();
+        seeds.insert(Ipv4Addr::new(192, 0, 2, 2).into());
+        let mut peers = BloomFilter::default();
+        peers.insert(Ipv4Addr::new(192, 0, 2, 3).into());
+        peers.insert(Ipv4Addr::new(192, 0, 2, 4).into());
+        let filters = (seeds, peers);
+
+        let resp = bt_bencode::to_vec(&GetPeersRespValues::new(
+            &local_id,
+            b"token",
+            None,
+            None,
+            None,
+            Some(&filters),
+        ))
+        .unwrap();
+        let values: ScrapeValues<'_> = bt_bencode::from_slice(&resp).unwrap();
+        op.on_scrape_resp(&values);
+
+        let scrape = op.result().scrape.unwrap();
+        assert!((scrape.seeders - 1.0).abs() < 0.01);
+        assert!((scrape.leechers - 2.0).abs() < 0.01);
+    }
+}
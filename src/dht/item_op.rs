@@ -0,0 +1,692 @@
+use bt_bencode::Value;
+use cloudburst::dht::{
+    krpc::{find_node::RespValues, transaction, CompactAddr, ErrorCode},
+    node::{self, AddrId, AddrOptId},
+};
+use ed25519_dalek::{
+    Signature, Signer, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH,
+};
+use sha1::{Digest, Sha1};
+use std::{collections::HashMap, time::Instant};
+use tokio::sync::oneshot;
+use tracing::trace;
+
+use super::{
+    krpc_ext::GetRespValues,
+    lookup::{resp_nodes, Lookup},
+    SupportedAddr,
+};
+
+/// The maximum length of an item's encoded value.
+pub const MAX_VALUE_LEN: usize = 1000;
+
+/// The maximum length of a mutable item's salt.
+pub const MAX_SALT_LEN: usize = 64;
+
+/// The reasons an item cannot be stored.
+///
+/// See [BEP 44](http://bittorrent.org/beps/bep_0044.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum PutError {
+    /// The value could not be encoded
+    #[error("invalid value")]
+    InvalidValue,
+    /// The encoded value is longer than [`MAX_VALUE_LEN`]
+    #[error("message (v field) too big")]
+    ValueTooBig,
+    /// The mutable item's signature is not valid for the public key
+    #[error("invalid signature")]
+    InvalidSignature,
+    /// The salt is longer than [`MAX_SALT_LEN`]
+    #[error("salt (salt field) too big")]
+    SaltTooBig,
+    /// The `cas` argument does not match the stored item's sequence number
+    #[error("the CAS hash mismatched, re-read value and try again")]
+    CasMismatch,
+    /// The sequence number is less than the stored item's sequence number
+    #[error("sequence number less than current")]
+    SeqLessThanCurrent,
+}
+
+impl PutError {
+    /// Returns the error code sent in a KRPC error message.
+    #[must_use]
+    pub fn code(self) -> ErrorCode {
+        match self {
+            PutError::InvalidValue => ErrorCode::ProtocolError,
+            PutError::ValueTooBig => ErrorCode::Other(205),
+            PutError::InvalidSignature => ErrorCode::Other(206),
+            PutError::SaltTooBig => ErrorCode::Other(207),
+            PutError::CasMismatch => ErrorCode::Other(301),
+            PutError::SeqLessThanCurrent => ErrorCode::Other(302),
+        }
+    }
+}
+
+/// Returns the encoded value.
+///
+/// # Errors
+///
+/// If the value cannot be encoded or the encoded value is too long, an error
+/// is returned.
+pub fn encode_value(value: &Value) -> Result<Vec<u8>, PutError> {
+    let encoded = bt_bencode::to_vec(value).map_err(|_| PutError::InvalidValue)?;
+    if encoded.len() > MAX_VALUE_LEN {
+        return Err(PutError::ValueTooBig);
+    }
+    Ok(encoded)
+}
+
+/// Returns the target of an immutable item which is the SHA-1 hash of the
+/// encoded value.
+///
+/// # Errors
+///
+/// If the value cannot be encoded or the encoded value is too long, an error
+/// is returned.
+pub fn immutable_target(value: &Value) -> Result<node::Id, PutError> {
+    let encoded = encode_value(value)?;
+    Ok(node::Id::from(<[u8; 20]>::from(Sha1::digest(&encoded))))
+}
+
+/// Returns the target of a mutable item which is the SHA-1 hash of the public
+/// key and the salt.
+#[must_use]
+pub fn mutable_target(k: &[u8; PUBLIC_KEY_LENGTH], salt: &[u8]) -> node::Id {
+    let mut hasher = Sha1::new();
+    hasher.update(k);
+    hasher.update(salt);
+    node::Id::from(<[u8; 20]>::from(hasher.finalize()))
+}
+
+/// An item stored in the DHT.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    /// A value which is found by the hash of the value
+    Immutable(Value),
+    /// A value which is found by the hash of a public key and a salt
+    Mutable(MutableItem),
+}
+
+impl Item {
+    /// Returns the item's value.
+    #[must_use]
+    pub fn value(&self) -> &Value {
+        match self {
+            Item::Immutable(value) => value,
+            Item::Mutable(item) => &item.value,
+        }
+    }
+
+    /// Returns the sequence number if the item is mutable.
+    #[must_use]
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            Item::Immutable(_) => None,
+            Item::Mutable(item) => Some(item.seq),
+        }
+    }
+
+    /// Returns the item's target if the item is valid.
+    ///
+    /// # Errors
+    ///
+    /// If the value is too long or a mutable item's salt or signature is
+    /// invalid, an error is returned.
+    pub fn target(&self) -> Result<node::Id, PutError> {
+        match self {
+            Item::Immutable(value) => immutable_target(value),
+            Item::Mutable(item) => {
+                item.verify()?;
+                Ok(mutable_target(&item.k, &item.salt))
+            }
+        }
+    }
+}
+
+/// A value signed by the owner of a public key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutableItem {
+    /// The ed25519 public key
+    pub k: [u8; PUBLIC_KEY_LENGTH],
+    /// The salt which allows multiple items for the same public key
+    pub salt: Vec<u8>,
+    /// The sequence number which increases every time the item is changed
+    pub seq: i64,
+    /// The ed25519 signature of the salt, sequence number, and value
+    pub sig: [u8; SIGNATURE_LENGTH],
+    /// The item's value
+    pub value: Value,
+}
+
+impl MutableItem {
+    /// Signs a value.
+    ///
+    /// # Errors
+    ///
+    /// If the value is too long or the salt is too long, an error is returned.
+    pub fn sign(
+        signing_key: &SigningKey,
+        salt: Vec<u8>,
+        seq: i64,
+        value: Value,
+    ) -> Result<Self, PutError> {
+        let msg = signable(&salt, seq, &value)?;
+        Ok(Self {
+            k: signing_key.verifying_key().to_bytes(),
+            salt,
+            seq,
+            sig: signing_key.sign(&msg).to_bytes(),
+            value,
+        })
+    }
+
+    /// Verifies the signature.
+    ///
+    /// # Errors
+    ///
+    /// If the value is too long, the salt is too long, or the signature is
+    /// invalid, an error is returned.
+    pub fn verify(&self) -> Result<(), PutError> {
+        let msg = signable(&self.salt, self.seq, &self.value)?;
+        let k = VerifyingKey::from_bytes(&self.k).map_err(|_| PutError::InvalidSignature)?;
+        k.verify_strict(&msg, &Signature::from_bytes(&self.sig))
+            .map_err(|_| PutError::InvalidSignature)
+    }
+}
+
+/// Returns the data which is signed for a mutable item.
+fn signable(salt: &[u8], seq: i64, value: &Value) -> Result<Vec<u8>, PutError> {
+    if salt.len() > MAX_SALT_LEN {
+        return Err(PutError::SaltTooBig);
+    }
+    let encoded = encode_value(value)?;
+
+    let mut msg = Vec::with_capacity(salt.len() + encoded.len() + 32);
+    if !salt.is_empty() {
+        msg.extend_from_slice(b"4:salt");
+        msg.extend_from_slice(salt.len().to_string().as_bytes());
+        msg.push(b':');
+        msg.extend_from_slice(salt);
+    }
+    msg.extend_from_slice(b"3:seqi");
+    msg.extend_from_slice(seq.to_string().as_bytes());
+    msg.extend_from_slice(b"e1:v");
+    msg.extend_from_slice(&encoded);
+    Ok(msg)
+}
+
+/// An item to put after the closest nodes are found.
+#[derive(Debug)]
+enum Put {
+    Immutable(Value),
+    Mutable {
+        signing_key: Box<SigningKey>,
+        salt: Vec<u8>,
+        value: Value,
+    },
+}
+
+/// The result of an item lookup.
+#[derive(Debug, Clone)]
+pub struct GetItemResult {
+    /// The item's target
+    pub target: node::Id,
+    /// The item if a queried node returned a valid item
+    ///
+    /// For a mutable item, the item with the highest sequence number is
+    /// returned. If the item was put, the put item is returned.
+    pub item: Option<Item>,
+    /// The closest nodes found with the token each one returned
+    ///
+    /// The nodes are sorted by distance to the target. The tokens can be used
+    /// to put the item to the nodes.
+    pub closest_nodes: Vec<(AddrId<CompactAddr>, Vec<u8>)>,
+}
+
+/// A get query to send for an item lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetItemQuery {
+    /// The item's target
+    pub target: node::Id,
+    /// The node to query
+    pub addr_opt_id: AddrOptId<CompactAddr>,
+}
+
+/// A put query to send to a node which returned a token.
+#[derive(Debug, Clone)]
+pub struct PutItemQuery {
+    /// The item's target
+    pub target: node::Id,
+    /// The node to store the item on
+    pub addr_id: AddrId<CompactAddr>,
+    /// The token returned by the node in a get response
+    pub token: Vec<u8>,
+    /// The item to store
+    pub item: Item,
+    /// The sequence number of the mutable item which the node returned
+    ///
+    /// The node only stores the item if it still has the same item.
+    pub cas: Option<i64>,
+}
+
+/// Finds an item by iteratively querying nodes closer to the target.
+///
+/// If the item should be put, the item is sent to the closest nodes when the
+/// lookup is done.
+#[derive(Debug)]
+pub struct ItemOp {
+    lookup: Lookup<Vec<u8>>,
+    salt: Vec<u8>,
+    item: Option<Item>,
+    node_seqs: HashMap<CompactAddr, i64>,
+    subscribers: Vec<oneshot::Sender<GetItemResult>>,
+    put: Option<Put>,
+}
+
+impl ItemOp {
+    pub fn new<T>(
+        target_id: node::Id,
+        max_found_nodes: usize,
+        supported_addr: SupportedAddr,
+        addrs: T,
+        now: Instant,
+    ) -> Self
+    where
+        T: IntoIterator<Item = AddrOptId<CompactAddr>>,
+    {
+        Self {
+            lookup: Lookup::new(target_id, max_found_nodes, supported_addr, addrs, now),
+            salt: Vec::new(),
+            item: None,
+            node_seqs: HashMap::new(),
+            subscribers: Vec::new(),
+            put: None,
+        }
+    }
+
+    /// Sets the salt used to verify mutable items.
+    pub fn set_salt(&mut self, salt: Vec<u8>) {
+        self.salt = salt;
+    }
+
+    /// Puts an immutable item to the closest nodes when the op is done.
+    pub fn set_put_immutable(&mut self, value: Value) {
+        self.put = Some(Put::Immutable(value));
+    }
+
+    /// Puts a mutable item to the closest nodes when the op is done.
+    ///
+    /// The item is signed with a sequence number greater than the sequence
+    /// number of any item found during the lookup.
+    pub fn set_put_mutable(&mut self, signing_key: SigningKey, salt: Vec<u8>, value: Value) {
+        self.salt.clone_from(&salt);
+        self.put = Some(Put::Mutable {
+            signing_key: Box::new(signing_key),
+            salt,
+            value,
+        });
+    }
+
+    /// Adds a subscriber which is sent the result when the op is done.
+    pub fn subscribe(&mut self, tx: oneshot::Sender<GetItemResult>) {
+        self.subscribers.push(tx);
+    }
+
+    /// Moves the subscribers and the put from another op to this op.
+    pub fn merge(&mut self, other: &mut ItemOp) {
+        self.subscribers.append(&mut other.subscribers);
+        if let Some(put) = other.put.take() {
+            if let Put::Mutable { salt, .. } = &put {
+                self.salt.clone_from(salt);
+            }
+            self.put = Some(put);
+        }
+    }
+
+    /// Returns the target ID.
+    #[must_use]
+    #[inline]
+    pub fn target_id(&self) -> node::Id {
+        self.lookup.target_id()
+    }
+
+    /// Returns if the space is done.
+    #[must_use]
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.lookup.queries().is_done()
+    }
+
+    /// Returns when the lookup finishes with the closest nodes found so far.
+    #[must_use]
+    #[inline]
+    pub fn deadline(&self) -> Instant {
+        self.lookup.deadline()
+    }
+
+    /// Finishes the lookup with the closest nodes found so far if the
+    /// deadline has passed.
+    pub fn on_timeout(&mut self, now: Instant) {
+        self.lookup.on_timeout(now);
+    }
+
+    /// Returns the current result of the lookup.
+    #[must_use]
+    pub fn result(&self) -> GetItemResult {
+        GetItemResult {
+            target: self.target_id(),
+            item: self.item.clone(),
+            closest_nodes: self.lookup.closest_nodes().to_vec(),
+        }
+    }
+
+    /// Sends the result to all subscribers.
+    ///
+    /// Returns the put queries to send if the op should put the item.
+    ///
+    /// A mutable item is sent to each node with the sequence number of the
+    /// item which that node returned as the `cas`.
+    pub fn finish(&mut self) -> impl Iterator<Item = PutItemQuery> + '_ {
+        let max_seq = self.item.as_ref().and_then(Item::seq);
+        let put = self.put.take().and_then(|put| match put {
+            Put::Immutable(value) => Some(Item::Immutable(value)),
+            Put::Mutable {
+                signing_key,
+                salt,
+                value,
+            } => {
+                let seq = max_seq.map_or(0, |seq| seq.saturating_add(1));
+                match MutableItem::sign(&signing_key, salt, seq, value) {
+                    Ok(item) => Some(Item::Mutable(item)),
+                    Err(e) => {
+                        trace!(%e, "could not sign mutable item");
+                        None
+                    }
+                }
+            }
+        });
+        if let Some(item) = &put {
+            self.item = Some(item.clone());
+        }
+
+        let result = self.result();
+        for tx in self.subscribers.drain(..) {
+            let _ = tx.send(result.clone());
+        }
+
+        let target = self.target_id();
+        let closest_nodes = self.lookup.closest_nodes();
+        let node_seqs = &self.node_seqs;
+        put.into_iter().flat_map(move |item| {
+            closest_nodes
+                .iter()
+                .map(move |(addr_id, token)| PutItemQuery {
+                    target,
+                    addr_id: *addr_id,
+                    token: token.clone(),
+                    cas: item
+                        .seq()
+                        .and_then(|_| node_seqs.get(addr_id.addr()).copied()),
+                    item: item.clone(),
+                })
+        })
+    }
+
+    pub(crate) fn next_query(&self, now: Instant) -> Option<GetItemQuery> {
+        self.lookup
+            .queries()
+            .next_addr(now)
+            .map(|addr_opt_id| GetItemQuery {
+                target: self.target_id(),
+                addr_opt_id,
+            })
+    }
+
+    pub(crate) fn on_query_sent(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        tx_id: transaction::Id,
+    ) {
+        self.lookup.queries_mut().on_query_sent(addr_opt_id, tx_id);
+    }
+
+    pub(crate) fn on_resp(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        resp: &RespValues<'_>,
+        item: &GetRespValues<'_>,
+        now: Instant,
+    ) {
+        self.lookup.queries_mut().on_success(addr_opt_id);
+
+        if let Some(item) = item.item(&self.salt) {
+            if item.target().ok() != Some(self.target_id()) {
+                trace!(target_id = %self.target_id(), "item does not match target");
+            } else {
+                if let Some(seq) = item.seq() {
+                    self.node_seqs.insert(*addr_opt_id.addr(), seq);
+                }
+                if self
+                    .item
+                    .as_ref()
+                    .map_or(true, |current| current.seq() < item.seq())
+                {
+                    self.item = Some(item);
+                }
+            }
+        }
+
+        if let (Some(node_id), Some(token)) = (
+            addr_opt_id
+                .id()
+                .or_else(|| node::Id::try_from(resp.id.as_ref()).ok()),
+            item.token(),
+        ) {
+            self.lookup.try_replace_closest_nodes(
+                AddrId::new(*addr_opt_id.addr(), node_id),
+                token.to_vec(),
+            );
+        }
+
+        if matches!(self.item, Some(Item::Immutable(_))) && self.put.is_none() {
+            // An immutable item cannot change, so the lookup is finished once
+            // the outstanding queries return.
+            self.lookup.queries_mut().stop();
+            return;
+        }
+
+        self.lookup
+            .insert_nodes(resp_nodes(resp.nodes(), resp.nodes6()), now);
+    }
+
+    pub(crate) fn on_failure(&mut self, addr_opt_id: AddrOptId<CompactAddr>, now: Instant) {
+        self.lookup.queries_mut().on_failure(addr_opt_id, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::item_store::ItemStore;
+    use cloudburst::dht::{
+        krpc::{ser, Msg},
+        node::LocalId,
+    };
+    use serde_bytes::Bytes;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn test_finds_immutable_item() {
+        let now = Instant::now();
+        let value = Value::from("Hello World!");
+        let target = immutable_target(&value).unwrap();
+        assert_eq!(
+            target,
+            node::Id::from(hex_literal::hex!(
+                "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+            ))
+        );
+
+        let node_id = node::Id::rand(&mut rand::thread_rng()).unwrap();
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let other_addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 6881));
+        let addr_opt_id = AddrOptId::with_addr(addr);
+
+        let mut op = ItemOp::new(
+            target,
+            8,
+            SupportedAddr::Ipv4,
+            [addr_opt_id, AddrOptId::with_addr(other_addr)],
+            now,
+        );
+        let (tx, mut rx) = oneshot::channel();
+        op.subscribe(tx);
+
+        let query = op.next_query(now).unwrap();
+        op.on_query_sent(query.addr_opt_id, transaction::Id::from(1));
+
+        let item = Item::Immutable(value);
+        let local_id = LocalId::from(node_id);
+        let mut values = GetRespValues::new(&local_id, b"token", None, None);
+        values.set_item(&item);
+        let resp = bt_bencode::to_vec(&ser::RespMsg {
+            r: values,
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+        op.on_resp(
+            query.addr_opt_id,
+            &msg.values().unwrap().unwrap(),
+            &msg.values().unwrap().unwrap(),
+            now,
+        );
+
+        assert!(op.is_done());
+        assert_eq!(op.finish().count(), 0);
+
+        let result = rx.try_recv().unwrap();
+        assert_eq!(result.item, Some(item));
+        assert_eq!(
+            result.closest_nodes,
+            vec![(
+                AddrId::new(*query.addr_opt_id.addr(), node_id),
+                b"token".to_vec()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_put_mutable_item_uses_each_nodes_seq_as_cas() {
+        let now = Instant::now();
+        let signing_key = SigningKey::generate(&mut rand::thread_rng());
+        let salt = b"salt".to_vec();
+        let target = mutable_target(&signing_key.verifying_key().to_bytes(), &salt);
+        let sign = |seq: i64| {
+            Item::Mutable(
+                MutableItem::sign(&signing_key, salt.clone(), seq, Value::from("old")).unwrap(),
+            )
+        };
+
+        let addrs = [1, 2].map(|i| {
+            AddrOptId::with_addr(CompactAddr::from(SocketAddrV4::new(
+                Ipv4Addr::new(192, 0, 2, i),
+                6881,
+            )))
+        });
+        let mut op = ItemOp::new(target, 8, SupportedAddr::Ipv4, addrs, now);
+        op.set_put_mutable(signing_key.clone(), salt.clone(), Value::from("new"));
+
+        let mut item_stores = Vec::new();
+        for (i, seq) in [5, 3].into_iter().enumerate() {
+            let query = op.next_query(now).unwrap();
+            op.on_query_sent(query.addr_opt_id, transaction::Id::from(i as u16));
+
+            let item = sign(seq);
+            let mut item_store = ItemStore::default();
+            item_store.insert(item.clone(), None, now).unwrap();
+            item_stores.push((*query.addr_opt_id.addr(), item_store));
+
+            let local_id = LocalId::from(node::Id::rand(&mut rand::thread_rng()).unwrap());
+            let mut values = GetRespValues::new(&local_id, b"token", None, None);
+            values.set_item(&item);
+            let resp = bt_bencode::to_vec(&ser::RespMsg {
+                r: values,
+                t: Bytes::new(&[0, 1]),
+                v: None,
+            })
+            .unwrap();
+            let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+            op.on_resp(
+                query.addr_opt_id,
+                &msg.values().unwrap().unwrap(),
+                &msg.values().unwrap().unwrap(),
+                now,
+            );
+        }
+        assert!(op.is_done());
+
+        let queries = op.finish().collect::<Vec<_>>();
+        assert_eq!(queries.len(), 2);
+        for query in queries {
+            assert_eq!(query.item.seq(), Some(6));
+            let (_, item_store) = item_stores
+                .iter_mut()
+                .find(|(addr, _)| addr == query.addr_id.addr())
+                .unwrap();
+            assert_eq!(
+                item_store.insert(query.item.clone(), query.cas, now),
+                Ok(target)
+            );
+            assert_eq!(item_store.get(&target, now), Some(&query.item));
+        }
+    }
+
+    #[test]
+    fn test_verifies_bep_44_mutable_items() {
+        let k =
+            hex_literal::hex!("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        let mut item = MutableItem {
+            k,
+            salt: Vec::new(),
+            seq: 1,
+            sig: hex_literal::hex!(
+                "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff"
+                "1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01"
+            ),
+            value: Value::from("Hello World!"),
+        };
+        assert_eq!(
+            Item::Mutable(item.clone()).target(),
+            Ok(node::Id::from(hex_literal::hex!(
+                "4a533d47ec9c7d95b1ad75f576cffc641853b750"
+            )))
+        );
+
+        item.salt = b"foobar".to_vec();
+        item.sig = hex_literal::hex!(
+            "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d"
+            "df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"
+        );
+        assert_eq!(
+            Item::Mutable(item.clone()).target(),
+            Ok(node::Id::from(hex_literal::hex!(
+                "411eba73b6f087ca51a3795d9c8c938d365e32c1"
+            )))
+        );
+
+        item.seq = 2;
+        assert_eq!(item.verify(), Err(PutError::InvalidSignature));
+
+        item.salt = vec![0; MAX_SALT_LEN + 1];
+        assert_eq!(item.verify(), Err(PutError::SaltTooBig));
+    }
+
+    #[test]
+    fn test_value_too_big() {
+        let value = Value::from("a".repeat(MAX_VALUE_LEN));
+        assert_eq!(immutable_target(&value), Err(PutError::ValueTooBig));
+    }
+}
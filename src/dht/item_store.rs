@@ -0,0 +1,271 @@
+use bt_bencode::Value;
+use cloudburst::dht::node;
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    time::{Duration, Instant},
+};
+use tracing::trace;
+
+use super::{
+    item_op::{Item, MutableItem, PutError},
+    snapshot,
+};
+
+/// The amount of time an item is kept without being put again.
+const ITEM_EXPIRATION: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// The maximum number of items stored.
+const MAX_ITEMS: usize = 4096;
+
+#[derive(Debug)]
+struct StoredItem {
+    item: Item,
+    expiration: Instant,
+}
+
+/// The bencoded form of a stored item.
+///
+/// The mutable item fields are only present for mutable items.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncodedItem {
+    v: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    k: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    salt: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sig: Option<ByteBuf>,
+    exp: u64,
+}
+
+/// The bencoded form of the item store.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncodedItemStore {
+    items: Vec<EncodedItem>,
+}
+
+/// Stores items which have been put by other nodes.
+///
+/// See [BEP 44](http://bittorrent.org/beps/bep_0044.html).
+#[derive(Debug, Default)]
+pub struct ItemStore {
+    items: HashMap<node::Id, StoredItem>,
+}
+
+impl ItemStore {
+    /// Inserts or refreshes an item.
+    ///
+    /// A mutable item replaces a stored item only if the sequence number is
+    /// greater than the stored item's sequence number. If `cas` is given, it
+    /// must be the stored item's sequence number.
+    ///
+    /// Returns the item's target. If the store is full, the item is dropped.
+    ///
+    /// # Errors
+    ///
+    /// If the item is invalid or cannot replace the stored item, an error is
+    /// returned.
+    pub fn insert(
+        &mut self,
+        item: Item,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> Result<node::Id, PutError> {
+        let target = item.target()?;
+
+        if let Some(stored) = self.items.get(&target).filter(|s| now < s.expiration) {
+            if let (Some(stored_seq), Some(seq)) = (stored.item.seq(), item.seq()) {
+                if cas.is_some_and(|cas| cas != stored_seq) {
+                    return Err(PutError::CasMismatch);
+                }
+                if seq < stored_seq || (seq == stored_seq && stored.item != item) {
+                    return Err(PutError::SeqLessThanCurrent);
+                }
+            }
+        } else if self.items.len() >= MAX_ITEMS {
+            self.cleanup(now);
+            if self.items.len() >= MAX_ITEMS {
+                trace!(%target, "item store is full");
+                return Ok(target);
+            }
+        }
+
+        self.items.insert(
+            target,
+            StoredItem {
+                item,
+                expiration: now + ITEM_EXPIRATION,
+            },
+        );
+        Ok(target)
+    }
+
+    /// Returns the unexpired item for a target.
+    #[must_use]
+    pub fn get(&self, target: &node::Id, now: Instant) -> Option<&Item> {
+        self.items
+            .get(target)
+            .filter(|stored| now < stored.expiration)
+            .map(|stored| &stored.item)
+    }
+
+    /// Removes expired items.
+    pub fn cleanup(&mut self, now: Instant) {
+        self.items.retain(|_, stored| now < stored.expiration);
+    }
+
+    /// Encodes the unexpired items.
+    pub fn to_bytes(&self, now: Instant, unix_now: u64) -> Vec<u8> {
+        let items = self
+            .items
+            .values()
+            .filter(|stored| now < stored.expiration)
+            .map(|stored| {
+                let exp = snapshot::to_unix_expiration(stored.expiration, now, unix_now);
+                match &stored.item {
+                    Item::Immutable(value) => EncodedItem {
+                        v: value.clone(),
+                        k: None,
+                        salt: None,
+                        seq: None,
+                        sig: None,
+                        exp,
+                    },
+                    Item::Mutable(item) => EncodedItem {
+                        v: item.value.clone(),
+                        k: Some(ByteBuf::from(item.k.to_vec())),
+                        salt: Some(ByteBuf::from(item.salt.clone())),
+                        seq: Some(item.seq),
+                        sig: Some(ByteBuf::from(item.sig.to_vec())),
+                        exp,
+                    },
+                }
+            })
+            .collect();
+
+        bt_bencode::to_vec(&EncodedItemStore { items }).expect("items should be encodable")
+    }
+
+    /// Decodes items which were encoded by [`Self::to_bytes()`].
+    ///
+    /// Items which have expired since they were encoded or which are no
+    /// longer valid are dropped.
+    ///
+    /// # Errors
+    ///
+    /// If the data is not a valid encoding, an error is returned.
+    pub fn from_bytes(bytes: &[u8], now: Instant, unix_now: u64) -> io::Result<Self> {
+        let invalid_data = || snapshot::invalid_data("item store");
+
+        let encoded: EncodedItemStore =
+            bt_bencode::from_slice(bytes).map_err(|_| invalid_data())?;
+        let mut item_store = Self::default();
+        for encoded in encoded.items {
+            let item = match (encoded.k, encoded.seq, encoded.sig) {
+                (Some(k), Some(seq), Some(sig)) => Item::Mutable(MutableItem {
+                    k: <[u8; PUBLIC_KEY_LENGTH]>::try_from(k.as_slice())
+                        .map_err(|_| invalid_data())?,
+                    salt: encoded.salt.map(ByteBuf::into_vec).unwrap_or_default(),
+                    seq,
+                    sig: <[u8; SIGNATURE_LENGTH]>::try_from(sig.as_slice())
+                        .map_err(|_| invalid_data())?,
+                    value: encoded.v,
+                }),
+                (None, None, None) => Item::Immutable(encoded.v),
+                _ => return Err(invalid_data()),
+            };
+            let Some(expiration) = snapshot::from_unix_expiration(encoded.exp, now, unix_now)
+            else {
+                continue;
+            };
+            if item_store.items.len() >= MAX_ITEMS {
+                break;
+            }
+
+            match item.target() {
+                Ok(target) => {
+                    item_store
+                        .items
+                        .insert(target, StoredItem { item, expiration });
+                }
+                Err(e) => {
+                    trace!(%e, "dropping invalid stored item");
+                }
+            }
+        }
+        Ok(item_store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::item_op::MutableItem;
+    use bt_bencode::Value;
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn test_items_expire() {
+        let now = Instant::now();
+        let item = Item::Immutable(Value::from("Hello World!"));
+
+        let mut item_store = ItemStore::default();
+        let target = item_store.insert(item.clone(), None, now).unwrap();
+        assert_eq!(item_store.get(&target, now), Some(&item));
+
+        let later = now + ITEM_EXPIRATION;
+        assert_eq!(item_store.get(&target, later), None);
+
+        item_store.cleanup(later);
+        assert!(item_store.items.is_empty());
+    }
+
+    #[test]
+    fn test_mutable_item_seq_and_cas() {
+        let now = Instant::now();
+        let signing_key = SigningKey::generate(&mut rand::thread_rng());
+        let sign = |seq: i64, value: &str| {
+            Item::Mutable(
+                MutableItem::sign(&signing_key, b"salt".to_vec(), seq, Value::from(value)).unwrap(),
+            )
+        };
+
+        let mut item_store = ItemStore::default();
+        let target = item_store.insert(sign(1, "a"), None, now).unwrap();
+        assert_eq!(item_store.insert(sign(1, "a"), None, now), Ok(target));
+        assert_eq!(
+            item_store.insert(sign(1, "b"), None, now),
+            Err(PutError::SeqLessThanCurrent)
+        );
+        assert_eq!(
+            item_store.insert(sign(0, "b"), None, now),
+            Err(PutError::SeqLessThanCurrent)
+        );
+        assert_eq!(
+            item_store.insert(sign(2, "b"), Some(0), now),
+            Err(PutError::CasMismatch)
+        );
+        assert_eq!(item_store.insert(sign(2, "b"), Some(1), now), Ok(target));
+        assert_eq!(item_store.get(&target, now), Some(&sign(2, "b")));
+
+        let unix_now = 1_700_000_000;
+        let loaded =
+            ItemStore::from_bytes(&item_store.to_bytes(now, unix_now), now, unix_now).unwrap();
+        assert_eq!(loaded.get(&target, now), Some(&sign(2, "b")));
+
+        let Item::Mutable(mut forged) = sign(3, "c") else {
+            unreachable!()
+        };
+        forged.value = Value::from("d");
+        assert_eq!(
+            item_store.insert(Item::Mutable(forged), None, now),
+            Err(PutError::InvalidSignature)
+        );
+    }
+}
@@ -0,0 +1,687 @@
+//! KRPC message arguments and values which are not defined in `cloudburst`.
+
+use bt_bencode::Value;
+use cloudburst::{
+    dht::{
+        krpc::{CompactAddr, CompactAddrV4, CompactAddrV6, Msg},
+        node::{self, LocalId},
+    },
+    metainfo::InfoHash,
+};
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use serde::{ser::SerializeMap, Serializer};
+use serde_bytes::Bytes;
+use serde_derive::{Deserialize, Serialize};
+
+use super::{
+    bloom::BloomFilter,
+    item_op::{Item, MutableItem, PutError, MAX_SALT_LEN},
+};
+
+/// A query message which can be marked as sent from a read-only node.
+///
+/// See [BEP 43](http://bittorrent.org/beps/bep_0043.html).
+#[derive(Debug)]
+pub struct QueryMsg<'a, T> {
+    /// Query arguments
+    pub a: T,
+    /// Method name of query
+    pub q: &'a [u8],
+    /// If the querying node is read-only
+    pub ro: bool,
+    /// Transaction id
+    pub t: &'a [u8],
+    /// Client version
+    pub v: Option<&'a [u8]>,
+}
+
+impl<'a, T> serde::Serialize for QueryMsg<'a, T>
+where
+    T: serde::Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("a", &self.a)?;
+        map.serialize_entry("q", Bytes::new(self.q))?;
+        if self.ro {
+            map.serialize_entry("ro", &1)?;
+        }
+        map.serialize_entry("t", Bytes::new(self.t))?;
+        if let Some(v) = self.v {
+            map.serialize_entry("v", Bytes::new(v))?;
+        }
+        map.serialize_entry("y", "q")?;
+        map.end()
+    }
+}
+
+/// A response message with the BEP 42 `ip` value.
+///
+/// The `ip` value is the address which the querying node's message was
+/// received from.
+#[derive(Debug)]
+pub struct RespMsg<'a, T> {
+    /// The querying node's address
+    pub ip: CompactAddr,
+    /// Return values
+    pub r: T,
+    /// Transaction id
+    pub t: &'a [u8],
+    /// Client version
+    pub v: Option<&'a [u8]>,
+}
+
+impl<'a, T> serde::Serialize for RespMsg<'a, T>
+where
+    T: serde::Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("ip", &self.ip)?;
+        map.serialize_entry("r", &self.r)?;
+        map.serialize_entry("t", Bytes::new(self.t))?;
+        if let Some(v) = self.v {
+            map.serialize_entry("v", Bytes::new(v))?;
+        }
+        map.serialize_entry("y", "r")?;
+        map.end()
+    }
+}
+
+/// The top level values of a message which are not in `Msg`.
+///
+/// The values are decoded together so an encoded message is only parsed once
+/// more after the `Msg` is parsed.
+#[derive(Debug, Default, Deserialize)]
+pub struct MsgExt<'a> {
+    /// The local node's address as seen by the remote node
+    #[serde(borrow, default)]
+    ip: Option<&'a Bytes>,
+    /// If the querying node is read-only
+    #[serde(default)]
+    ro: Option<i64>,
+}
+
+impl<'a> MsgExt<'a> {
+    /// Decodes the values from an encoded message.
+    ///
+    /// If the message cannot be decoded, no values are returned.
+    #[must_use]
+    pub fn from_slice(buf: &'a [u8]) -> Self {
+        bt_bencode::from_slice(buf).unwrap_or_default()
+    }
+
+    /// Returns true if the message is from a read-only node.
+    ///
+    /// See [BEP 43](http://bittorrent.org/beps/bep_0043.html).
+    #[must_use]
+    pub fn is_read_only(&self) -> bool {
+        self.ro.is_some_and(|ro| ro != 0)
+    }
+
+    /// Returns the local node's address as seen by the remote node from the
+    /// `ip` value.
+    ///
+    /// See [BEP 42](http://bittorrent.org/beps/bep_0042.html).
+    #[must_use]
+    pub fn external_addr(&self) -> Option<CompactAddr> {
+        let ip = self.ip?;
+        if let Ok(ip) = <[u8; 6]>::try_from(ip.as_ref()) {
+            return Some(CompactAddr::from(CompactAddrV4::from(ip)));
+        }
+        if let Ok(ip) = <[u8; 18]>::try_from(ip.as_ref()) {
+            return Some(CompactAddr::from(CompactAddrV6::from(ip)));
+        }
+        None
+    }
+}
+
+/// The `want` value which requests IPv4 nodes.
+const WANT_N4: &[u8] = b"n4";
+
+/// The `want` value which requests IPv6 nodes.
+const WANT_N6: &[u8] = b"n6";
+
+#[derive(Debug, Deserialize)]
+struct WantArgs<'a> {
+    #[serde(borrow, default)]
+    want: Option<Vec<&'a Bytes>>,
+}
+
+/// The address families of nodes requested in a `find_node` or `get_peers` query.
+///
+/// See [BEP 32](http://bittorrent.org/beps/bep_0032.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Want {
+    /// If IPv4 nodes should be returned in `nodes`
+    pub n4: bool,
+    /// If IPv6 nodes should be returned in `nodes6`
+    pub n6: bool,
+}
+
+impl Want {
+    /// Returns the address families requested by a query from an address.
+    ///
+    /// If the query does not have a `want` argument, only nodes in the same
+    /// address family as the querying node are returned.
+    #[must_use]
+    pub fn from_query(msg: &Msg<'_>, addr: &CompactAddr) -> Self {
+        match msg
+            .args::<WantArgs<'_>>()
+            .and_then(Result::ok)
+            .and_then(|args| args.want)
+        {
+            Some(want) => Self {
+                n4: want.iter().any(|w| w.as_ref() == WANT_N4),
+                n6: want.iter().any(|w| w.as_ref() == WANT_N6),
+            },
+            None => Self {
+                n4: matches!(addr, CompactAddr::V4(_)),
+                n6: matches!(addr, CompactAddr::V6(_)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FlagArgs {
+    #[serde(default)]
+    scrape: Option<i64>,
+    #[serde(default)]
+    seed: Option<i64>,
+}
+
+fn flag_args(msg: &Msg<'_>) -> Option<FlagArgs> {
+    msg.args::<FlagArgs>().and_then(Result::ok)
+}
+
+/// Returns true if a `get_peers` query requests the BEP 33 bloom filters.
+#[must_use]
+pub fn is_scrape(msg: &Msg<'_>) -> bool {
+    flag_args(msg)
+        .and_then(|args| args.scrape)
+        .is_some_and(|scrape| scrape != 0)
+}
+
+/// Returns true if an `announce_peer` query is from a seed.
+#[must_use]
+pub fn is_seed(msg: &Msg<'_>) -> bool {
+    flag_args(msg)
+        .and_then(|args| args.seed)
+        .is_some_and(|seed| seed != 0)
+}
+
+/// The arguments for a `get_peers` query with the BEP 33 `scrape` flag.
+#[derive(Debug, Serialize)]
+pub struct GetPeersQueryArgs<'a> {
+    id: &'a Bytes,
+    info_hash: &'a Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scrape: Option<u8>,
+}
+
+impl<'a> GetPeersQueryArgs<'a> {
+    /// Instantiates a new query message.
+    #[must_use]
+    pub fn new(id: &'a LocalId, info_hash: &'a InfoHash, scrape: bool) -> Self {
+        Self {
+            id: Bytes::new(&(id.0).0),
+            info_hash: Bytes::new(&info_hash.0),
+            scrape: scrape.then_some(1),
+        }
+    }
+}
+
+/// The value for a `get_peers` response with the BEP 33 bloom filters.
+#[derive(Debug, Serialize)]
+pub struct GetPeersRespValues<'a> {
+    id: &'a Bytes,
+    token: &'a Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<CompactAddr>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes: Option<&'a Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes6: Option<&'a Bytes>,
+    #[serde(rename = "BFsd", skip_serializing_if = "Option::is_none")]
+    seeds: Option<&'a Bytes>,
+    #[serde(rename = "BFpe", skip_serializing_if = "Option::is_none")]
+    peers: Option<&'a Bytes>,
+}
+
+impl<'a> GetPeersRespValues<'a> {
+    /// Instantiates a new instance.
+    #[must_use]
+    pub fn new(
+        id: &'a LocalId,
+        token: &'a [u8],
+        values: Option<Vec<CompactAddr>>,
+        nodes: Option<&'a Bytes>,
+        nodes6: Option<&'a Bytes>,
+        scrape: Option<&'a (BloomFilter, BloomFilter)>,
+    ) -> Self {
+        Self {
+            id: Bytes::new(&(id.0).0),
+            token: Bytes::new(token),
+            values,
+            nodes,
+            nodes6,
+            seeds: scrape.map(|(seeds, _)| Bytes::new(seeds.as_ref())),
+            peers: scrape.map(|(_, peers)| Bytes::new(peers.as_ref())),
+        }
+    }
+}
+
+/// The BEP 33 bloom filters in a `get_peers` response.
+#[derive(Debug, Deserialize)]
+pub struct ScrapeValues<'a> {
+    #[serde(rename = "BFsd", borrow, default)]
+    seeds: Option<&'a Bytes>,
+    #[serde(rename = "BFpe", borrow, default)]
+    peers: Option<&'a Bytes>,
+}
+
+impl<'a> ScrapeValues<'a> {
+    /// Returns the bloom filters for seeds and for peers which are not seeds.
+    #[must_use]
+    pub fn filters(&self) -> Option<(BloomFilter, BloomFilter)> {
+        let seeds = BloomFilter::try_from(self.seeds?.as_ref()).ok()?;
+        let peers = BloomFilter::try_from(self.peers?.as_ref()).ok()?;
+        Some((seeds, peers))
+    }
+}
+
+/// The method name for a BEP 44 `get` query.
+pub const METHOD_GET: &[u8] = b"get";
+
+/// The method name for a BEP 44 `put` query.
+pub const METHOD_PUT: &[u8] = b"put";
+
+/// The arguments for a `get` query.
+///
+/// See [BEP 44](http://bittorrent.org/beps/bep_0044.html).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetQueryArgs<'a> {
+    #[serde(borrow)]
+    id: &'a Bytes,
+    #[serde(borrow)]
+    target: &'a Bytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+}
+
+impl<'a> GetQueryArgs<'a> {
+    /// Instantiates a new query message.
+    #[must_use]
+    pub fn new(id: &'a LocalId, target: &'a node::Id) -> Self {
+        Self {
+            id: Bytes::new(&(id.0).0),
+            target: Bytes::new(&target.0),
+            seq: None,
+        }
+    }
+
+    /// Returns the target of the item.
+    #[must_use]
+    pub fn target(&self) -> Option<node::Id> {
+        node::Id::try_from(self.target.as_ref()).ok()
+    }
+
+    /// Returns the sequence number of a mutable item which the querying node
+    /// already has.
+    #[must_use]
+    pub fn seq(&self) -> Option<i64> {
+        self.seq
+    }
+}
+
+/// The value for a `get` response.
+///
+/// The closest nodes are returned in the same `nodes` and `nodes6` values as
+/// a `find_node` response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetRespValues<'a> {
+    #[serde(borrow)]
+    id: &'a Bytes,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    k: Option<&'a Bytes>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<&'a Bytes>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    nodes6: Option<&'a Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    sig: Option<&'a Bytes>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    token: Option<&'a Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    v: Option<Value>,
+}
+
+impl<'a> GetRespValues<'a> {
+    /// Instantiates a new instance.
+    #[must_use]
+    pub fn new(
+        id: &'a LocalId,
+        token: &'a [u8],
+        nodes: Option<&'a Bytes>,
+        nodes6: Option<&'a Bytes>,
+    ) -> Self {
+        Self {
+            id: Bytes::new(&(id.0).0),
+            k: None,
+            nodes,
+            nodes6,
+            seq: None,
+            sig: None,
+            token: Some(Bytes::new(token)),
+            v: None,
+        }
+    }
+
+    /// Sets the stored item.
+    pub fn set_item(&mut self, item: &'a Item) {
+        match item {
+            Item::Immutable(value) => {
+                self.v = Some(value.clone());
+            }
+            Item::Mutable(item) => {
+                self.k = Some(Bytes::new(&item.k));
+                self.seq = Some(item.seq);
+                self.sig = Some(Bytes::new(&item.sig));
+                self.v = Some(item.value.clone());
+            }
+        }
+    }
+
+    /// Sets only the sequence number of a stored mutable item.
+    ///
+    /// Used when the querying node already has the current item.
+    pub fn set_seq(&mut self, seq: i64) {
+        self.seq = Some(seq);
+    }
+
+    /// Returns the token which is used to put an item to the queried node.
+    #[must_use]
+    pub fn token(&self) -> Option<&[u8]> {
+        self.token.map(AsRef::as_ref)
+    }
+
+    /// Returns the stored item.
+    ///
+    /// The salt is not in the response, so the salt used to find the item must
+    /// be given. The item is not verified.
+    #[must_use]
+    pub fn item(&self, salt: &[u8]) -> Option<Item> {
+        let v = self.v.clone()?;
+        let Some(k) = self.k else {
+            return Some(Item::Immutable(v));
+        };
+        Some(Item::Mutable(MutableItem {
+            k: <[u8; PUBLIC_KEY_LENGTH]>::try_from(k.as_ref()).ok()?,
+            salt: salt.to_vec(),
+            seq: self.seq?,
+            sig: <[u8; SIGNATURE_LENGTH]>::try_from(self.sig?.as_ref()).ok()?,
+            value: v,
+        }))
+    }
+}
+
+/// The arguments for a `put` query.
+///
+/// See [BEP 44](http://bittorrent.org/beps/bep_0044.html).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PutQueryArgs<'a> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cas: Option<i64>,
+    #[serde(borrow)]
+    id: &'a Bytes,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    k: Option<&'a Bytes>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    salt: Option<&'a Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    sig: Option<&'a Bytes>,
+    #[serde(borrow)]
+    token: &'a Bytes,
+    v: Value,
+}
+
+impl<'a> PutQueryArgs<'a> {
+    /// Instantiates a new query message.
+    #[must_use]
+    pub fn new(id: &'a LocalId, token: &'a [u8], item: &'a Item, cas: Option<i64>) -> Self {
+        match item {
+            Item::Immutable(value) => Self {
+                cas: None,
+                id: Bytes::new(&(id.0).0),
+                k: None,
+                salt: None,
+                seq: None,
+                sig: None,
+                token: Bytes::new(token),
+                v: value.clone(),
+            },
+            Item::Mutable(item) => Self {
+                cas,
+                id: Bytes::new(&(id.0).0),
+                k: Some(Bytes::new(&item.k)),
+                salt: (!item.salt.is_empty()).then(|| Bytes::new(&item.salt)),
+                seq: Some(item.seq),
+                sig: Some(Bytes::new(&item.sig)),
+                token: Bytes::new(token),
+                v: item.value.clone(),
+            },
+        }
+    }
+
+    /// Returns the token from a previous `get` response.
+    #[must_use]
+    pub fn token(&self) -> &[u8] {
+        self.token
+    }
+
+    /// Returns the expected sequence number of the currently stored mutable item.
+    #[must_use]
+    pub fn cas(&self) -> Option<i64> {
+        self.cas
+    }
+
+    /// Returns the item to store.
+    ///
+    /// The item is not verified.
+    ///
+    /// # Errors
+    ///
+    /// If the mutable item arguments are malformed, an error is returned.
+    pub fn into_item(self) -> Result<Item, PutError> {
+        let Some(k) = self.k else {
+            return Ok(Item::Immutable(self.v));
+        };
+        let salt = self.salt.map(|salt| salt.to_vec()).unwrap_or_default();
+        if salt.len() > MAX_SALT_LEN {
+            return Err(PutError::SaltTooBig);
+        }
+        Ok(Item::Mutable(MutableItem {
+            k: <[u8; PUBLIC_KEY_LENGTH]>::try_from(k.as_ref())
+                .map_err(|_| PutError::InvalidSignature)?,
+            salt,
+            seq: self.seq.ok_or(PutError::InvalidSignature)?,
+            sig: self
+                .sig
+                .and_then(|sig| <[u8; SIGNATURE_LENGTH]>::try_from(sig.as_ref()).ok())
+                .ok_or(PutError::InvalidSignature)?,
+            value: self.v,
+        }))
+    }
+}
+
+/// The method name for a BEP 51 `sample_infohashes` query.
+///
+/// The query has the same arguments as a `find_node` query.
+pub const METHOD_SAMPLE_INFOHASHES: &[u8] = b"sample_infohashes";
+
+/// The value for a `sample_infohashes` response.
+///
+/// The closest nodes to the target are returned in the same `nodes` and
+/// `nodes6` values as a `find_node` response.
+///
+/// See [BEP 51](http://bittorrent.org/beps/bep_0051.html).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SampleInfoHashesRespValues<'a> {
+    #[serde(borrow)]
+    id: &'a Bytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interval: Option<i64>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<&'a Bytes>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    nodes6: Option<&'a Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    num: Option<i64>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    samples: Option<&'a Bytes>,
+}
+
+impl<'a> SampleInfoHashesRespValues<'a> {
+    /// Instantiates a new instance.
+    ///
+    /// The samples are concatenated `InfoHash`es.
+    #[must_use]
+    pub fn new(
+        id: &'a LocalId,
+        interval: i64,
+        nodes: Option<&'a Bytes>,
+        nodes6: Option<&'a Bytes>,
+        num: i64,
+        samples: &'a [u8],
+    ) -> Self {
+        Self {
+            id: Bytes::new(&(id.0).0),
+            interval: Some(interval),
+            nodes,
+            nodes6,
+            num: Some(num),
+            samples: Some(Bytes::new(samples)),
+        }
+    }
+
+    /// Returns the number of `InfoHash`es stored by the queried node.
+    #[must_use]
+    pub fn num(&self) -> Option<i64> {
+        self.num
+    }
+
+    /// Returns the sampled `InfoHash`es.
+    pub fn samples(&self) -> impl Iterator<Item = InfoHash> + '_ {
+        self.samples
+            .into_iter()
+            .flat_map(|samples| samples.chunks_exact(20))
+            .filter_map(|sample| <[u8; 20]>::try_from(sample).ok().map(InfoHash::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudburst::dht::{
+        krpc::{find_node, ping, ser},
+        node::{Id, LocalId},
+    };
+    use serde_derive::Serialize;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    #[derive(Serialize)]
+    struct QueryArgs<'a> {
+        id: &'a Bytes,
+        target: &'a Bytes,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        want: Option<Vec<&'a Bytes>>,
+    }
+
+    #[test]
+    fn test_want_from_query() {
+        let id = Id::rand(&mut rand::thread_rng()).unwrap();
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let addr6 = CompactAddr::from(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 6881, 0, 0));
+
+        let query = bt_bencode::to_vec(&ser::QueryMsg {
+            a: &find_node::QueryArgs::new(&LocalId::from(id), &id),
+            q: Bytes::new(find_node::METHOD_FIND_NODE),
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&query).unwrap();
+        assert_eq!(
+            Want::from_query(&msg, &addr),
+            Want {
+                n4: true,
+                n6: false
+            }
+        );
+        assert_eq!(
+            Want::from_query(&msg, &addr6),
+            Want {
+                n4: false,
+                n6: true
+            }
+        );
+
+        let query = bt_bencode::to_vec(&ser::QueryMsg {
+            a: &QueryArgs {
+                id: Bytes::new(&id.0),
+                target: Bytes::new(&id.0),
+                want: Some(vec![Bytes::new(WANT_N4), Bytes::new(WANT_N6)]),
+            },
+            q: Bytes::new(find_node::METHOD_FIND_NODE),
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&query).unwrap();
+        assert_eq!(Want::from_query(&msg, &addr), Want { n4: true, n6: true });
+    }
+
+    #[test]
+    fn test_msg_ext() {
+        let id = LocalId::from(Id::rand(&mut rand::thread_rng()).unwrap());
+        let ip = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+
+        let query = bt_bencode::to_vec(&QueryMsg {
+            a: &ping::QueryArgs::new(&id),
+            q: ping::METHOD_PING,
+            ro: true,
+            t: &[0, 1],
+            v: None,
+        })
+        .unwrap();
+        let msg_ext = MsgExt::from_slice(&query);
+        assert!(msg_ext.is_read_only());
+        assert_eq!(msg_ext.external_addr(), None);
+
+        let resp = bt_bencode::to_vec(&RespMsg {
+            ip,
+            r: &ping::RespValues::new(&id),
+            t: &[0, 1],
+            v: None,
+        })
+        .unwrap();
+        let msg_ext = MsgExt::from_slice(&resp);
+        assert!(!msg_ext.is_read_only());
+        assert_eq!(msg_ext.external_addr(), Some(ip));
+
+        let msg_ext = MsgExt::from_slice(b"not bencode");
+        assert!(!msg_ext.is_read_only());
+        assert_eq!(msg_ext.external_addr(), None);
+    }
+}
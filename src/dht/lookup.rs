@@ -0,0 +1,431 @@
+//! The query state shared by the iterative lookups.
+//!
+//! The `find_node`, `get_peers`, item, and `sample_infohashes` ops all keep
+//! track of which nodes have been queried and retry failed queries the same
+//! way. [`Queries`] holds that state and [`Lookup`] adds the closest nodes
+//! found so far for the ops which converge on a target.
+
+use cloudburst::dht::{
+    krpc::{transaction, CompactAddr, CompactAddrV4, CompactAddrV6},
+    node::{self, AddrId, AddrOptId},
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tracing::{error, trace};
+
+use super::SupportedAddr;
+
+/// The number of times a node is queried before the node is skipped.
+const MAX_ATTEMPTS: u8 = 3;
+
+/// The time to wait before querying a node again after a failed query.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// The maximum time a lookup runs before it finishes with the closest nodes
+/// found so far.
+///
+/// Without a deadline, a lookup waits until every unresponsive node has used
+/// all of its attempts.
+pub(crate) const MAX_DURATION: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum State {
+    NotQueried(u8, Instant),
+    Querying(u8, transaction::Id),
+    SuccessfulQuery,
+    DoNotQuery,
+}
+
+/// The nodes to query for an op and the state of each node's query.
+#[derive(Debug, Default)]
+pub(crate) struct Queries {
+    addrs: HashMap<AddrOptId<CompactAddr>, State>,
+}
+
+impl Queries {
+    /// Adds a node to query if the node is not already known.
+    pub(crate) fn insert(&mut self, addr_opt_id: AddrOptId<CompactAddr>, now: Instant) {
+        self.addrs
+            .entry(addr_opt_id)
+            .or_insert(State::NotQueried(0, now));
+    }
+
+    /// Returns the number of known nodes.
+    #[must_use]
+    pub(crate) fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Returns if a node with the address is known.
+    #[must_use]
+    pub(crate) fn contains_addr(&self, addr: &CompactAddr) -> bool {
+        self.addrs.keys().any(|a| a.addr() == addr)
+    }
+
+    /// Returns the number of queries waiting for a response.
+    #[must_use]
+    pub(crate) fn in_flight(&self) -> usize {
+        self.addrs
+            .values()
+            .filter(|s| matches!(s, State::Querying(_, _)))
+            .count()
+    }
+
+    /// Returns the state of every known node's query.
+    pub(crate) fn states(&self) -> impl Iterator<Item = &State> {
+        self.addrs.values()
+    }
+
+    /// Returns if every node has been queried or skipped.
+    #[must_use]
+    pub(crate) fn is_done(&self) -> bool {
+        self.addrs.values().all(|s| match *s {
+            State::SuccessfulQuery | State::DoNotQuery => true,
+            State::NotQueried(_, _) | State::Querying(_, _) => false,
+        })
+    }
+
+    /// Returns a node which is ready to be queried.
+    #[must_use]
+    pub(crate) fn next_addr(&self, now: Instant) -> Option<AddrOptId<CompactAddr>> {
+        self.addrs
+            .iter()
+            .find_map(|(addr_opt_id, state)| match state {
+                State::NotQueried(_, deadline) if *deadline <= now => Some(*addr_opt_id),
+                State::NotQueried(_, _)
+                | State::Querying(_, _)
+                | State::SuccessfulQuery
+                | State::DoNotQuery => None,
+            })
+    }
+
+    /// Marks a node as being queried.
+    ///
+    /// Returns false if the node was not waiting to be queried.
+    pub(crate) fn on_query_sent(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        tx_id: transaction::Id,
+    ) -> bool {
+        if let Some(state) = self.addrs.get_mut(&addr_opt_id) {
+            match state {
+                State::NotQueried(attempts, _) => {
+                    *state = State::Querying(*attempts + 1, tx_id);
+                    true
+                }
+                State::Querying(_, _) | State::SuccessfulQuery | State::DoNotQuery => {
+                    debug_assert!(false, "unexpected state {state:?}");
+                    error!(
+                        ?addr_opt_id,
+                        ?tx_id,
+                        ?state,
+                        "sent query to node in unexpected state"
+                    );
+                    false
+                }
+            }
+        } else {
+            debug_assert!(false, "started tx for op which does not know about address");
+            error!(
+                ?addr_opt_id,
+                ?tx_id,
+                "started tx for op which does not know about address"
+            );
+            false
+        }
+    }
+
+    /// Marks a node as having responded.
+    pub(crate) fn on_success(&mut self, addr_opt_id: AddrOptId<CompactAddr>) {
+        if let Some(state) = self.addrs.get_mut(&addr_opt_id) {
+            *state = State::SuccessfulQuery;
+        }
+    }
+
+    /// Schedules a node to be queried again or skips the node if the node has
+    /// failed too many times.
+    pub(crate) fn on_failure(&mut self, addr_opt_id: AddrOptId<CompactAddr>, now: Instant) {
+        if let Some(state) = self.addrs.get_mut(&addr_opt_id) {
+            match state {
+                State::Querying(attempts, _) => {
+                    if *attempts < MAX_ATTEMPTS {
+                        *state = State::NotQueried(*attempts, now + RETRY_DELAY);
+                    } else {
+                        *state = State::DoNotQuery;
+                    }
+                }
+                State::DoNotQuery | State::NotQueried(_, _) | State::SuccessfulQuery => {
+                    debug_assert!(false, "unexpected state {state:?}");
+                    error!(
+                        ?addr_opt_id,
+                        ?state,
+                        "query failed for node in unexpected state"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Skips every node which has not been queried yet.
+    pub(crate) fn stop(&mut self) {
+        for state in self.addrs.values_mut() {
+            if let State::NotQueried(_, _) = state {
+                *state = State::DoNotQuery;
+            }
+        }
+    }
+
+    /// Skips every node which has not responded yet, including the nodes
+    /// with an outstanding query.
+    fn abandon(&mut self) {
+        for state in self.addrs.values_mut() {
+            if let State::NotQueried(_, _) | State::Querying(_, _) = state {
+                *state = State::DoNotQuery;
+            }
+        }
+    }
+}
+
+/// The queries and closest nodes of a lookup for a target.
+///
+/// Each closest node is stored with a value from the node's response such as
+/// a token.
+#[derive(Debug)]
+pub(crate) struct Lookup<V> {
+    target_id: node::Id,
+    closest_nodes: Vec<(AddrId<CompactAddr>, V)>,
+    max_found_nodes: usize,
+    supported_addr: SupportedAddr,
+    queries: Queries,
+    deadline: Instant,
+}
+
+impl<V> Lookup<V> {
+    pub(crate) fn new<T>(
+        target_id: node::Id,
+        max_found_nodes: usize,
+        supported_addr: SupportedAddr,
+        addrs: T,
+        now: Instant,
+    ) -> Self
+    where
+        T: IntoIterator<Item = AddrOptId<CompactAddr>>,
+    {
+        let mut queries = Queries::default();
+        for addr_opt_id in addrs {
+            if supported_addr.is_supported(addr_opt_id.addr()) {
+                queries.insert(addr_opt_id, now);
+            }
+        }
+        Self {
+            target_id,
+            closest_nodes: Vec::new(),
+            max_found_nodes,
+            supported_addr,
+            queries,
+            deadline: now + MAX_DURATION,
+        }
+    }
+
+    /// Returns when the lookup finishes with the closest nodes found so far.
+    #[must_use]
+    #[inline]
+    pub(crate) fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Skips the nodes which have not responded if the deadline has passed.
+    pub(crate) fn on_timeout(&mut self, now: Instant) {
+        if self.deadline <= now {
+            trace!(target_id = ?self.target_id, "lookup deadline passed");
+            self.queries.abandon();
+        }
+    }
+
+    /// Returns the target ID.
+    #[must_use]
+    #[inline]
+    pub(crate) fn target_id(&self) -> node::Id {
+        self.target_id
+    }
+
+    /// Returns the closest nodes sorted by distance to the target.
+    #[must_use]
+    #[inline]
+    pub(crate) fn closest_nodes(&self) -> &[(AddrId<CompactAddr>, V)] {
+        &self.closest_nodes
+    }
+
+    #[must_use]
+    #[inline]
+    pub(crate) fn queries(&self) -> &Queries {
+        &self.queries
+    }
+
+    #[must_use]
+    #[inline]
+    pub(crate) fn queries_mut(&mut self) -> &mut Queries {
+        &mut self.queries
+    }
+
+    /// Returns the distance which a node must be closer than to be queried.
+    #[must_use]
+    #[inline]
+    fn max_distance(&self) -> node::Id {
+        if self.closest_nodes.len() < self.max_found_nodes {
+            node::Id::max()
+        } else {
+            self.closest_nodes
+                .last()
+                .map_or(node::Id::max(), |(addr_id, _)| {
+                    addr_id.id().distance(self.target_id)
+                })
+        }
+    }
+
+    /// Adds a node which responded to the closest nodes if the node is closer
+    /// than the current closest nodes.
+    ///
+    /// Once the maximum number of closest nodes is found, nodes which are
+    /// further away are no longer queried.
+    pub(crate) fn try_replace_closest_nodes(&mut self, addr_id: AddrId<CompactAddr>, value: V) {
+        let new_distance = addr_id.id().distance(self.target_id);
+        let is_max_found_nodes = self.closest_nodes.len() == self.max_found_nodes;
+        if is_max_found_nodes {
+            let max_distance = self.max_distance();
+            if new_distance < max_distance {
+                self.closest_nodes.pop();
+            } else {
+                return;
+            }
+        }
+
+        self.closest_nodes.push((addr_id, value));
+        let target_id = self.target_id;
+        self.closest_nodes
+            .sort_by_key(|(a, _)| a.id().distance(target_id));
+
+        if is_max_found_nodes {
+            let max_distance = self.max_distance();
+            self.queries.addrs.retain(|potential_addr_opt_id, _| {
+                potential_addr_opt_id
+                    .id()
+                    .map_or(true, |id| id.distance(target_id) < max_distance)
+            });
+        }
+    }
+
+    /// Adds the nodes returned in a response which are closer than the
+    /// current closest nodes.
+    pub(crate) fn insert_nodes<I>(&mut self, nodes: I, now: Instant)
+    where
+        I: IntoIterator<Item = AddrId<CompactAddr>>,
+    {
+        let max_distance = self.max_distance();
+
+        for node in nodes {
+            if !self.supported_addr.is_supported(node.addr()) {
+                continue;
+            }
+
+            let node_id = node.id();
+            let node_distance = node_id.distance(self.target_id);
+            if node_distance >= max_distance {
+                trace!(
+                    ?node_id,
+                    ?node_distance,
+                    ?max_distance,
+                    "distance is greater than maximum distance"
+                );
+                continue;
+            }
+
+            self.queries
+                .insert(AddrOptId::new(*node.addr(), Some(node_id)), now);
+        }
+    }
+}
+
+/// Returns the IPv4 and IPv6 nodes from a response's `nodes` and `nodes6`
+/// values.
+///
+/// Invalid values are ignored.
+pub(crate) fn resp_nodes<N, N6, E>(
+    nodes: Option<Result<N, E>>,
+    nodes6: Option<Result<N6, E>>,
+) -> impl Iterator<Item = AddrId<CompactAddr>>
+where
+    N: IntoIterator<Item = AddrId<CompactAddrV4>>,
+    N6: IntoIterator<Item = AddrId<CompactAddrV6>>,
+{
+    let nodes = nodes
+        .and_then(Result::ok)
+        .into_iter()
+        .flatten()
+        .map(|node| AddrId::new(CompactAddr::from(*node.addr()), node.id()));
+    let nodes6 = nodes6
+        .and_then(Result::ok)
+        .into_iter()
+        .flatten()
+        .map(|node| AddrId::new(CompactAddr::from(*node.addr()), node.id()));
+    nodes.chain(nodes6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn addr(last: u8) -> CompactAddr {
+        CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, last), 6881))
+    }
+
+    #[test]
+    fn test_failed_queries_are_retried_then_skipped() {
+        let now = Instant::now();
+        let addr_opt_id = AddrOptId::with_addr(addr(1));
+        let mut queries = Queries::default();
+        queries.insert(addr_opt_id, now);
+
+        let mut now = now;
+        for attempt in 0..MAX_ATTEMPTS {
+            assert_eq!(queries.next_addr(now), Some(addr_opt_id));
+            assert!(queries.on_query_sent(addr_opt_id, transaction::Id::from(u16::from(attempt))));
+            assert_eq!(queries.next_addr(now), None);
+            assert!(!queries.is_done());
+
+            queries.on_failure(addr_opt_id, now);
+            assert_eq!(queries.next_addr(now), None);
+            now += RETRY_DELAY;
+        }
+
+        assert!(queries.is_done());
+        assert_eq!(queries.next_addr(now), None);
+    }
+
+    #[test]
+    fn test_closest_nodes_limit_queried_nodes() {
+        let now = Instant::now();
+        let target_id = node::Id::from([0; 20]);
+        let mut far_id = [0xFF; 20];
+        far_id[19] = 0;
+        let far = AddrId::new(addr(1), node::Id::from(far_id));
+        let near = AddrId::new(addr(2), node::Id::from([0x01; 20]));
+        let nearer = AddrId::new(addr(3), node::Id::from([0x00; 20]));
+
+        let mut lookup = Lookup::new(target_id, 1, SupportedAddr::Ipv4, [], now);
+        lookup.insert_nodes([far, near], now);
+        assert_eq!(lookup.queries().len(), 2);
+
+        lookup.try_replace_closest_nodes(far, ());
+        lookup.try_replace_closest_nodes(near, ());
+        assert_eq!(lookup.closest_nodes(), &[(near, ())]);
+        assert!(!lookup.queries().contains_addr(far.addr()));
+
+        lookup.insert_nodes([far, nearer], now);
+        assert_eq!(lookup.queries().len(), 1);
+        assert!(lookup.queries().contains_addr(nearer.addr()));
+    }
+}
@@ -0,0 +1,302 @@
+//! Counters and histograms for the Prometheus `/metrics` endpoint.
+//!
+//! The metrics are only updated by the DHT task, so no synchronization is
+//! needed. Gauges such as the routing table size are computed when the
+//! metrics are rendered.
+
+use cloudburst::dht::krpc::{
+    announce_peer::METHOD_ANNOUNCE_PEER, find_node::METHOD_FIND_NODE, get_peers::METHOD_GET_PEERS,
+    ping::METHOD_PING, Ty,
+};
+use core::{fmt::Write, time::Duration};
+use std::collections::BTreeMap;
+
+use super::krpc_ext::{METHOD_GET, METHOD_PUT, METHOD_SAMPLE_INFOHASHES};
+
+/// The upper bounds of the round trip time histogram buckets in seconds.
+const RTT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Returns the label for a KRPC method name.
+///
+/// Unknown methods share a label so remote nodes cannot create an unbounded
+/// number of series.
+#[must_use]
+pub fn method_label(method: Option<&[u8]>) -> &'static str {
+    match method {
+        Some(METHOD_PING) => "ping",
+        Some(METHOD_FIND_NODE) => "find_node",
+        Some(METHOD_GET_PEERS) => "get_peers",
+        Some(METHOD_ANNOUNCE_PEER) => "announce_peer",
+        Some(METHOD_GET) => "get",
+        Some(METHOD_PUT) => "put",
+        Some(METHOD_SAMPLE_INFOHASHES) => "sample_infohashes",
+        Some(_) | None => "unknown",
+    }
+}
+
+/// Returns the label for a KRPC message type.
+#[must_use]
+pub fn ty_label(ty: Ty) -> &'static str {
+    match ty {
+        Ty::Query => "query",
+        Ty::Response => "response",
+        Ty::Error => "error",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; RTT_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(pos) = RTT_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[pos] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The values which are read from the node's state when rendering.
+#[derive(Debug, Default)]
+pub struct Gauges {
+    /// The number of nodes by address family and node state
+    pub routing_table_nodes: Vec<(&'static str, &'static str, usize)>,
+    /// The number of buckets by address family
+    pub routing_table_buckets: Vec<(&'static str, usize)>,
+    /// The number of running ops by kind
+    pub active_ops: Vec<(&'static str, usize)>,
+    /// The number of queries waiting for a response
+    pub outstanding_transactions: usize,
+}
+
+/// The node's counters.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    packets_received: BTreeMap<(&'static str, &'static str), u64>,
+    bytes_received: BTreeMap<(&'static str, &'static str), u64>,
+    packets_sent: BTreeMap<(&'static str, &'static str), u64>,
+    bytes_sent: BTreeMap<(&'static str, &'static str), u64>,
+    replies_sent: BTreeMap<&'static str, u64>,
+    parse_failures: u64,
+    timeouts: BTreeMap<&'static str, u64>,
+    rtt: BTreeMap<&'static str, Histogram>,
+}
+
+impl Metrics {
+    /// Counts a received message.
+    pub fn on_recv(&mut self, method: &'static str, ty: &'static str, len: usize) {
+        *self.packets_received.entry((method, ty)).or_default() += 1;
+        *self.bytes_received.entry((method, ty)).or_default() += len as u64;
+    }
+
+    /// Counts a received packet which is not a valid KRPC message.
+    pub fn on_parse_failure(&mut self) {
+        self.parse_failures += 1;
+    }
+
+    /// Counts a sent message.
+    pub fn on_sent(&mut self, method: &'static str, ty: &'static str, len: usize) {
+        *self.packets_sent.entry((method, ty)).or_default() += 1;
+        *self.bytes_sent.entry((method, ty)).or_default() += len as u64;
+    }
+
+    /// Counts a reply to a remote node's query.
+    pub fn on_reply_sent(&mut self, method: &'static str, ty: &'static str, len: usize) {
+        *self.replies_sent.entry(method).or_default() += 1;
+        self.on_sent(method, ty, len);
+    }
+
+    /// Counts a query which did not receive a response before its deadline.
+    pub fn on_tx_timeout(&mut self, method: &'static str) {
+        *self.timeouts.entry(method).or_default() += 1;
+    }
+
+    /// Records the time between sending a query and receiving its response.
+    pub fn on_rtt(&mut self, method: &'static str, rtt: Duration) {
+        self.rtt
+            .entry(method)
+            .or_default()
+            .observe(rtt.as_secs_f64());
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    #[must_use]
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+
+        let msg_counters = [
+            (
+                "waynode_packets_received_total",
+                "KRPC messages received.",
+                &self.packets_received,
+            ),
+            (
+                "waynode_bytes_received_total",
+                "Bytes of KRPC messages received.",
+                &self.bytes_received,
+            ),
+            (
+                "waynode_packets_sent_total",
+                "KRPC messages sent.",
+                &self.packets_sent,
+            ),
+            (
+                "waynode_bytes_sent_total",
+                "Bytes of KRPC messages sent.",
+                &self.bytes_sent,
+            ),
+        ];
+        for (name, help, values) in msg_counters {
+            header(&mut out, name, help, "counter");
+            for ((method, ty), value) in values {
+                let _ = writeln!(out, "{name}{{method=\"{method}\",type=\"{ty}\"}} {value}");
+            }
+        }
+
+        let method_counters = [
+            (
+                "waynode_replies_sent_total",
+                "Replies sent to remote nodes' queries.",
+                &self.replies_sent,
+            ),
+            (
+                "waynode_transaction_timeouts_total",
+                "Queries which did not receive a response in time.",
+                &self.timeouts,
+            ),
+        ];
+        for (name, help, values) in method_counters {
+            header(&mut out, name, help, "counter");
+            for (method, value) in values {
+                let _ = writeln!(out, "{name}{{method=\"{method}\"}} {value}");
+            }
+        }
+
+        let name = "waynode_parse_failures_total";
+        header(
+            &mut out,
+            name,
+            "Received packets which are not valid KRPC messages.",
+            "counter",
+        );
+        let _ = writeln!(out, "{name} {}", self.parse_failures);
+
+        let name = "waynode_rtt_seconds";
+        header(
+            &mut out,
+            name,
+            "Time between sending a query and receiving its response.",
+            "histogram",
+        );
+        for (method, histogram) in &self.rtt {
+            let mut cumulative = 0;
+            for (bound, count) in RTT_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{method=\"{method}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{method=\"{method}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(out, "{name}_sum{{method=\"{method}\"}} {}", histogram.sum);
+            let _ = writeln!(
+                out,
+                "{name}_count{{method=\"{method}\"}} {}",
+                histogram.count
+            );
+        }
+
+        let name = "waynode_routing_table_nodes";
+        header(
+            &mut out,
+            name,
+            "Nodes in the routing tables by node state.",
+            "gauge",
+        );
+        for (family, state, value) in &gauges.routing_table_nodes {
+            let _ = writeln!(
+                out,
+                "{name}{{family=\"{family}\",state=\"{state}\"}} {value}"
+            );
+        }
+
+        let name = "waynode_routing_table_buckets";
+        header(&mut out, name, "Buckets in the routing tables.", "gauge");
+        for (family, value) in &gauges.routing_table_buckets {
+            let _ = writeln!(out, "{name}{{family=\"{family}\"}} {value}");
+        }
+
+        let name = "waynode_active_ops";
+        header(&mut out, name, "Running lookups by kind.", "gauge");
+        for (kind, value) in &gauges.active_ops {
+            let _ = writeln!(out, "{name}{{kind=\"{kind}\"}} {value}");
+        }
+
+        let name = "waynode_outstanding_transactions";
+        header(&mut out, name, "Queries waiting for a response.", "gauge");
+        let _ = writeln!(out, "{name} {}", gauges.outstanding_transactions);
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, ty: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {ty}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::default();
+        metrics.on_recv(method_label(Some(METHOD_PING)), ty_label(Ty::Query), 50);
+        metrics.on_recv(method_label(Some(b"vote")), ty_label(Ty::Query), 60);
+        metrics.on_reply_sent("ping", "response", 40);
+        metrics.on_rtt("find_node", Duration::from_millis(30));
+        metrics.on_rtt("find_node", Duration::from_secs(20));
+        metrics.on_tx_timeout("ping");
+        metrics.on_parse_failure();
+
+        let out = metrics.render(&Gauges {
+            routing_table_nodes: vec![("ipv4", "good", 3)],
+            routing_table_buckets: vec![("ipv4", 1)],
+            active_ops: vec![("find_node", 2)],
+            outstanding_transactions: 4,
+        });
+
+        for line in [
+            "waynode_packets_received_total{method=\"ping\",type=\"query\"} 1",
+            "waynode_bytes_received_total{method=\"unknown\",type=\"query\"} 60",
+            "waynode_packets_sent_total{method=\"ping\",type=\"response\"} 1",
+            "waynode_replies_sent_total{method=\"ping\"} 1",
+            "waynode_transaction_timeouts_total{method=\"ping\"} 1",
+            "waynode_parse_failures_total 1",
+            "waynode_rtt_seconds_bucket{method=\"find_node\",le=\"0.025\"} 0",
+            "waynode_rtt_seconds_bucket{method=\"find_node\",le=\"0.05\"} 1",
+            "waynode_rtt_seconds_bucket{method=\"find_node\",le=\"10\"} 1",
+            "waynode_rtt_seconds_bucket{method=\"find_node\",le=\"+Inf\"} 2",
+            "waynode_rtt_seconds_count{method=\"find_node\"} 2",
+            "waynode_routing_table_nodes{family=\"ipv4\",state=\"good\"} 3",
+            "waynode_routing_table_buckets{family=\"ipv4\"} 1",
+            "waynode_active_ops{kind=\"find_node\"} 2",
+            "waynode_outstanding_transactions 4",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {line}");
+        }
+    }
+}
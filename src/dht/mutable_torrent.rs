@@ -0,0 +1,129 @@
+//! Mutable torrents which point to the latest `InfoHash` with a mutable item.
+//!
+//! See [BEP 46](http://bittorrent.org/beps/bep_0046.html).
+
+use bt_bencode::Value;
+use cloudburst::metainfo::InfoHash;
+use core::{fmt, str::FromStr};
+use ed25519_dalek::PUBLIC_KEY_LENGTH;
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+
+use super::item_op::{Item, MAX_SALT_LEN};
+use crate::hex::{parse_hex, parse_hex_bytes, HexBytes};
+
+/// The value of a mutable item which points to a torrent.
+#[derive(Debug, Serialize, Deserialize)]
+struct TorrentValue {
+    ih: ByteBuf,
+}
+
+/// Returns the mutable item value which points to a torrent.
+#[must_use]
+pub fn value(info_hash: InfoHash) -> Value {
+    bt_bencode::to_value(&TorrentValue {
+        ih: ByteBuf::from(info_hash.0.to_vec()),
+    })
+    .expect("torrent value should be encodable")
+}
+
+/// Returns the `InfoHash` which a mutable item points to.
+#[must_use]
+pub fn info_hash(item: &Item) -> Option<InfoHash> {
+    let Item::Mutable(item) = item else {
+        return None;
+    };
+    let value = bt_bencode::from_value::<TorrentValue>(item.value.clone()).ok()?;
+    <[u8; 20]>::try_from(value.ih.as_slice())
+        .ok()
+        .map(InfoHash::from)
+}
+
+/// The error when a mutable torrent magnet link cannot be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("invalid mutable torrent magnet link")]
+pub struct ParseLinkError;
+
+/// A `magnet:?xs=urn:btpk:<public key>&s=<salt>` link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableTorrentLink {
+    /// The ed25519 public key which signs the mutable item
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    /// The salt of the mutable item
+    pub salt: Vec<u8>,
+}
+
+impl fmt::Display for MutableTorrentLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("magnet:?xs=urn:btpk:")?;
+        write!(f, "{}", HexBytes(&self.public_key))?;
+        if !self.salt.is_empty() {
+            write!(f, "&s={}", HexBytes(&self.salt))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MutableTorrentLink {
+    type Err = ParseLinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = s.strip_prefix("magnet:?").ok_or(ParseLinkError)?;
+
+        let mut public_key = None;
+        let mut salt = Vec::new();
+        for param in query.split('&') {
+            let (key, value) = param.split_once('=').ok_or(ParseLinkError)?;
+            match key {
+                "xs" => {
+                    let value = value.replace("%3A", ":").replace("%3a", ":");
+                    if let Some(hex) = value.strip_prefix("urn:btpk:") {
+                        public_key =
+                            Some(parse_hex::<PUBLIC_KEY_LENGTH>(hex).ok_or(ParseLinkError)?);
+                    }
+                }
+                "s" => {
+                    salt = parse_hex_bytes(value).ok_or(ParseLinkError)?;
+                    if salt.len() > MAX_SALT_LEN {
+                        return Err(ParseLinkError);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            public_key: public_key.ok_or(ParseLinkError)?,
+            salt,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::item_op::MutableItem;
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn test_resolve_link() {
+        let link = "magnet:?xs=urn:btpk:8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e&s=6e"
+            .parse::<MutableTorrentLink>()
+            .unwrap();
+        assert_eq!(link.salt, b"n");
+        assert_eq!(
+            link.to_string(),
+            "magnet:?xs=urn:btpk:8543D3E6115F0F98C944077A4493DCD543E49C739FD998550A1F614AB36ED63E&s=6E"
+        );
+        assert_eq!(
+            "magnet:?xt=urn:btih:0000000000000000000000000000000000000000"
+                .parse::<MutableTorrentLink>(),
+            Err(ParseLinkError)
+        );
+
+        let signing_key = SigningKey::generate(&mut rand::thread_rng());
+        let ih = InfoHash::from([0xAB; 20]);
+        let item = MutableItem::sign(&signing_key, link.salt.clone(), 1, value(ih)).unwrap();
+        assert_eq!(info_hash(&Item::Mutable(item)), Some(ih));
+    }
+}
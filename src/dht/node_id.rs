@@ -0,0 +1,167 @@
+//! Node IDs which are restricted by the node's IP address.
+//!
+//! See [BEP 42](http://bittorrent.org/beps/bep_0042.html).
+
+use cloudburst::dht::node;
+use core::{fmt, str::FromStr};
+use serde_derive::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// How remote nodes with IDs which are not valid for their IP address are
+/// treated in the routing table.
+///
+/// The serialized names are the same as the names parsed by [`FromStr`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeIdPolicy {
+    /// Node IDs are not checked.
+    Ignore,
+    /// Nodes with invalid IDs are only added if a bucket has space, are
+    /// replaced by nodes with valid IDs, and are returned after nodes with
+    /// valid IDs as neighbors.
+    #[default]
+    Deprioritize,
+    /// Nodes with invalid IDs are never added.
+    Enforce,
+}
+
+impl fmt::Display for NodeIdPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NodeIdPolicy::Ignore => "ignore",
+            NodeIdPolicy::Deprioritize => "deprioritize",
+            NodeIdPolicy::Enforce => "enforce",
+        })
+    }
+}
+
+/// The error when a node ID policy cannot be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("node ID policy must be one of: ignore, deprioritize, enforce")]
+pub struct ParseNodeIdPolicyError;
+
+impl FromStr for NodeIdPolicy {
+    type Err = ParseNodeIdPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(NodeIdPolicy::Ignore),
+            "deprioritize" => Ok(NodeIdPolicy::Deprioritize),
+            "enforce" => Ok(NodeIdPolicy::Enforce),
+            _ => Err(ParseNodeIdPolicyError),
+        }
+    }
+}
+
+/// Returns the CRC32-C of the masked IP address with the random value.
+fn crc(ip: IpAddr, r: u8) -> u32 {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mut bytes = ip.octets();
+            for (b, m) in bytes.iter_mut().zip(IPV4_MASK) {
+                *b &= m;
+            }
+            bytes[0] |= (r & 0x07) << 5;
+            crc32c::crc32c(&bytes)
+        }
+        IpAddr::V6(ip) => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&ip.octets()[..8]);
+            for (b, m) in bytes.iter_mut().zip(IPV6_MASK) {
+                *b &= m;
+            }
+            bytes[0] |= (r & 0x07) << 5;
+            crc32c::crc32c(&bytes)
+        }
+    }
+}
+
+/// Returns true if the IP address is exempt from node ID restrictions.
+fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Generates a random node ID which is valid for an IP address.
+pub fn secure_id<R>(ip: IpAddr, rng: &mut R) -> node::Id
+where
+    R: rand::Rng,
+{
+    let mut id = [0; 20];
+    rng.fill_bytes(&mut id);
+
+    // BEP 42 keeps the whole random last byte and only uses its low 3 bits.
+    let r = id[19] & 0x07;
+    let crc = crc(ip, r).to_be_bytes();
+    id[0] = crc[0];
+    id[1] = crc[1];
+    id[2] = (crc[2] & 0xf8) | (id[2] & 0x07);
+
+    node::Id::from(id)
+}
+
+/// Returns true if the node ID is valid for the IP address.
+///
+/// IDs for local network addresses are always valid.
+#[must_use]
+pub fn is_valid(id: node::Id, ip: IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+
+    let crc = crc(ip, id.0[19]).to_be_bytes();
+    id.0[0] == crc[0] && id.0[1] == crc[1] && (id.0[2] & 0xf8) == (crc[2] & 0xf8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_bep_42_vectors() {
+        let vectors = [
+            (Ipv4Addr::new(124, 31, 75, 21), 1, [0x5f, 0xbf, 0xb8]),
+            (Ipv4Addr::new(21, 75, 31, 124), 86, [0x5a, 0x3c, 0xe8]),
+            (Ipv4Addr::new(65, 23, 51, 170), 22, [0xa5, 0xd4, 0x30]),
+            (Ipv4Addr::new(84, 124, 73, 14), 65, [0x1b, 0x03, 0x20]),
+            (Ipv4Addr::new(43, 213, 53, 83), 90, [0xe5, 0x6f, 0x68]),
+        ];
+
+        for (ip, r, prefix) in vectors {
+            let mut id = [0; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = r;
+            assert!(is_valid(node::Id::from(id), IpAddr::V4(ip)), "{ip}");
+
+            id[0] ^= 0x01;
+            assert!(!is_valid(node::Id::from(id), IpAddr::V4(ip)), "{ip}");
+
+            let id = secure_id(IpAddr::V4(ip), &mut rand::thread_rng());
+            assert!(is_valid(id, IpAddr::V4(ip)), "{ip}");
+        }
+
+        assert!(is_valid(
+            node::Id::rand(&mut rand::thread_rng()).unwrap(),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))
+        ));
+    }
+
+    #[test]
+    fn test_secure_id_keeps_random_last_byte() {
+        let ip = IpAddr::V4(Ipv4Addr::new(124, 31, 75, 21));
+        let ids = (0..64)
+            .map(|_| secure_id(ip, &mut rand::thread_rng()))
+            .collect::<Vec<_>>();
+        assert!(ids.iter().all(|id| is_valid(*id, ip)));
+        assert!(ids.iter().any(|id| id.0[19] > 0x07));
+    }
+}
@@ -0,0 +1,275 @@
+use cloudburst::{
+    dht::krpc::{CompactAddr, CompactAddrV4, CompactAddrV6},
+    metainfo::InfoHash,
+};
+use rand::seq::SliceRandom;
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tracing::trace;
+
+use super::{bloom::BloomFilter, snapshot};
+
+/// The amount of time an announced peer is kept without being re-announced.
+const PEER_EXPIRATION: Duration = Duration::from_secs(30 * 60);
+
+/// The maximum number of peers stored for a single torrent.
+const MAX_PEERS_PER_INFO_HASH: usize = 256;
+
+/// The maximum number of torrents which peers are stored for.
+const MAX_INFO_HASHES: usize = 4096;
+
+#[derive(Debug)]
+struct Peer {
+    addr: CompactAddr,
+    seed: bool,
+    expiration: Instant,
+}
+
+/// The bencoded form of a stored peer.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncodedPeer {
+    addr: ByteBuf,
+    seed: u8,
+    exp: u64,
+}
+
+/// The bencoded form of the peers for a torrent.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncodedTorrent {
+    ih: ByteBuf,
+    peers: Vec<EncodedPeer>,
+}
+
+/// The bencoded form of the peer store.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncodedPeerStore {
+    torrents: Vec<EncodedTorrent>,
+}
+
+/// Stores peers which have announced themselves for a torrent.
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    torrents: HashMap<InfoHash, Vec<Peer>>,
+}
+
+impl PeerStore {
+    /// Inserts or refreshes an announced peer for a torrent.
+    ///
+    /// If the store is full, the peer is dropped.
+    pub fn insert(&mut self, info_hash: InfoHash, addr: CompactAddr, seed: bool, now: Instant) {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_INFO_HASHES {
+            trace!(?info_hash, "peer store is full");
+            return;
+        }
+
+        let peers = self.torrents.entry(info_hash).or_default();
+        let expiration = now + PEER_EXPIRATION;
+        if let Some(peer) = peers.iter_mut().find(|p| p.addr == addr) {
+            peer.seed = seed;
+            peer.expiration = expiration;
+            return;
+        }
+
+        if peers.len() >= MAX_PEERS_PER_INFO_HASH {
+            peers.retain(|p| now < p.expiration);
+            if peers.len() >= MAX_PEERS_PER_INFO_HASH {
+                trace!(?info_hash, "too many peers for info hash");
+                return;
+            }
+        }
+
+        peers.push(Peer {
+            addr,
+            seed,
+            expiration,
+        });
+    }
+
+    /// Returns the unexpired peers for a torrent.
+    pub fn peers(
+        &self,
+        info_hash: &InfoHash,
+        now: Instant,
+    ) -> impl Iterator<Item = CompactAddr> + '_ {
+        self.torrents
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(move |p| now < p.expiration)
+            .map(|p| p.addr)
+    }
+
+    /// Returns bloom filters of the unexpired seeds and of the other peers for a torrent.
+    #[must_use]
+    pub fn scrape(&self, info_hash: &InfoHash, now: Instant) -> (BloomFilter, BloomFilter) {
+        let mut seeds = BloomFilter::default();
+        let mut peers = BloomFilter::default();
+        for peer in self
+            .torrents
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|p| now < p.expiration)
+        {
+            let ip = SocketAddr::from(peer.addr).ip();
+            if peer.seed {
+                seeds.insert(ip);
+            } else {
+                peers.insert(ip);
+            }
+        }
+        (seeds, peers)
+    }
+
+    /// Returns a random sample of the `InfoHash`es with unexpired peers and
+    /// the total number of those `InfoHash`es.
+    pub fn sample_info_hashes<R>(
+        &self,
+        rng: &mut R,
+        amount: usize,
+        now: Instant,
+    ) -> (Vec<InfoHash>, usize)
+    where
+        R: rand::Rng,
+    {
+        let info_hashes = self
+            .torrents
+            .iter()
+            .filter(|(_, peers)| peers.iter().any(|p| now < p.expiration))
+            .map(|(info_hash, _)| *info_hash)
+            .collect::<Vec<_>>();
+        let num = info_hashes.len();
+        let samples = info_hashes.choose_multiple(rng, amount).copied().collect();
+        (samples, num)
+    }
+
+    /// Removes expired peers.
+    pub fn cleanup(&mut self, now: Instant) {
+        self.torrents.retain(|_, peers| {
+            peers.retain(|p| now < p.expiration);
+            !peers.is_empty()
+        });
+    }
+
+    /// Encodes the unexpired peers.
+    pub fn to_bytes(&self, now: Instant, unix_now: u64) -> Vec<u8> {
+        let torrents = self
+            .torrents
+            .iter()
+            .map(|(info_hash, peers)| EncodedTorrent {
+                ih: ByteBuf::from(info_hash.0.to_vec()),
+                peers: peers
+                    .iter()
+                    .filter(|p| now < p.expiration)
+                    .map(|p| EncodedPeer {
+                        addr: ByteBuf::from(p.addr.as_ref().to_vec()),
+                        seed: u8::from(p.seed),
+                        exp: snapshot::to_unix_expiration(p.expiration, now, unix_now),
+                    })
+                    .collect(),
+            })
+            .filter(|t| !t.peers.is_empty())
+            .collect();
+
+        bt_bencode::to_vec(&EncodedPeerStore { torrents }).expect("peers should be encodable")
+    }
+
+    /// Decodes peers which were encoded by [`Self::to_bytes()`].
+    ///
+    /// Peers which have expired since they were encoded are dropped.
+    ///
+    /// # Errors
+    ///
+    /// If the data is not a valid encoding, an error is returned.
+    pub fn from_bytes(bytes: &[u8], now: Instant, unix_now: u64) -> io::Result<Self> {
+        let invalid_data = || snapshot::invalid_data("peer store");
+
+        let encoded: EncodedPeerStore =
+            bt_bencode::from_slice(bytes).map_err(|_| invalid_data())?;
+        let mut peer_store = Self::default();
+        for torrent in encoded.torrents {
+            let info_hash = <[u8; 20]>::try_from(torrent.ih.as_slice())
+                .map(InfoHash::from)
+                .map_err(|_| invalid_data())?;
+            for peer in torrent.peers {
+                let addr = if let Ok(addr) = <[u8; 6]>::try_from(peer.addr.as_slice()) {
+                    CompactAddr::from(CompactAddrV4::from(addr))
+                } else if let Ok(addr) = <[u8; 18]>::try_from(peer.addr.as_slice()) {
+                    CompactAddr::from(CompactAddrV6::from(addr))
+                } else {
+                    return Err(invalid_data());
+                };
+                let Some(expiration) = snapshot::from_unix_expiration(peer.exp, now, unix_now)
+                else {
+                    continue;
+                };
+
+                peer_store.insert(info_hash, addr, peer.seed != 0, now);
+                if let Some(p) = peer_store
+                    .torrents
+                    .get_mut(&info_hash)
+                    .and_then(|peers| peers.iter_mut().find(|p| p.addr == addr))
+                {
+                    p.expiration = expiration;
+                }
+            }
+        }
+        Ok(peer_store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn test_peers_expire() {
+        let info_hash = InfoHash::from([1; 20]);
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let now = Instant::now();
+
+        let mut peer_store = PeerStore::default();
+        peer_store.insert(info_hash, addr, false, now);
+        peer_store.insert(info_hash, addr, true, now);
+        assert_eq!(
+            peer_store.peers(&info_hash, now).collect::<Vec<_>>(),
+            vec![addr]
+        );
+        let (seeds, peers) = peer_store.scrape(&info_hash, now);
+        assert!((seeds.estimate() - 1.0).abs() < 0.01);
+        assert_eq!(peers, BloomFilter::default());
+
+        assert_eq!(
+            peer_store.sample_info_hashes(&mut rand::thread_rng(), 20, now),
+            (vec![info_hash], 1)
+        );
+
+        let later = now + PEER_EXPIRATION;
+        assert_eq!(peer_store.peers(&info_hash, later).count(), 0);
+        assert_eq!(
+            peer_store.sample_info_hashes(&mut rand::thread_rng(), 20, later),
+            (vec![], 0)
+        );
+
+        let unix_now = 1_700_000_000;
+        let bytes = peer_store.to_bytes(now, unix_now);
+        let loaded = PeerStore::from_bytes(&bytes, now, unix_now).unwrap();
+        assert_eq!(
+            loaded.peers(&info_hash, now).collect::<Vec<_>>(),
+            vec![addr]
+        );
+        let loaded =
+            PeerStore::from_bytes(&bytes, now, unix_now + PEER_EXPIRATION.as_secs()).unwrap();
+        assert!(loaded.torrents.is_empty());
+
+        peer_store.cleanup(later);
+        assert!(peer_store.torrents.is_empty());
+    }
+}
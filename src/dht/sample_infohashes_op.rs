@@ -0,0 +1,283 @@
+use cloudburst::{
+    dht::{
+        krpc::{find_node::RespValues, transaction, CompactAddr},
+        node::AddrOptId,
+    },
+    metainfo::InfoHash,
+};
+use std::{collections::BTreeSet, time::Instant};
+use tokio::sync::oneshot;
+
+use super::{
+    krpc_ext::SampleInfoHashesRespValues,
+    lookup::{resp_nodes, Queries},
+    SupportedAddr,
+};
+
+/// The maximum number of nodes queried in a crawl.
+pub const MAX_NODES: usize = 4096;
+
+/// The maximum number of queries waiting for a response in a crawl.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// The result of a `sample_infohashes` crawl.
+#[derive(Debug, Clone)]
+pub struct SampleInfoHashesResult {
+    /// The sampled `InfoHash`es from all of the queried nodes
+    pub info_hashes: Vec<InfoHash>,
+    /// The number of nodes which returned a sample
+    pub sampled_nodes: usize,
+    /// The sum of the number of `InfoHash`es stored by each sampled node
+    pub num: u64,
+}
+
+/// A `sample_infohashes` query to send for a crawl.
+///
+/// The target of the query should be a random ID so that the crawl walks
+/// different regions of the keyspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleInfoHashesQuery {
+    /// The node to query
+    pub addr_opt_id: AddrOptId<CompactAddr>,
+}
+
+/// Collects `InfoHash`es by sending `sample_infohashes` queries to nodes.
+///
+/// Every node returned in a response is queried once until the maximum number
+/// of nodes is reached. The maximum number of nodes is at most [`MAX_NODES`].
+///
+/// See [BEP 51](http://bittorrent.org/beps/bep_0051.html).
+#[derive(Debug)]
+pub struct SampleInfoHashesOp {
+    max_nodes: usize,
+    supported_addr: SupportedAddr,
+    queries: Queries,
+    info_hashes: BTreeSet<InfoHash>,
+    sampled_nodes: usize,
+    num: u64,
+    subscribers: Vec<oneshot::Sender<SampleInfoHashesResult>>,
+}
+
+impl SampleInfoHashesOp {
+    pub fn new<T>(max_nodes: usize, supported_addr: SupportedAddr, addrs: T, now: Instant) -> Self
+    where
+        T: IntoIterator<Item = AddrOptId<CompactAddr>>,
+    {
+        let mut op = Self {
+            max_nodes: max_nodes.min(MAX_NODES),
+            supported_addr,
+            queries: Queries::default(),
+            info_hashes: BTreeSet::new(),
+            sampled_nodes: 0,
+            num: 0,
+            subscribers: Vec::new(),
+        };
+        for addr_opt_id in addrs {
+            op.insert_addr(addr_opt_id, now);
+        }
+        op
+    }
+
+    /// Adds a node to query if the node's address is not known and the
+    /// maximum number of nodes has not been reached.
+    fn insert_addr(&mut self, addr_opt_id: AddrOptId<CompactAddr>, now: Instant) {
+        if self.queries.len() >= self.max_nodes
+            || !self.supported_addr.is_supported(addr_opt_id.addr())
+            || self.queries.contains_addr(addr_opt_id.addr())
+        {
+            return;
+        }
+        self.queries.insert(addr_opt_id, now);
+    }
+
+    /// Adds a subscriber which is sent the result when the op is done.
+    pub fn subscribe(&mut self, tx: oneshot::Sender<SampleInfoHashesResult>) {
+        self.subscribers.push(tx);
+    }
+
+    /// Moves the subscribers from another op to this op.
+    ///
+    /// The maximum number of nodes is raised to the other op's maximum.
+    pub fn merge(&mut self, other: &mut SampleInfoHashesOp) {
+        self.subscribers.append(&mut other.subscribers);
+        self.max_nodes = self.max_nodes.max(other.max_nodes).min(MAX_NODES);
+    }
+
+    /// Returns if the space is done.
+    #[must_use]
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.queries.is_done()
+    }
+
+    /// Returns the current result of the crawl.
+    #[must_use]
+    pub fn result(&self) -> SampleInfoHashesResult {
+        SampleInfoHashesResult {
+            info_hashes: self.info_hashes.iter().copied().collect(),
+            sampled_nodes: self.sampled_nodes,
+            num: self.num,
+        }
+    }
+
+    /// Sends the result to all subscribers.
+    pub fn finish(&mut self) {
+        let result = self.result();
+        for tx in self.subscribers.drain(..) {
+            let _ = tx.send(result.clone());
+        }
+    }
+
+    /// Returns a node to query unless too many queries are waiting for a
+    /// response.
+    pub(crate) fn next_query(&self, now: Instant) -> Option<SampleInfoHashesQuery> {
+        if self.queries.in_flight() >= MAX_IN_FLIGHT {
+            return None;
+        }
+        self.queries
+            .next_addr(now)
+            .map(|addr_opt_id| SampleInfoHashesQuery { addr_opt_id })
+    }
+
+    pub(crate) fn on_query_sent(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        tx_id: transaction::Id,
+    ) {
+        self.queries.on_query_sent(addr_opt_id, tx_id);
+    }
+
+    pub(crate) fn on_resp(
+        &mut self,
+        addr_opt_id: AddrOptId<CompactAddr>,
+        resp: &RespValues<'_>,
+        sample: &SampleInfoHashesRespValues<'_>,
+        now: Instant,
+    ) {
+        self.queries.on_success(addr_opt_id);
+
+        self.sampled_nodes += 1;
+        self.info_hashes.extend(sample.samples());
+        self.num += sample
+            .num()
+            .and_then(|num| u64::try_from(num).ok())
+            .unwrap_or_default();
+
+        for node in resp_nodes(resp.nodes(), resp.nodes6()) {
+            self.insert_addr(AddrOptId::new(*node.addr(), Some(node.id())), now);
+        }
+    }
+
+    pub(crate) fn on_failure(&mut self, addr_opt_id: AddrOptId<CompactAddr>, now: Instant) {
+        self.queries.on_failure(addr_opt_id, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudburst::dht::{
+        krpc::{ser, Msg},
+        node::{self, LocalId},
+    };
+    use serde_bytes::Bytes;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn test_collects_samples_and_follows_nodes() {
+        let now = Instant::now();
+        let local_id = LocalId::from(node::Id::rand(&mut rand::thread_rng()).unwrap());
+        let addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881));
+        let addr_opt_id = AddrOptId::with_addr(addr);
+        let next_id = node::Id::rand(&mut rand::thread_rng()).unwrap();
+        let next_addr = CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 6881));
+
+        let mut op = SampleInfoHashesOp::new(2, SupportedAddr::Ipv4, [addr_opt_id], now);
+        let (tx, mut rx) = oneshot::channel();
+        op.subscribe(tx);
+
+        assert_eq!(
+            op.next_query(now),
+            Some(SampleInfoHashesQuery { addr_opt_id })
+        );
+        op.on_query_sent(addr_opt_id, transaction::Id::from(1));
+        assert_eq!(op.next_query(now), None);
+
+        let mut nodes = next_id.0.to_vec();
+        nodes.extend_from_slice(&[192, 0, 2, 2, 0x1A, 0xE1]);
+        let samples = [[0xAB; 20], [0xCD; 20]].concat();
+        let resp = bt_bencode::to_vec(&ser::RespMsg {
+            r: SampleInfoHashesRespValues::new(
+                &local_id,
+                300,
+                Some(Bytes::new(&nodes)),
+                None,
+                5,
+                &samples,
+            ),
+            t: Bytes::new(&[0, 1]),
+            v: None,
+        })
+        .unwrap();
+        let msg: Msg<'_> = bt_bencode::from_slice(&resp).unwrap();
+        op.on_resp(
+            addr_opt_id,
+            &msg.values().unwrap().unwrap(),
+            &msg.values().unwrap().unwrap(),
+            now,
+        );
+
+        let next_addr_opt_id = AddrOptId::new(next_addr, Some(next_id));
+        assert!(!op.is_done());
+        assert_eq!(
+            op.next_query(now),
+            Some(SampleInfoHashesQuery {
+                addr_opt_id: next_addr_opt_id
+            })
+        );
+        op.on_query_sent(next_addr_opt_id, transaction::Id::from(2));
+        op.on_failure(next_addr_opt_id, now);
+        assert_eq!(op.next_query(now), None);
+
+        let result = op.result();
+        assert_eq!(
+            result.info_hashes,
+            vec![InfoHash::from([0xAB; 20]), InfoHash::from([0xCD; 20])]
+        );
+        assert_eq!(result.sampled_nodes, 1);
+        assert_eq!(result.num, 5);
+
+        op.finish();
+        assert_eq!(rx.try_recv().unwrap().num, 5);
+    }
+
+    #[test]
+    fn test_limits_nodes_and_queries_in_flight() {
+        let now = Instant::now();
+        let addrs = (0..=u8::try_from(MAX_IN_FLIGHT).unwrap()).map(|i| {
+            AddrOptId::with_addr(CompactAddr::from(SocketAddrV4::new(
+                Ipv4Addr::new(192, 0, 2, i),
+                6881,
+            )))
+        });
+
+        let mut op = SampleInfoHashesOp::new(usize::MAX, SupportedAddr::Ipv4, addrs, now);
+        assert_eq!(op.max_nodes, MAX_NODES);
+
+        let mut sent = Vec::new();
+        for i in 0..MAX_IN_FLIGHT {
+            let query = op.next_query(now).unwrap();
+            op.on_query_sent(
+                query.addr_opt_id,
+                transaction::Id::from(u16::try_from(i).unwrap()),
+            );
+            sent.push(query.addr_opt_id);
+        }
+        assert_eq!(op.queries.len(), MAX_IN_FLIGHT + 1);
+        assert_eq!(op.next_query(now), None);
+
+        op.on_failure(sent[0], now);
+        assert!(op.next_query(now).is_some());
+        assert!(!op.is_done());
+    }
+}
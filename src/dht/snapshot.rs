@@ -0,0 +1,58 @@
+//! Helpers for writing the node's data to files and reading it back.
+//!
+//! Expiration deadlines are written as seconds since the Unix epoch because an
+//! [`Instant`] is only meaningful within the running process.
+
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Returns the number of seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Converts an expiration deadline to seconds since the Unix epoch.
+pub fn to_unix_expiration(expiration: Instant, now: Instant, unix_now: u64) -> u64 {
+    unix_now + expiration.saturating_duration_since(now).as_secs()
+}
+
+/// Converts seconds since the Unix epoch to an expiration deadline.
+///
+/// Returns `None` if the deadline has passed.
+pub fn from_unix_expiration(unix_expiration: u64, now: Instant, unix_now: u64) -> Option<Instant> {
+    unix_expiration
+        .checked_sub(unix_now)
+        .filter(|secs| *secs > 0)
+        .map(|secs| now + Duration::from_secs(secs))
+}
+
+/// Reads a file.
+///
+/// Returns `None` if the file does not exist.
+pub fn read(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a file.
+///
+/// The data is written to a temporary file first so an existing file is not
+/// corrupted if the write fails.
+pub fn write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Returns the error for a file which cannot be decoded.
+pub fn invalid_data(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {name}"))
+}
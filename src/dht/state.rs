@@ -0,0 +1,145 @@
+//! The local node's state which is kept across restarts.
+
+use cloudburst::dht::{
+    krpc::{CompactAddr, CompactAddrV4, CompactAddrV6},
+    node::{self, AddrId},
+};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::{io, path::Path};
+
+use super::snapshot;
+
+/// The length of a node ID and a compact IPv4 address.
+const COMPACT_NODE_LEN: usize = 26;
+
+/// The length of a node ID and a compact IPv6 address.
+const COMPACT_NODE6_LEN: usize = 38;
+
+/// The bencoded form of the state.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncodedState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+    nodes6: ByteBuf,
+}
+
+/// The local node ID and the good nodes in the routing tables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtState {
+    /// The local node ID
+    pub local_id: node::Id,
+    /// The good nodes from each bucket
+    pub addr_ids: Vec<AddrId<CompactAddr>>,
+}
+
+impl DhtState {
+    /// Reads the state from a file.
+    ///
+    /// Returns `None` if the file does not exist.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be read or is not a valid state, an error is returned.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        snapshot::read(path)?
+            .map(|bytes| Self::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Writes the state to a file.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be written, an error is returned.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        snapshot::write(path, &self.to_bytes())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut nodes = Vec::new();
+        let mut nodes6 = Vec::new();
+        for addr_id in &self.addr_ids {
+            let buf = match addr_id.addr() {
+                CompactAddr::V4(_) => &mut nodes,
+                CompactAddr::V6(_) => &mut nodes6,
+            };
+            buf.extend_from_slice(&addr_id.id().0);
+            buf.extend_from_slice(addr_id.addr().as_ref());
+        }
+
+        bt_bencode::to_vec(&EncodedState {
+            id: ByteBuf::from(self.local_id.0.to_vec()),
+            nodes: ByteBuf::from(nodes),
+            nodes6: ByteBuf::from(nodes6),
+        })
+        .expect("state should be encodable")
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid_data = || snapshot::invalid_data("DHT state");
+
+        let state: EncodedState = bt_bencode::from_slice(bytes).map_err(|_| invalid_data())?;
+        let local_id = <[u8; 20]>::try_from(state.id.as_slice())
+            .map(node::Id::from)
+            .map_err(|_| invalid_data())?;
+        if state.nodes.len() % COMPACT_NODE_LEN != 0 || state.nodes6.len() % COMPACT_NODE6_LEN != 0
+        {
+            return Err(invalid_data());
+        }
+
+        let nodes = state.nodes.chunks_exact(COMPACT_NODE_LEN).map(|bytes| {
+            let id = <[u8; 20]>::try_from(&bytes[..20]).unwrap();
+            let addr = <[u8; 6]>::try_from(&bytes[20..]).unwrap();
+            AddrId::new(
+                CompactAddr::from(CompactAddrV4::from(addr)),
+                node::Id::from(id),
+            )
+        });
+        let nodes6 = state.nodes6.chunks_exact(COMPACT_NODE6_LEN).map(|bytes| {
+            let id = <[u8; 20]>::try_from(&bytes[..20]).unwrap();
+            let addr = <[u8; 18]>::try_from(&bytes[20..]).unwrap();
+            AddrId::new(
+                CompactAddr::from(CompactAddrV6::from(addr)),
+                node::Id::from(id),
+            )
+        });
+
+        Ok(Self {
+            local_id,
+            addr_ids: nodes.chain(nodes6).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = rand::thread_rng();
+        let state = DhtState {
+            local_id: node::Id::rand(&mut rng).unwrap(),
+            addr_ids: vec![
+                AddrId::new(
+                    CompactAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6881)),
+                    node::Id::rand(&mut rng).unwrap(),
+                ),
+                AddrId::new(
+                    CompactAddr::from(SocketAddrV6::new(
+                        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+                        6881,
+                        0,
+                        0,
+                    )),
+                    node::Id::rand(&mut rng).unwrap(),
+                ),
+            ],
+        };
+
+        assert_eq!(DhtState::from_bytes(&state.to_bytes()).unwrap(), state);
+        assert!(DhtState::from_bytes(b"d2:id3:abce").is_err());
+    }
+}
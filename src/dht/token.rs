@@ -0,0 +1,117 @@
+use sha1::{Digest, Sha1};
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// The interval between secret rotations.
+///
+/// Both the current and the previous secret are accepted, so a token is
+/// valid for between one and two intervals.
+const SECRET_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The number of bytes in a token.
+pub const TOKEN_LEN: usize = 8;
+
+/// Generates and verifies the opaque tokens required to announce a peer.
+///
+/// A token is derived from the requesting node's IP address and a local
+/// secret. The secret is rotated periodically so a token is only valid for a
+/// limited time.
+#[derive(Debug)]
+pub struct Tokens {
+    secret: [u8; 20],
+    prev_secret: [u8; 20],
+    rotate_deadline: Instant,
+}
+
+impl Tokens {
+    /// Instantiates with a random secret.
+    pub fn new<R>(rng: &mut R, now: Instant) -> Self
+    where
+        R: rand::Rng,
+    {
+        let secret = rng.gen();
+        Self {
+            secret,
+            prev_secret: secret,
+            rotate_deadline: now + SECRET_ROTATION_INTERVAL,
+        }
+    }
+
+    /// Returns the deadline when the secret should be rotated.
+    #[must_use]
+    pub fn timeout(&self) -> Instant {
+        self.rotate_deadline
+    }
+
+    /// Rotates the secret if the deadline has passed.
+    pub fn on_timeout<R>(&mut self, rng: &mut R, now: Instant)
+    where
+        R: rand::Rng,
+    {
+        if self.rotate_deadline <= now {
+            self.prev_secret = self.secret;
+            self.secret = rng.gen();
+            self.rotate_deadline = now + SECRET_ROTATION_INTERVAL;
+        }
+    }
+
+    /// Returns the token for an IP address.
+    #[must_use]
+    pub fn token(&self, ip: IpAddr) -> [u8; TOKEN_LEN] {
+        make_token(&self.secret, ip)
+    }
+
+    /// Returns true if the token was given to the IP address with either the
+    /// current or the previous secret.
+    #[must_use]
+    pub fn is_valid(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == make_token(&self.secret, ip) || token == make_token(&self.prev_secret, ip)
+    }
+}
+
+fn make_token(secret: &[u8; 20], ip: IpAddr) -> [u8; TOKEN_LEN] {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(secret);
+    let hash = hasher.finalize();
+
+    let mut token = [0; TOKEN_LEN];
+    token.copy_from_slice(&hash[..TOKEN_LEN]);
+    token
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_token_valid_for_two_rotations() {
+        let mut rng = rand::thread_rng();
+        let now = Instant::now();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        let mut tokens = Tokens::new(&mut rng, now);
+        let token = tokens.token(ip);
+        assert!(tokens.is_valid(&token, ip));
+        assert!(!tokens.is_valid(&token, other_ip));
+
+        tokens.on_timeout(&mut rng, now);
+        assert!(tokens.is_valid(&token, ip));
+
+        let now = tokens.timeout();
+        tokens.on_timeout(&mut rng, now);
+        assert!(tokens.is_valid(&token, ip));
+        assert_ne!(tokens.token(ip), token);
+
+        let now = tokens.timeout();
+        tokens.on_timeout(&mut rng, now);
+        assert!(!tokens.is_valid(&token, ip));
+    }
+}
@@ -0,0 +1,155 @@
+//! Outstanding queries sent by the local node.
+//!
+//! This is a fork of `cloudburst`'s `Transactions` because the upstream
+//! collection cannot list its transactions and does not keep the time each
+//! query was sent. The methods match the upstream methods so the fork can be
+//! replaced once `cloudburst` has accessors for both. The only differences
+//! are that [`Transactions::insert`] takes the time the query was sent,
+//! [`Transactions::on_recv`] also returns that time, and
+//! [`Transactions::iter`] lists the transactions.
+
+use cloudburst::dht::krpc::transaction::{self, Transaction};
+use std::time::Instant;
+
+/// The error when a message does not match an outstanding transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("unknown transaction")]
+pub struct UnknownTx;
+
+#[derive(Debug)]
+struct Entry<Addr> {
+    tx: Transaction<Addr, transaction::Id, Instant>,
+    sent_at: Instant,
+}
+
+/// A collection of local transactions.
+#[derive(Debug)]
+pub struct Transactions<Addr> {
+    txs: Vec<Entry<Addr>>,
+}
+
+impl<Addr> Default for Transactions<Addr> {
+    fn default() -> Self {
+        Self { txs: Vec::new() }
+    }
+}
+
+impl<Addr> Transactions<Addr> {
+    /// Inserts a transaction into the collection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the transaction ID matches an existing transaction ID.
+    pub fn insert(&mut self, tx: Transaction<Addr, transaction::Id, Instant>, sent_at: &Instant) {
+        assert!(!self.contains(&tx.tx_id));
+        self.txs.push(Entry {
+            tx,
+            sent_at: *sent_at,
+        });
+    }
+
+    /// Removes and returns the transaction for a received message's
+    /// transaction ID and the time the query was sent.
+    ///
+    /// # Errors
+    ///
+    /// If there is no transaction with the ID, an error is returned.
+    pub fn on_recv(
+        &mut self,
+        tx_id: &transaction::Id,
+    ) -> Result<(Transaction<Addr, transaction::Id, Instant>, Instant), UnknownTx> {
+        self.txs
+            .iter()
+            .position(|entry| entry.tx.tx_id == *tx_id)
+            .map(|index| {
+                let entry = self.txs.remove(index);
+                (entry.tx, entry.sent_at)
+            })
+            .ok_or(UnknownTx)
+    }
+
+    /// Returns true if there is a transaction which has the given transaction ID.
+    #[must_use]
+    pub fn contains(&self, tx_id: &transaction::Id) -> bool {
+        self.txs.iter().any(|entry| entry.tx.tx_id == *tx_id)
+    }
+
+    /// The number of transactions.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    /// Returns the minimum timeout deadline of all the transactions.
+    ///
+    /// Returns `None` if there are no transactions.
+    #[must_use]
+    pub fn timeout(&self) -> Option<Instant> {
+        self.txs.iter().map(|entry| entry.tx.timeout_deadline).min()
+    }
+
+    /// Finds and removes a transaction which has timed out.
+    pub fn pop_timed_out_tx(
+        &mut self,
+        now: &Instant,
+    ) -> Option<Transaction<Addr, transaction::Id, Instant>> {
+        let pos = self
+            .txs
+            .iter()
+            .position(|entry| entry.tx.timeout_deadline <= *now)?;
+        Some(self.txs.remove(pos).tx)
+    }
+
+    /// Returns the outstanding transactions.
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction<Addr, transaction::Id, Instant>> {
+        self.txs.iter().map(|entry| &entry.tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudburst::dht::node::AddrOptId;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timed_out_tx() {
+        let now = Instant::now();
+        let mut txs = Transactions::default();
+        txs.insert(
+            Transaction::new(
+                AddrOptId::with_addr(1),
+                transaction::Id::from(1),
+                b"ping",
+                now + Duration::from_secs(60),
+            ),
+            &now,
+        );
+        txs.insert(
+            Transaction::new(
+                AddrOptId::with_addr(2),
+                transaction::Id::from(2),
+                b"find_node",
+                now + Duration::from_secs(30),
+            ),
+            &now,
+        );
+        assert_eq!(txs.timeout(), Some(now + Duration::from_secs(30)));
+        assert_eq!(txs.iter().count(), 2);
+
+        assert!(txs.pop_timed_out_tx(&now).is_none());
+        let tx = txs
+            .pop_timed_out_tx(&(now + Duration::from_secs(30)))
+            .unwrap();
+        assert_eq!(tx.tx_id, transaction::Id::from(2));
+
+        assert_eq!(
+            txs.on_recv(&transaction::Id::from(2)).err(),
+            Some(UnknownTx)
+        );
+        let (tx, sent_at) = txs.on_recv(&transaction::Id::from(1)).unwrap();
+        assert_eq!(tx.tx_id, transaction::Id::from(1));
+        assert_eq!(sent_at, now);
+        assert_eq!(txs.len(), 0);
+    }
+}
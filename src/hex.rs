@@ -0,0 +1,57 @@
+//! Hex encoding of byte strings such as IDs, tokens, and keys.
+//!
+//! Bytes are always written as uppercase hex. Both cases are accepted when
+//! parsing.
+
+use core::fmt;
+
+/// Displays bytes as uppercase hex.
+pub struct HexBytes<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Display for HexBytes<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a hex string with exactly `N` bytes.
+pub fn parse_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 {
+        return None;
+    }
+    parse_hex_bytes(value)?.try_into().ok()
+}
+
+/// Parses a hex string.
+pub fn parse_hex_bytes(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    value
+        .as_bytes()
+        .chunks_exact(2)
+        .map(|chunk| {
+            let chunk = core::str::from_utf8(chunk).ok()?;
+            u8::from_str_radix(chunk, 16).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(HexBytes(&[0x0A, 0xBC, 0xDE]).to_string(), "0ABCDE");
+        assert_eq!(parse_hex_bytes("0abcDE"), Some(vec![0x0A, 0xBC, 0xDE]));
+        assert_eq!(parse_hex::<2>("0ABC"), Some([0x0A, 0xBC]));
+        assert_eq!(parse_hex::<2>("0ABCDE"), None);
+        assert_eq!(parse_hex_bytes("0AB"), None);
+        assert_eq!(parse_hex_bytes("+A"), None);
+    }
+}
@@ -1,17 +1,45 @@
 use axum::{
-    http::StatusCode,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use bt_bencode::Value;
+use cloudburst::{dht::node, metainfo::InfoHash};
 use hyper::{body::Incoming, Request};
 use hyper_util::rt::TokioIo;
-use serde_derive::Serialize;
-use std::{fmt, io, net::SocketAddr, time::Duration};
-use tokio::{net::TcpListener, sync::watch};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot, watch},
+};
 use tower::Service;
 use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
 
-use crate::dht::{self, Cmd};
+use crate::config;
+use crate::dht::{
+    self,
+    find_node_op::FindNodeResult,
+    get_peers_op::{AnnounceArgs, GetPeersResult},
+    item_op::{self, GetItemResult, Item, PutError},
+    mutable_torrent::{self, MutableTorrentLink},
+    node_id::NodeIdPolicy,
+    sample_infohashes_op::SampleInfoHashesResult,
+    Cmd,
+};
+use crate::hex::{parse_hex, parse_hex_bytes, HexBytes};
+
+/// The maximum amount of time to wait for a lookup in the DHT to finish.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The maximum amount of time for the DHT task to answer a health check.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Serialize)]
 struct Config {
@@ -22,41 +50,35 @@ struct Config {
     is_response_queried_node_id_strictly_checked: bool,
     routing_table_next_response_interval: Duration,
     routing_table_next_query_interval: Duration,
+    announce_interval: Duration,
+    supported_addr: dht::SupportedAddr,
+    node_id_policy: NodeIdPolicy,
+    is_local_id_regenerated_for_external_ip: bool,
 }
 
 impl From<dht::Config> for Config {
     fn from(value: dht::Config) -> Self {
-        struct ClientVersion<'a> {
-            version: &'a [u8],
-        }
-
-        impl<'a> fmt::Display for ClientVersion<'a> {
-            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                for b in self.version {
-                    write!(f, "{b:02X}")?;
-                }
-                Ok(())
-            }
-        }
-
         Self {
             local_id: format!("{}", value.local_id().0),
-            client_version: value.client_version.map(|version| {
-                let v = ClientVersion { version: &version };
-                v.to_string()
-            }),
+            client_version: value
+                .client_version
+                .map(|version| String::from_utf8_lossy(&version).into_owned()),
             default_query_timeout: value.default_query_timeout,
             is_read_only_node: value.is_read_only_node,
             is_response_queried_node_id_strictly_checked: value
                 .is_response_queried_node_id_strictly_checked,
             routing_table_next_response_interval: value.routing_table_next_response_interval,
             routing_table_next_query_interval: value.routing_table_next_query_interval,
+            announce_interval: value.announce_interval,
+            supported_addr: value.supported_addr,
+            node_id_policy: value.node_id_policy,
+            is_local_id_regenerated_for_external_ip: value.is_local_id_regenerated_for_external_ip,
         }
     }
 }
 
-async fn get_config(cmd_tx: tokio::sync::mpsc::Sender<Cmd>) -> Response {
-    let (tx, rx) = tokio::sync::oneshot::channel();
+async fn get_config(State(cmd_tx): State<mpsc::Sender<Cmd>>) -> Response {
+    let (tx, rx) = oneshot::channel();
     let _ = cmd_tx.send(Cmd::GetConfig(tx)).await;
 
     match rx.await {
@@ -68,24 +90,1048 @@ async fn get_config(cmd_tx: tokio::sync::mpsc::Sender<Cmd>) -> Response {
     }
 }
 
+/// Changes to the config.
+///
+/// The fields are the same as the config returned from `GET /config`. A
+/// `PATCH` only sets the fields which are present. A `PUT` must have every
+/// field.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateConfig {
+    local_id: Option<String>,
+    client_version: Option<String>,
+    default_query_timeout: Option<Duration>,
+    is_read_only_node: Option<bool>,
+    is_response_queried_node_id_strictly_checked: Option<bool>,
+    routing_table_next_response_interval: Option<Duration>,
+    routing_table_next_query_interval: Option<Duration>,
+    announce_interval: Option<Duration>,
+    supported_addr: Option<dht::SupportedAddr>,
+    node_id_policy: Option<NodeIdPolicy>,
+    is_local_id_regenerated_for_external_ip: Option<bool>,
+}
+
+impl UpdateConfig {
+    fn to_update(&self) -> Result<dht::ConfigUpdate, (StatusCode, String)> {
+        let client_version = match &self.client_version {
+            Some(version) => match config::parse_client_version(version) {
+                Ok(version) => Some(version.into_bytes()),
+                Err(e) => return Err((StatusCode::BAD_REQUEST, e)),
+            },
+            None => None,
+        };
+
+        for (name, value) in [
+            ("default_query_timeout", self.default_query_timeout),
+            (
+                "routing_table_next_response_interval",
+                self.routing_table_next_response_interval,
+            ),
+            (
+                "routing_table_next_query_interval",
+                self.routing_table_next_query_interval,
+            ),
+            ("announce_interval", self.announce_interval),
+        ] {
+            if value.is_some_and(|value| value.is_zero()) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("{name} must be greater than zero"),
+                ));
+            }
+        }
+
+        Ok(dht::ConfigUpdate {
+            client_version,
+            default_query_timeout: self.default_query_timeout,
+            is_read_only_node: self.is_read_only_node,
+            is_response_queried_node_id_strictly_checked: self
+                .is_response_queried_node_id_strictly_checked,
+            routing_table_next_response_interval: self.routing_table_next_response_interval,
+            routing_table_next_query_interval: self.routing_table_next_query_interval,
+            announce_interval: self.announce_interval,
+            node_id_policy: self.node_id_policy,
+            is_local_id_regenerated_for_external_ip: self.is_local_id_regenerated_for_external_ip,
+        })
+    }
+
+    /// Returns the fields which are not set.
+    fn missing_fields(&self) -> Vec<&'static str> {
+        [
+            ("local_id", self.local_id.is_none()),
+            ("client_version", self.client_version.is_none()),
+            (
+                "default_query_timeout",
+                self.default_query_timeout.is_none(),
+            ),
+            ("is_read_only_node", self.is_read_only_node.is_none()),
+            (
+                "is_response_queried_node_id_strictly_checked",
+                self.is_response_queried_node_id_strictly_checked.is_none(),
+            ),
+            (
+                "routing_table_next_response_interval",
+                self.routing_table_next_response_interval.is_none(),
+            ),
+            (
+                "routing_table_next_query_interval",
+                self.routing_table_next_query_interval.is_none(),
+            ),
+            ("announce_interval", self.announce_interval.is_none()),
+            ("supported_addr", self.supported_addr.is_none()),
+            ("node_id_policy", self.node_id_policy.is_none()),
+            (
+                "is_local_id_regenerated_for_external_ip",
+                self.is_local_id_regenerated_for_external_ip.is_none(),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, is_missing)| is_missing.then_some(name))
+        .collect()
+    }
+
+    /// Returns the fields which are different from the config but can only
+    /// be changed by restarting the node.
+    fn restart_required(&self, config: &dht::Config) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self
+            .local_id
+            .as_ref()
+            .is_some_and(|id| parse_hex(id).map(node::Id::from) != Some(config.local_id.0))
+        {
+            fields.push("local_id");
+        }
+        if self
+            .supported_addr
+            .is_some_and(|supported_addr| supported_addr != config.supported_addr)
+        {
+            fields.push("supported_addr");
+        }
+        fields
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct UpdatedConfig {
+    applied: Vec<&'static str>,
+    restart_required: Vec<&'static str>,
+    config: Config,
+}
+
+async fn replace_config(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Json(body): Json<UpdateConfig>,
+) -> Response {
+    let missing_fields = body.missing_fields();
+    if !missing_fields.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("missing fields: {}", missing_fields.join(", ")),
+        )
+            .into_response();
+    }
+
+    apply_config(cmd_tx, body).await
+}
+
+async fn update_config(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Json(body): Json<UpdateConfig>,
+) -> Response {
+    apply_config(cmd_tx, body).await
+}
+
+async fn apply_config(cmd_tx: mpsc::Sender<Cmd>, body: UpdateConfig) -> Response {
+    let update = match body.to_update() {
+        Ok(update) => update,
+        Err(e) => return e.into_response(),
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::UpdateConfig(update, tx)).await;
+
+    match rx.await {
+        Ok((applied, config)) => Json(UpdatedConfig {
+            applied,
+            restart_required: body.restart_required(&config),
+            config: Config::from(config),
+        })
+        .into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Returns the number of seconds until a deadline.
+///
+/// The value is negative if the deadline has passed.
+fn secs_until(deadline: Instant, now: Instant) -> i64 {
+    if deadline >= now {
+        i64::try_from(deadline.duration_since(now).as_secs()).unwrap_or(i64::MAX)
+    } else {
+        -i64::try_from(now.duration_since(deadline).as_secs()).unwrap_or(i64::MAX)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RoutingTableNode {
+    id: String,
+    addr: SocketAddr,
+    karma: i8,
+    state: &'static str,
+    next_response_deadline_secs: i64,
+    next_query_deadline_secs: i64,
+    is_pinging: bool,
+}
+
+impl RoutingTableNode {
+    fn new(node: &dht::NodeSnapshot, now: Instant) -> Self {
+        Self {
+            id: node.addr_id.id().to_string(),
+            addr: SocketAddr::from(*node.addr_id.addr()),
+            karma: node.karma,
+            state: match node.state {
+                dht::NodeState::Good => "Good",
+                dht::NodeState::Questionable => "Questionable",
+                dht::NodeState::Bad => "Bad",
+            },
+            next_response_deadline_secs: secs_until(node.next_response_deadline, now),
+            next_query_deadline_secs: secs_until(node.next_query_deadline, now),
+            is_pinging: node.is_pinging,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RoutingTableBucket {
+    range_start: String,
+    range_end: String,
+    refresh_deadline_secs: i64,
+    nodes: Vec<RoutingTableNode>,
+}
+
+impl RoutingTableBucket {
+    fn new(bucket: &dht::BucketSnapshot, now: Instant) -> Self {
+        Self {
+            range_start: bucket.range.start().to_string(),
+            range_end: bucket.range.end().to_string(),
+            refresh_deadline_secs: secs_until(bucket.refresh_deadline, now),
+            nodes: bucket
+                .nodes
+                .iter()
+                .map(|node| RoutingTableNode::new(node, now))
+                .collect(),
+        }
+    }
+}
+
+/// The routing tables.
+///
+/// Deadlines are the number of seconds from now and are negative if the
+/// deadline has passed.
+#[derive(Debug, Serialize)]
+struct RoutingTable {
+    local_id: String,
+    buckets: Vec<RoutingTableBucket>,
+    buckets6: Vec<RoutingTableBucket>,
+}
+
+impl RoutingTable {
+    fn new(snapshot: &dht::RoutingTableSnapshot, now: Instant) -> Self {
+        let buckets = |buckets: &[dht::BucketSnapshot]| {
+            buckets
+                .iter()
+                .map(|bucket| RoutingTableBucket::new(bucket, now))
+                .collect()
+        };
+        Self {
+            local_id: snapshot.pivot.to_string(),
+            buckets: buckets(&snapshot.buckets),
+            buckets6: buckets(&snapshot.buckets6),
+        }
+    }
+}
+
+async fn get_routing_table(State(cmd_tx): State<mpsc::Sender<Cmd>>) -> Response {
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetRoutingTable(tx)).await;
+
+    match rx.await {
+        Ok(snapshot) => Json(RoutingTable::new(&snapshot, Instant::now())).into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OutstandingTransaction {
+    tx_id: String,
+    method: String,
+    addr: SocketAddr,
+    node_id: Option<String>,
+    timeout_deadline_secs: i64,
+}
+
+async fn get_transactions(State(cmd_tx): State<mpsc::Sender<Cmd>>) -> Response {
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetTransactions(tx)).await;
+
+    match rx.await {
+        Ok(txs) => {
+            let now = Instant::now();
+            Json(
+                txs.into_iter()
+                    .map(|tx| OutstandingTransaction {
+                        tx_id: HexBytes(&tx.tx_id.0).to_string(),
+                        method: String::from_utf8_lossy(tx.method).into_owned(),
+                        addr: SocketAddr::from(*tx.addr_opt_id.addr()),
+                        node_id: tx.addr_opt_id.id().map(|id| id.to_string()),
+                        timeout_deadline_secs: secs_until(tx.timeout_deadline, now),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .into_response()
+        }
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AddrStates {
+    not_queried: usize,
+    querying: usize,
+    successful_query: usize,
+    do_not_query: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct FoundNode {
+    id: String,
+    addr: SocketAddr,
+}
+
+#[derive(Debug, Serialize)]
+struct FindNodeOp {
+    target: String,
+    closest_nodes: Vec<FoundNode>,
+    addrs: AddrStates,
+}
+
+impl From<dht::find_node_op::FindNodeOpSnapshot> for FindNodeOp {
+    fn from(value: dht::find_node_op::FindNodeOpSnapshot) -> Self {
+        Self {
+            target: value.target_id.to_string(),
+            closest_nodes: value
+                .closest_nodes
+                .into_iter()
+                .map(|addr_id| FoundNode {
+                    id: addr_id.id().to_string(),
+                    addr: SocketAddr::from(*addr_id.addr()),
+                })
+                .collect(),
+            addrs: AddrStates {
+                not_queried: value.not_queried,
+                querying: value.querying,
+                successful_query: value.successful_query,
+                do_not_query: value.do_not_query,
+            },
+        }
+    }
+}
+
+async fn get_ops(State(cmd_tx): State<mpsc::Sender<Cmd>>) -> Response {
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetOps(tx)).await;
+
+    match rx.await {
+        Ok(ops) => Json(ops.into_iter().map(FindNodeOp::from).collect::<Vec<_>>()).into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    local_id: String,
+    external_ipv4: Option<Ipv4Addr>,
+    external_ipv6: Option<Ipv6Addr>,
+    is_bootstrapped: bool,
+}
+
+impl From<dht::Status> for Status {
+    fn from(value: dht::Status) -> Self {
+        Self {
+            local_id: format!("{}", value.local_id.0),
+            external_ipv4: value.external_ipv4,
+            external_ipv6: value.external_ipv6,
+            is_bootstrapped: value.is_bootstrapped,
+        }
+    }
+}
+
+async fn get_status(State(cmd_tx): State<mpsc::Sender<Cmd>>) -> Response {
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetStatus(tx)).await;
+
+    match rx.await {
+        Ok(status) => Json(Status::from(status)).into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ClosestNode {
+    id: String,
+    addr: String,
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Peers {
+    info_hash: String,
+    peers: Vec<String>,
+    closest_nodes: Vec<ClosestNode>,
+}
+
+impl From<GetPeersResult> for Peers {
+    fn from(value: GetPeersResult) -> Self {
+        Self {
+            info_hash: value.info_hash.to_string(),
+            peers: value.peers.iter().map(ToString::to_string).collect(),
+            closest_nodes: value
+                .closest_nodes
+                .iter()
+                .map(|(addr_id, token)| ClosestNode {
+                    id: addr_id.id().to_string(),
+                    addr: addr_id.addr().to_string(),
+                    token: HexBytes(token).to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+async fn get_peers(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Path(info_hash): Path<String>,
+) -> Response {
+    let Some(info_hash) = parse_hex(&info_hash).map(InfoHash::from) else {
+        return (StatusCode::BAD_REQUEST, "invalid info hash").into_response();
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetPeers(info_hash, tx)).await;
+
+    match rx.await {
+        Ok(result) => Json(Peers::from(result)).into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Scrape {
+    info_hash: String,
+    seeders: f64,
+    leechers: f64,
+}
+
+async fn get_scrape(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Path(info_hash): Path<String>,
+) -> Response {
+    let Some(info_hash) = parse_hex(&info_hash).map(InfoHash::from) else {
+        return (StatusCode::BAD_REQUEST, "invalid info hash").into_response();
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::Scrape(info_hash, tx)).await;
+
+    match rx.await {
+        Ok(GetPeersResult {
+            info_hash,
+            scrape: Some(scrape),
+            ..
+        }) => Json(Scrape {
+            info_hash: info_hash.to_string(),
+            seeders: scrape.seeders,
+            leechers: scrape.leechers,
+        })
+        .into_response(),
+        Ok(_) | Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AnnounceEntry {
+    info_hash: String,
+    port: u16,
+    implied_port: bool,
+}
+
+async fn get_announces(State(cmd_tx): State<mpsc::Sender<Cmd>>) -> Response {
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetAnnounces(tx)).await;
+
+    match rx.await {
+        Ok(announces) => Json(
+            announces
+                .into_iter()
+                .map(|(info_hash, args)| AnnounceEntry {
+                    info_hash: info_hash.to_string(),
+                    port: args.port,
+                    implied_port: args.implied_port,
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PutAnnounce {
+    port: u16,
+    #[serde(default)]
+    implied_port: bool,
+}
+
+async fn put_announce(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Path(info_hash): Path<String>,
+    Json(body): Json<PutAnnounce>,
+) -> Response {
+    let Some(info_hash) = parse_hex(&info_hash).map(InfoHash::from) else {
+        return (StatusCode::BAD_REQUEST, "invalid info hash").into_response();
+    };
+
+    let args = AnnounceArgs {
+        port: body.port,
+        implied_port: body.implied_port,
+    };
+    match cmd_tx.send(Cmd::Announce(info_hash, args)).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn delete_announce(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Path(info_hash): Path<String>,
+) -> Response {
+    let Some(info_hash) = parse_hex(&info_hash).map(InfoHash::from) else {
+        return (StatusCode::BAD_REQUEST, "invalid info hash").into_response();
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::StopAnnounce(info_hash, tx)).await;
+
+    match rx.await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Returns the item's encoded value.
+///
+/// The sequence number of a mutable item is in the `x-item-seq` header.
+fn item_response(rx_result: Result<GetItemResult, oneshot::error::RecvError>) -> Response {
+    match rx_result {
+        Ok(GetItemResult {
+            item: Some(item), ..
+        }) => match bt_bencode::to_vec(item.value()) {
+            Ok(value) => {
+                let mut response =
+                    ([(header::CONTENT_TYPE, "application/octet-stream")], value).into_response();
+                if let Some(seq) = item.seq() {
+                    response
+                        .headers_mut()
+                        .insert("x-item-seq", header::HeaderValue::from(seq));
+                }
+                response
+            }
+            Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+        Ok(_) => StatusCode::NOT_FOUND.into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn get_item(State(cmd_tx): State<mpsc::Sender<Cmd>>, Path(target): Path<String>) -> Response {
+    let Some(target) = parse_hex(&target).map(node::Id::from) else {
+        return (StatusCode::BAD_REQUEST, "invalid target").into_response();
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetItem(target, tx)).await;
+
+    item_response(rx.await)
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SaltQuery {
+    #[serde(default)]
+    salt: String,
+}
+
+async fn get_mutable_item(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Path(public_key): Path<String>,
+    Query(query): Query<SaltQuery>,
+) -> Response {
+    let Some(public_key) = parse_hex(&public_key) else {
+        return (StatusCode::BAD_REQUEST, "invalid public key").into_response();
+    };
+    let Some(salt) = parse_hex_bytes(&query.salt) else {
+        return (StatusCode::BAD_REQUEST, "invalid salt").into_response();
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetMutableItem(public_key, salt, tx)).await;
+
+    item_response(rx.await)
+}
+
+#[derive(Debug, Serialize)]
+struct PutItem {
+    target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+    closest_nodes: Vec<ClosestNode>,
+}
+
+impl From<GetItemResult> for PutItem {
+    fn from(value: GetItemResult) -> Self {
+        let mutable_item = match &value.item {
+            Some(Item::Mutable(item)) => Some(item),
+            Some(Item::Immutable(_)) | None => None,
+        };
+        Self {
+            target: value.target.to_string(),
+            public_key: mutable_item.map(|item| HexBytes(&item.k).to_string()),
+            seq: mutable_item.map(|item| item.seq),
+            closest_nodes: value
+                .closest_nodes
+                .iter()
+                .map(|(addr_id, token)| ClosestNode {
+                    id: addr_id.id().to_string(),
+                    addr: addr_id.addr().to_string(),
+                    token: HexBytes(token).to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parses a bencoded value which can be stored in an item.
+fn parse_item_value(body: &[u8]) -> Result<Value, (StatusCode, String)> {
+    let Ok(value) = bt_bencode::from_slice::<Value>(body) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            String::from("invalid bencoded value"),
+        ));
+    };
+    match item_op::encode_value(&value) {
+        Ok(_) => Ok(value),
+        Err(e @ PutError::ValueTooBig) => Err((StatusCode::PAYLOAD_TOO_LARGE, e.to_string())),
+        Err(e) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+    }
+}
+
+async fn put_item(State(cmd_tx): State<mpsc::Sender<Cmd>>, body: Bytes) -> Response {
+    let value = match parse_item_value(&body) {
+        Ok(value) => value,
+        Err(e) => return e.into_response(),
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::PutItem(value, tx)).await;
+
+    match rx.await {
+        Ok(result) => Json(PutItem::from(result)).into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn put_mutable_item(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Query(query): Query<SaltQuery>,
+    body: Bytes,
+) -> Response {
+    let Some(salt) = parse_hex_bytes(&query.salt) else {
+        return (StatusCode::BAD_REQUEST, "invalid salt").into_response();
+    };
+    if salt.len() > item_op::MAX_SALT_LEN {
+        return (StatusCode::BAD_REQUEST, "salt too big").into_response();
+    }
+    let value = match parse_item_value(&body) {
+        Ok(value) => value,
+        Err(e) => return e.into_response(),
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::PutMutableItem(salt, value, tx)).await;
+
+    match rx.await {
+        Ok(result) => Json(PutItem::from(result)).into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MutableTorrent {
+    magnet: String,
+    target: String,
+    seq: i64,
+    info_hash: String,
+}
+
+impl MutableTorrent {
+    fn from_result(result: GetItemResult) -> Option<Self> {
+        let info_hash = mutable_torrent::info_hash(result.item.as_ref()?)?;
+        let Some(Item::Mutable(item)) = result.item else {
+            return None;
+        };
+        Some(Self {
+            magnet: MutableTorrentLink {
+                public_key: item.k,
+                salt: item.salt,
+            }
+            .to_string(),
+            target: result.target.to_string(),
+            seq: item.seq,
+            info_hash: info_hash.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MagnetQuery {
+    magnet: String,
+}
+
+async fn resolve_mutable_torrent(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Query(query): Query<MagnetQuery>,
+) -> Response {
+    let link = match query.magnet.parse::<MutableTorrentLink>() {
+        Ok(link) => link,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::ResolveMutableTorrent(link, tx)).await;
+
+    match rx.await {
+        Ok(result) => match MutableTorrent::from_result(result) {
+            Some(torrent) => Json(torrent).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishMutableTorrent {
+    info_hash: String,
+    #[serde(default)]
+    salt: String,
+}
+
+async fn publish_mutable_torrent(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Json(body): Json<PublishMutableTorrent>,
+) -> Response {
+    let Some(info_hash) = parse_hex(&body.info_hash).map(InfoHash::from) else {
+        return (StatusCode::BAD_REQUEST, "invalid info hash").into_response();
+    };
+    let Some(salt) = parse_hex_bytes(&body.salt) else {
+        return (StatusCode::BAD_REQUEST, "invalid salt").into_response();
+    };
+    if salt.len() > item_op::MAX_SALT_LEN {
+        return (StatusCode::BAD_REQUEST, "salt too big").into_response();
+    }
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx
+        .send(Cmd::PublishMutableTorrent(salt, info_hash, tx))
+        .await;
+
+    match rx.await.ok().and_then(MutableTorrent::from_result) {
+        Some(torrent) => Json(torrent).into_response(),
+        None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SampleQuery {
+    #[serde(default = "SampleQuery::default_max_nodes")]
+    max_nodes: usize,
+}
+
+impl SampleQuery {
+    fn default_max_nodes() -> usize {
+        256
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Samples {
+    info_hashes: Vec<String>,
+    sampled_nodes: usize,
+    num: u64,
+}
+
+impl From<SampleInfoHashesResult> for Samples {
+    fn from(value: SampleInfoHashesResult) -> Self {
+        Self {
+            info_hashes: value.info_hashes.iter().map(ToString::to_string).collect(),
+            sampled_nodes: value.sampled_nodes,
+            num: value.num,
+        }
+    }
+}
+
+async fn sample_infohashes(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Query(query): Query<SampleQuery>,
+) -> Response {
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx
+        .send(Cmd::SampleInfoHashes(query.max_nodes, tx))
+        .await;
+
+    match rx.await {
+        Ok(result) => Json(Samples::from(result)).into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LookupNode {
+    /// A hex encoded node ID or `random`
+    target: String,
+}
+
+#[derive(Debug, Serialize)]
+struct LookupNodeResultNode {
+    id: String,
+    addr: SocketAddr,
+    distance: String,
+}
+
+#[derive(Debug, Serialize)]
+struct LookupNodeResult {
+    target: String,
+    closest_nodes: Vec<LookupNodeResultNode>,
+    queries_sent: usize,
+    failures: usize,
+    elapsed_secs: f64,
+}
+
+impl From<FindNodeResult> for LookupNodeResult {
+    fn from(value: FindNodeResult) -> Self {
+        let target_id = value.target_id;
+        Self {
+            target: target_id.to_string(),
+            closest_nodes: value
+                .closest_nodes
+                .into_iter()
+                .map(|addr_id| LookupNodeResultNode {
+                    id: addr_id.id().to_string(),
+                    addr: SocketAddr::from(*addr_id.addr()),
+                    distance: addr_id.id().distance(target_id).to_string(),
+                })
+                .collect(),
+            queries_sent: value.queries_sent,
+            failures: value.failures,
+            elapsed_secs: value.elapsed.as_secs_f64(),
+        }
+    }
+}
+
+async fn lookup_node(
+    State(cmd_tx): State<mpsc::Sender<Cmd>>,
+    Json(body): Json<LookupNode>,
+) -> Response {
+    let target_id = if body.target == "random" {
+        match node::Id::rand(&mut rand::thread_rng()) {
+            Ok(id) => id,
+            Err(_e) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    } else {
+        let Some(target_id) = parse_hex(&body.target).map(node::Id::from) else {
+            return (StatusCode::BAD_REQUEST, "invalid target").into_response();
+        };
+        target_id
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::FindNode(target_id, tx)).await;
+
+    match rx.await {
+        Ok(result) => Json(LookupNodeResult::from(result)).into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn get_metrics(State(cmd_tx): State<mpsc::Sender<Cmd>>) -> Response {
+    let (tx, rx) = oneshot::channel();
+    let _ = cmd_tx.send(Cmd::GetMetrics(tx)).await;
+
+    match rx.await {
+        Ok(metrics) => (
+            [(
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8",
+            )],
+            metrics,
+        )
+            .into_response(),
+        Err(_e) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Asks the DHT task for its health.
+///
+/// Fails if the task has stopped or does not answer within [`HEALTH_TIMEOUT`].
+async fn query_health(cmd_tx: &mpsc::Sender<Cmd>) -> Result<dht::Health, &'static str> {
+    let (tx, rx) = oneshot::channel();
+    let health = tokio::time::timeout(HEALTH_TIMEOUT, async {
+        cmd_tx.send(Cmd::GetHealth(tx)).await.ok()?;
+        rx.await.ok()
+    })
+    .await;
+
+    match health {
+        Ok(Some(health)) => Ok(health),
+        Ok(None) => Err("DHT task is not running"),
+        Err(_) => Err("DHT task did not respond in time"),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Liveness {
+    live: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
+
+async fn get_liveness(State(cmd_tx): State<mpsc::Sender<Cmd>>) -> Response {
+    match query_health(&cmd_tx).await {
+        Ok(_) => Json(Liveness {
+            live: true,
+            reason: None,
+        })
+        .into_response(),
+        Err(reason) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Liveness {
+                live: false,
+                reason: Some(reason),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_bootstrapped: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    good_nodes: Option<usize>,
+    min_good_nodes: usize,
+    reasons: Vec<&'static str>,
+}
+
+async fn get_readiness(cmd_tx: mpsc::Sender<Cmd>, min_good_nodes: usize) -> Response {
+    let mut readiness = Readiness {
+        ready: false,
+        is_bootstrapped: None,
+        good_nodes: None,
+        min_good_nodes,
+        reasons: Vec::new(),
+    };
+
+    match query_health(&cmd_tx).await {
+        Ok(health) => {
+            readiness.is_bootstrapped = Some(health.is_bootstrapped);
+            readiness.good_nodes = Some(health.good_nodes);
+            if !health.is_bootstrapped {
+                readiness.reasons.push("bootstrap has not finished");
+            }
+            if health.good_nodes < min_good_nodes {
+                readiness.reasons.push("not enough good nodes");
+            }
+        }
+        Err(reason) => readiness.reasons.push(reason),
+    }
+    readiness.ready = readiness.reasons.is_empty();
+
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness)).into_response()
+}
+
 pub(super) async fn http_task(
     socket_addr: SocketAddr,
-    cmd_tx: tokio::sync::mpsc::Sender<Cmd>,
-    mut shutdown_rx: tokio::sync::oneshot::Receiver<()>,
-    completion_tx: tokio::sync::oneshot::Sender<()>,
+    cmd_tx: mpsc::Sender<Cmd>,
+    ready_min_good_nodes: usize,
+    mut shutdown_rx: oneshot::Receiver<()>,
+    completion_tx: oneshot::Sender<()>,
 ) -> io::Result<()> {
-    use axum::{routing::get, Router};
+    use axum::{
+        routing::{get, post, put},
+        Router,
+    };
+
+    let lookups = Router::new()
+        .route("/peers/:info_hash", get(get_peers))
+        .route("/scrape/:info_hash", get(get_scrape))
+        .route("/items", put(put_item))
+        .route("/items/:target", get(get_item))
+        .route("/items/mutable", put(put_mutable_item))
+        .route("/items/mutable/:public_key", get(get_mutable_item))
+        .route(
+            "/torrents/mutable",
+            get(resolve_mutable_torrent).put(publish_mutable_torrent),
+        )
+        .route("/sample_infohashes", get(sample_infohashes))
+        .route("/lookup/find_node", post(lookup_node))
+        .layer(TimeoutLayer::new(LOOKUP_TIMEOUT));
 
     let app = Router::new()
-        .route("/health", get(|| async { "ok" }))
+        .route("/health", get(get_liveness))
+        .route("/health/live", get(get_liveness))
+        .route(
+            "/health/ready",
+            get(move |State(cmd_tx): State<mpsc::Sender<Cmd>>| {
+                get_readiness(cmd_tx, ready_min_good_nodes)
+            }),
+        )
         .route(
             "/config",
-            get(|| async move { get_config(cmd_tx.clone()).await }),
+            get(get_config).put(replace_config).patch(update_config),
         )
-        .layer((
-            TraceLayer::new_for_http(),
-            TimeoutLayer::new(Duration::from_secs(10)),
-        ));
+        .route("/status", get(get_status))
+        .route("/metrics", get(get_metrics))
+        .route("/routing-table", get(get_routing_table))
+        .route("/transactions", get(get_transactions))
+        .route("/ops", get(get_ops))
+        .route("/announces", get(get_announces))
+        .route(
+            "/announces/:info_hash",
+            put(put_announce).delete(delete_announce),
+        )
+        .layer(TimeoutLayer::new(Duration::from_secs(10)))
+        .merge(lookups)
+        .layer(TraceLayer::new_for_http())
+        .with_state(cmd_tx);
 
     let listener = TcpListener::bind(socket_addr).await.unwrap();
 
@@ -147,3 +1193,55 @@ pub(super) async fn http_task(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_fields() {
+        let body: UpdateConfig = serde_json::from_str(
+            r#"{"is_read_only_node":true,"announce_interval":{"secs":60,"nanos":0}}"#,
+        )
+        .unwrap();
+        let missing_fields = body.missing_fields();
+        assert_eq!(missing_fields.len(), 9);
+        assert!(missing_fields.contains(&"local_id"));
+        assert!(!missing_fields.contains(&"is_read_only_node"));
+        assert!(!missing_fields.contains(&"announce_interval"));
+    }
+
+    #[test]
+    fn test_enum_names() {
+        let body: UpdateConfig = serde_json::from_str(
+            r#"{"node_id_policy":"deprioritize","supported_addr":"ipv4_and_ipv6"}"#,
+        )
+        .unwrap();
+        assert_eq!(body.node_id_policy, Some(NodeIdPolicy::Deprioritize));
+        assert_eq!(body.supported_addr, Some(dht::SupportedAddr::Ipv4AndIpv6));
+        assert_eq!(
+            serde_json::to_string(&NodeIdPolicy::Deprioritize).unwrap(),
+            format!("\"{}\"", NodeIdPolicy::Deprioritize)
+        );
+        assert!(serde_json::from_str::<UpdateConfig>(r#"{"node_id_policy":"Enforce"}"#).is_err());
+    }
+
+    #[test]
+    fn test_client_version() {
+        let body: UpdateConfig = serde_json::from_str(r#"{"client_version":"WN01"}"#).unwrap();
+        let update = body.to_update().unwrap();
+        assert_eq!(update.client_version, Some(b"WN01".to_vec()));
+
+        for version in ["574E3031", "WN0", "WN0\u{e9}"] {
+            let body = UpdateConfig {
+                client_version: Some(String::from(version)),
+                ..serde_json::from_str("{}").unwrap()
+            };
+            assert_eq!(
+                body.to_update().unwrap_err().0,
+                StatusCode::BAD_REQUEST,
+                "{version}"
+            );
+        }
+    }
+}
@@ -8,51 +8,302 @@
     unused_qualifications
 )]
 
-use clap::Parser;
-use cloudburst::dht::node::{Id, LocalId};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use cloudburst::dht::node::{AddrId, Id, LocalId};
+use ed25519_dalek::{SigningKey, SECRET_KEY_LENGTH};
 use std::{
-    io,
-    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
-    time::Instant,
+    fs,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 use tokio::{
-    net::UdpSocket,
+    net::{lookup_host, UdpSocket},
     signal,
     sync::{mpsc, oneshot},
 };
-use tracing::info;
+use tracing::{info, warn};
 
+mod config;
 mod dht;
+mod hex;
 mod http;
 
-use dht::Node;
+use config::FileConfig;
+use dht::{node_id::NodeIdPolicy, state::DhtState, DataPaths, Node, SupportedAddr};
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))]
-    dht_bind: IpAddr,
-    #[arg(long, default_value_t = 6881)]
+    /// The TOML file with the node's settings
+    ///
+    /// The keys are the same as the flags with underscores instead of dashes.
+    /// Flags and environment variables override the file's settings.
+    #[arg(long, env = "WAYNODE_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "WAYNODE_DHT_BIND", default_value_t = Ipv4Addr::UNSPECIFIED)]
+    dht_bind: Ipv4Addr,
+    #[arg(long, env = "WAYNODE_DHT_BIND6", default_value_t = Ipv6Addr::UNSPECIFIED)]
+    dht_bind6: Ipv6Addr,
+    #[arg(long, env = "WAYNODE_DHT_PORT", default_value_t = 6881)]
     dht_port: u16,
-    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))]
+    #[arg(long, env = "WAYNODE_DISABLE_IPV4")]
+    disable_ipv4: bool,
+    #[arg(long, env = "WAYNODE_DISABLE_IPV6")]
+    disable_ipv6: bool,
+    #[arg(long, env = "WAYNODE_READ_ONLY", default_value_t = false, action = clap::ArgAction::Set)]
+    read_only: bool,
+    #[arg(long, env = "WAYNODE_HTTP_BIND", default_value_t = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))]
     http_bind: IpAddr,
-    #[arg(long, default_value_t = 8080)]
+    #[arg(long, env = "WAYNODE_HTTP_PORT", default_value_t = 8080)]
     http_port: u16,
-    #[arg(long, default_values_t = vec![
+    #[arg(long, env = "WAYNODE_BOOTSTRAP", value_delimiter = ',', default_values_t = vec![
         String::from("router.magnets.im:6881"),
         String::from("router.bittorent.com:6881"),
         String::from("router.utorrent.com:6881"),
         String::from("dht.transmissionbt.com:6881"),
     ])]
     bootstrap: Vec<String>,
+    /// The file with the key which signs mutable items put by the node
+    ///
+    /// If the file does not exist, a new key is generated and written to the
+    /// file. If no file is given, a new key is generated every time the node
+    /// starts.
+    #[arg(long, env = "WAYNODE_SIGNING_KEY_FILE")]
+    signing_key_file: Option<PathBuf>,
+    /// The external IP address which the local node ID is generated for
+    ///
+    /// If not given, a random node ID is used which may not be valid for the
+    /// node's IP address.
+    #[arg(long, env = "WAYNODE_EXTERNAL_IP")]
+    external_ip: Option<IpAddr>,
+    /// How remote nodes with IDs which are not valid for their IP address are
+    /// treated: ignore, deprioritize, or enforce
+    #[arg(long, env = "WAYNODE_NODE_ID_POLICY", default_value_t = NodeIdPolicy::default())]
+    node_id_policy: NodeIdPolicy,
+    /// Generates a new local node ID when remote nodes agree on a new external
+    /// IP address which the current ID is not valid for
+    #[arg(long, env = "WAYNODE_REGENERATE_NODE_ID")]
+    regenerate_node_id: bool,
+    /// The file which the local node ID and routing table nodes are kept in
+    ///
+    /// The state is loaded when the node starts and is written periodically
+    /// and on shutdown.
+    #[arg(long, env = "WAYNODE_STATE_FILE")]
+    state_file: Option<PathBuf>,
+    /// The directory which announced peers and stored items are kept in
+    ///
+    /// The data is loaded when the node starts and is written periodically
+    /// and on shutdown.
+    #[arg(long, env = "WAYNODE_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// The client version sent in messages
+    #[arg(long, env = "WAYNODE_CLIENT_VERSION", default_value = "ab12", value_parser = config::parse_client_version)]
+    client_version: String,
+    /// The number of seconds before a query without a response is timed out
+    #[arg(long, env = "WAYNODE_DEFAULT_QUERY_TIMEOUT_SECS", default_value_t = 5 * 60, value_parser = clap::value_parser!(u64).range(1..))]
+    default_query_timeout_secs: u64,
+    /// If responses must have the node ID of the queried node
+    #[arg(long, env = "WAYNODE_STRICT_RESPONSE_NODE_ID", default_value_t = true, action = clap::ArgAction::Set)]
+    strict_response_node_id: bool,
+    /// The number of seconds after a routing table node's last response
+    /// before the node is questionable
+    #[arg(long, env = "WAYNODE_ROUTING_TABLE_NEXT_RESPONSE_INTERVAL_SECS", default_value_t = 15 * 60, value_parser = clap::value_parser!(u64).range(1..))]
+    routing_table_next_response_interval_secs: u64,
+    /// The number of seconds after a routing table node's last query before
+    /// the node is questionable
+    #[arg(long, env = "WAYNODE_ROUTING_TABLE_NEXT_QUERY_INTERVAL_SECS", default_value_t = 15 * 60, value_parser = clap::value_parser!(u64).range(1..))]
+    routing_table_next_query_interval_secs: u64,
+    /// The number of seconds between announces for torrents
+    #[arg(long, env = "WAYNODE_ANNOUNCE_INTERVAL_SECS", default_value_t = 15 * 60, value_parser = clap::value_parser!(u64).range(1..))]
+    announce_interval_secs: u64,
+
+    /// The minimum number of good nodes in the routing tables for the node
+    /// to be ready
+    #[arg(long, env = "WAYNODE_READY_MIN_GOOD_NODES", default_value_t = 8)]
+    ready_min_good_nodes: usize,
 }
 
-fn get_config(local_id: LocalId) -> dht::Config {
+fn get_config(local_id: LocalId, supported_addr: SupportedAddr, args: &Args) -> dht::Config {
     let mut config = dht::Config::new(local_id);
-    config.set_client_version(Some("ab12".into()));
-    config.set_is_read_only_node(true);
+    config.set_client_version(Some(args.client_version.clone().into_bytes()));
+    config.set_default_query_timeout(Duration::from_secs(args.default_query_timeout_secs));
+    config.set_is_read_only_node(args.read_only);
+    config.set_is_response_queried_node_id_strictly_checked(args.strict_response_node_id);
+    config.set_routing_table_next_response_interval(Duration::from_secs(
+        args.routing_table_next_response_interval_secs,
+    ));
+    config.set_routing_table_next_query_interval(Duration::from_secs(
+        args.routing_table_next_query_interval_secs,
+    ));
+    config.set_announce_interval(Duration::from_secs(args.announce_interval_secs));
+    config.set_supported_addr(supported_addr);
+    config.set_node_id_policy(args.node_id_policy);
+    config.set_is_local_id_regenerated_for_external_ip(args.regenerate_node_id);
     config
 }
 
+/// Returns the settings which can be changed while the node is running.
+fn get_config_update(args: &Args) -> dht::ConfigUpdate {
+    dht::ConfigUpdate {
+        client_version: Some(args.client_version.clone().into_bytes()),
+        default_query_timeout: Some(Duration::from_secs(args.default_query_timeout_secs)),
+        is_read_only_node: Some(args.read_only),
+        is_response_queried_node_id_strictly_checked: Some(args.strict_response_node_id),
+        routing_table_next_response_interval: Some(Duration::from_secs(
+            args.routing_table_next_response_interval_secs,
+        )),
+        routing_table_next_query_interval: Some(Duration::from_secs(
+            args.routing_table_next_query_interval_secs,
+        )),
+        announce_interval: Some(Duration::from_secs(args.announce_interval_secs)),
+        node_id_policy: Some(args.node_id_policy),
+        is_local_id_regenerated_for_external_ip: Some(args.regenerate_node_id),
+    }
+}
+
+/// Parses the command line and merges in the configuration file.
+///
+/// If the arguments or the configuration file are invalid, the process exits
+/// with an error message.
+fn parse_args() -> (Args, ArgMatches) {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = &args.config {
+        match FileConfig::load(path) {
+            Ok(file_config) => file_config.merge_into(&mut args, &matches),
+            Err(e) => Args::command()
+                .error(clap::error::ErrorKind::InvalidValue, e)
+                .exit(),
+        }
+    }
+    (args, matches)
+}
+
+/// Reads the configuration file again and applies the settings which can be
+/// changed while the node is running.
+///
+/// Settings which were given on the command line or in an environment
+/// variable keep their values. Changed settings which require a restart are
+/// logged.
+async fn reload_config(args: &mut Args, matches: &ArgMatches, dht_cmd_tx: &mpsc::Sender<dht::Cmd>) {
+    let Some(path) = &args.config else {
+        warn!("no config file to reload");
+        return;
+    };
+
+    let mut new_args = Args::from_arg_matches(matches).expect("arguments were already parsed");
+    match FileConfig::load(path) {
+        Ok(file_config) => file_config.merge_into(&mut new_args, matches),
+        Err(e) => {
+            warn!(%e, "could not reload config file");
+            return;
+        }
+    }
+    info!(path = %path.display(), "reloading config file");
+
+    // The running values are kept so the settings are compared against what
+    // is in use on the next reload.
+    macro_rules! warn_if_restart_required {
+        ($($field:ident),* $(,)?) => {
+            $(
+                if args.$field != new_args.$field {
+                    warn!(
+                        setting = stringify!($field),
+                        old = ?args.$field,
+                        new = ?new_args.$field,
+                        "config changed but requires a restart"
+                    );
+                    new_args.$field = args.$field.clone();
+                }
+            )*
+        };
+    }
+    warn_if_restart_required!(
+        dht_bind,
+        dht_bind6,
+        dht_port,
+        disable_ipv4,
+        disable_ipv6,
+        http_bind,
+        http_port,
+        signing_key_file,
+        external_ip,
+        state_file,
+        data_dir,
+        ready_min_good_nodes,
+    );
+
+    let (tx, rx) = oneshot::channel();
+    let _ = dht_cmd_tx
+        .send(dht::Cmd::UpdateConfig(get_config_update(&new_args), tx))
+        .await;
+    let _ = dht_cmd_tx
+        .send(dht::Cmd::SetBootstrapAddrs(
+            resolve_bootstrap_addrs(&new_args.bootstrap).await,
+        ))
+        .await;
+    if let Ok((changed, _config)) = rx.await {
+        info!(?changed, "reloaded config file");
+    }
+
+    *args = new_args;
+}
+
+/// Resolves the bootstrap node host names.
+///
+/// The names are resolved outside of the DHT task so a slow resolver does not
+/// hold up the handling of messages. Names which cannot be resolved are
+/// skipped.
+async fn resolve_bootstrap_addrs(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for host in hosts {
+        match lookup_host(host.as_str()).await {
+            Ok(resolved) => addrs.extend(resolved),
+            Err(e) => warn!(%host, %e, "could not resolve bootstrap address"),
+        }
+    }
+    addrs
+}
+
+/// Reads the signing key from a file or writes a new key to the file.
+fn load_signing_key(path: &Path) -> io::Result<SigningKey> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let secret_key = <[u8; SECRET_KEY_LENGTH]>::try_from(bytes.as_slice())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid signing key"))?;
+            Ok(SigningKey::from_bytes(&secret_key))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let signing_key = SigningKey::generate(&mut rand::thread_rng());
+
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options.open(path)?.write_all(signing_key.as_bytes())?;
+
+            info!(path = %path.display(), "generated new signing key");
+            Ok(signing_key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Binds a UDP socket which only sends and receives IPv6 datagrams.
+fn bind_ipv6(addr: SocketAddrV6) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::V6(addr).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -96,24 +347,89 @@ async fn main() -> io::Result<()> {
         tracing_subscriber::fmt::init();
     }
 
-    let args = Args::parse();
+    let (mut args, matches) = parse_args();
 
-    let dht_socket = SocketAddr::new(args.dht_bind, args.dht_port);
+    let dht_socket = SocketAddr::new(IpAddr::V4(args.dht_bind), args.dht_port);
+    let dht_socket6 = SocketAddrV6::new(args.dht_bind6, args.dht_port, 0, 0);
 
     let shutdown = shutdown_signal();
     tokio::pin!(shutdown);
 
-    let socket = UdpSocket::bind(dht_socket).await?;
-    let local_id = Id::rand(&mut rand::thread_rng()).unwrap();
-    info!(dht_socket = %dht_socket, %local_id, "listening...");
+    let socket = if args.disable_ipv4 {
+        None
+    } else {
+        Some(UdpSocket::bind(dht_socket).await?)
+    };
+    let socket6 = if args.disable_ipv6 {
+        None
+    } else {
+        match bind_ipv6(dht_socket6) {
+            Ok(socket6) => Some(socket6),
+            Err(e) => {
+                warn!(%e, %dht_socket6, "could not bind IPv6 socket, continuing with only IPv4");
+                None
+            }
+        }
+    };
+    let state = match &args.state_file {
+        Some(path) => DhtState::load(path)?,
+        None => None,
+    };
+    let local_id = match (&state, args.external_ip) {
+        (Some(state), Some(external_ip)) if dht::node_id::is_valid(state.local_id, external_ip) => {
+            state.local_id
+        }
+        (Some(state), None) => state.local_id,
+        (_, Some(external_ip)) => dht::node_id::secure_id(external_ip, &mut rand::thread_rng()),
+        (None, None) => Id::rand(&mut rand::thread_rng()).unwrap(),
+    };
+    let addr_ids = state
+        .map(|state| state.addr_ids)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|addr_id| AddrId::new(SocketAddr::from(*addr_id.addr()), addr_id.id()));
 
-    let config = get_config(LocalId::from(local_id));
-    let node: Node<SocketAddrV4> =
-        Node::new(config, std::iter::empty(), args.bootstrap, Instant::now());
+    let supported_addr = match (&socket, &socket6) {
+        (Some(_), Some(_)) => SupportedAddr::Ipv4AndIpv6,
+        (Some(_), None) => SupportedAddr::Ipv4,
+        (None, Some(_)) => SupportedAddr::Ipv6,
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no IPv4 or IPv6 socket to listen on",
+            ));
+        }
+    };
+    info!(
+        dht_socket = ?socket.as_ref().map(|_| dht_socket),
+        dht_socket6 = ?socket6.as_ref().map(|_| dht_socket6),
+        %local_id,
+        "listening..."
+    );
+    let config = get_config(LocalId::from(local_id), supported_addr, &args);
+    let bootstrap_addrs = resolve_bootstrap_addrs(&args.bootstrap).await;
+    let mut node: Node<SocketAddr> = Node::new(config, addr_ids, bootstrap_addrs, Instant::now());
+    if let Some(path) = &args.signing_key_file {
+        node.set_signing_key(load_signing_key(path)?);
+    }
+    if let Some(dir) = &args.data_dir {
+        fs::create_dir_all(dir)?;
+        node.load_stores(dir, Instant::now())?;
+    }
 
     let (dht_cmd_tx, dht_cmd_rx) = mpsc::channel(32);
     let (dht_completion_tx, dht_completion_rx) = oneshot::channel();
-    let dht_handle = tokio::spawn(dht::dht_task(socket, node, dht_cmd_rx, dht_completion_tx));
+    let dht_handle = tokio::spawn(dht::dht_task(
+        socket,
+        socket6,
+        node,
+        DataPaths {
+            state_file: args.state_file.clone(),
+            data_dir: args.data_dir.clone(),
+        },
+        dht_cmd_rx,
+        dht_completion_tx,
+    ));
 
     let http_socket = SocketAddr::new(args.http_bind, args.http_port);
     info!(http_socket = %http_socket, "http listening...");
@@ -124,26 +440,42 @@ async fn main() -> io::Result<()> {
     let http_handle = tokio::spawn(http::http_task(
         http_socket,
         dht_cmd_tx.clone(),
+        args.ready_min_good_nodes,
         http_shutdown_rx,
         http_completion_tx,
     ));
 
-    tokio::select! {
-        _ = dht_completion_rx => {
-        }
-        _ = http_completion_rx => {
-        }
-        () = &mut shutdown => {
+    tokio::pin!(dht_completion_rx, http_completion_rx);
+
+    #[cfg(unix)]
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+
+    loop {
+        #[cfg(unix)]
+        let reload = hangup.recv();
+
+        #[cfg(not(unix))]
+        let reload = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = &mut dht_completion_rx => {
+                break;
+            }
+            _ = &mut http_completion_rx => {
+                break;
+            }
+            () = &mut shutdown => {
+                break;
+            }
+            _ = reload => {
+                reload_config(&mut args, &matches, &dht_cmd_tx).await;
+            }
         }
     }
 
     drop(dht_cmd_tx);
     http_shutdown_tx.send(()).unwrap();
 
-    dht_handle
-        .await
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
-    http_handle
-        .await
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+    dht_handle.await.map_err(io::Error::other)??;
+    http_handle.await.map_err(io::Error::other)?
 }